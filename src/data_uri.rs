@@ -0,0 +1,288 @@
+use std::io::{self, Read};
+
+/// Maximal length of the "data:" URI header preceding the data.
+const MAX_HEADER_LENGTH: usize = 1024;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Make a reader decoding the base64 data or the "data:" URI as it is read.
+///
+/// Returns the media type of the "data:" URI, if any, and the reader of the decoded data,
+/// so the data of any size can be decoded with no buffering, see `Base64Decoder`.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::io::Read;
+///     use trlogic_test::data_uri;
+///
+///     let (content_type, mut reader) = data_uri::decoder(&b"data:image/png;base64,VEVTVA"[..]).unwrap();
+///     let mut data = Vec::new();
+///     reader.read_to_end(&mut data).unwrap();
+///     assert_eq!((content_type, data), (Some(String::from("image/png")), b"TEST".to_vec()));
+///
+///     let (content_type, mut reader) = data_uri::decoder(&b"VEVTVA=="[..]).unwrap();
+///     let mut data = Vec::new();
+///     reader.read_to_end(&mut data).unwrap();
+///     assert_eq!((content_type, data), (None, b"TEST".to_vec()));
+///
+///     let (content_type, mut reader) = data_uri::decoder(&b"data:,TEST%20DATA"[..]).unwrap();
+///     let mut data = Vec::new();
+///     reader.read_to_end(&mut data).unwrap();
+///     assert_eq!((content_type, data), (None, b"TEST DATA".to_vec()));
+///
+///     assert!(data_uri::decoder(&b"data:image/png;base64"[..]).is_err());
+/// ```
+pub fn decoder<'a, R: Read + 'a>(
+    mut reader: R,
+) -> io::Result<(Option<String>, Box<dyn Read + 'a>)> {
+    let mut prefix = Vec::with_capacity(5);
+    reader.by_ref().take(5).read_to_end(&mut prefix)?;
+    if !prefix.eq_ignore_ascii_case(b"data:") {
+        let data = io::Cursor::new(prefix).chain(reader);
+        return Ok((None, Box::new(Base64Decoder::new(data))));
+    }
+
+    let mut header = Vec::new();
+    let mut separated = false;
+    let mut byte = [0; 1];
+    while header.len() < MAX_HEADER_LENGTH && reader.read(&mut byte)? > 0 {
+        if byte[0] == b',' {
+            separated = true;
+            break;
+        }
+        header.push(byte[0]);
+    }
+    if !separated {
+        return Err(invalid("invalid data URI"));
+    }
+    let header = String::from_utf8(header).map_err(|_| invalid("invalid data URI"))?;
+
+    let mut params = header.split(';').map(str::trim);
+    let content_type = params
+        .next()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase());
+    if params.any(|x| x.eq_ignore_ascii_case("base64")) {
+        Ok((content_type, Box::new(Base64Decoder::new(reader))))
+    } else {
+        Ok((content_type, Box::new(PercentDecoder::new(reader))))
+    }
+}
+
+/// Reader decoding base64 data leniently as it is read.
+///
+/// Both the standard and the URL-safe alphabets are accepted, padding is optional
+/// and whitespace, i.e. line breaks, is ignored.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::io::Read;
+///     use trlogic_test::data_uri::Base64Decoder;
+///
+///     let decode = |data: &str| {
+///         let mut decoded = Vec::new();
+///         Base64Decoder::new(data.as_bytes()).read_to_end(&mut decoded).map(|_| decoded)
+///     };
+///     assert_eq!(decode("VEVTVCBKUEVHIERBVEE=").unwrap(), b"TEST JPEG DATA");
+///     assert_eq!(decode("VEVTVCBKUEVH\r\nIERBVEE").unwrap(), b"TEST JPEG DATA");
+///     assert_eq!(decode("-_-_").unwrap(), [0xfb, 0xff, 0xbf]);
+///     assert!(decode("VEVTV!").is_err());
+///     assert!(decode("VEVTVB").is_err());
+/// ```
+pub struct Base64Decoder<R> {
+    reader: R,
+    input: [u8; 4096],
+    input_pos: usize,
+    input_len: usize,
+    /// Values of the base64 characters of the incomplete quantum.
+    quantum: [u8; 4],
+    quantum_len: usize,
+    output: [u8; 3],
+    output_pos: usize,
+    output_len: usize,
+    padded: bool,
+    done: bool,
+}
+
+impl<R: Read> Base64Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Base64Decoder {
+            reader,
+            input: [0; 4096],
+            input_pos: 0,
+            input_len: 0,
+            quantum: [0; 4],
+            quantum_len: 0,
+            output: [0; 3],
+            output_pos: 0,
+            output_len: 0,
+            padded: false,
+            done: false,
+        }
+    }
+
+    fn push(&mut self, x: u8) -> io::Result<()> {
+        let value = match x {
+            b'A'..=b'Z' => x - b'A',
+            b'a'..=b'z' => x - b'a' + 26,
+            b'0'..=b'9' => x - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => {
+                self.padded = true;
+                return Ok(());
+            }
+            x if x.is_ascii_whitespace() => return Ok(()),
+            _ => return Err(invalid("invalid base64 data")),
+        };
+        // Nothing but padding may follow the padding.
+        if self.padded {
+            return Err(invalid("invalid base64 data"));
+        }
+
+        self.quantum[self.quantum_len] = value;
+        self.quantum_len += 1;
+        if self.quantum_len == 4 {
+            self.flush_quantum();
+        }
+        Ok(())
+    }
+
+    fn flush_quantum(&mut self) {
+        let q = self.quantum;
+        self.output = [
+            q[0] << 2 | q[1] >> 4,
+            q[1] << 4 | q[2] >> 2,
+            q[2] << 6 | q[3],
+        ];
+        self.output_pos = 0;
+        self.output_len = self.quantum_len.saturating_sub(1);
+        self.quantum = [0; 4];
+        self.quantum_len = 0;
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Bits of the last character not used by the incomplete quantum must be zero.
+        let q = self.quantum;
+        let unused = match self.quantum_len {
+            1 => 0xff,
+            2 => q[1] & 0x0f,
+            3 => q[2] & 0x03,
+            _ => 0,
+        };
+        if unused != 0 {
+            return Err(invalid("invalid base64 data"));
+        }
+        self.flush_quantum();
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Base64Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.output_pos < self.output_len {
+                let len = (self.output_len - self.output_pos).min(buf.len() - n);
+                buf[n..n + len]
+                    .copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+                self.output_pos += len;
+                n += len;
+                continue;
+            }
+            if self.done {
+                break;
+            }
+
+            if self.input_pos == self.input_len {
+                // Don't wait for more input when there is decoded data already.
+                if n > 0 {
+                    break;
+                }
+                self.input_len = self.reader.read(&mut self.input)?;
+                self.input_pos = 0;
+                if self.input_len == 0 {
+                    self.finish()?;
+                    continue;
+                }
+            }
+
+            let x = self.input[self.input_pos];
+            self.input_pos += 1;
+            self.push(x)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Reader decoding percent-encoded data as it is read.
+///
+/// Like `percent_encoding::percent_decode`, invalid percent sequences are kept as is.
+pub struct PercentDecoder<R> {
+    bytes: io::Bytes<io::BufReader<R>>,
+    /// Bytes of the invalid percent sequence not read yet, in reverse order.
+    pending: Vec<u8>,
+}
+
+impl<R: Read> PercentDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        PercentDecoder {
+            bytes: io::BufReader::new(reader).bytes(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(x) = self.pending.pop() {
+            return Ok(Some(x));
+        }
+        self.bytes.next().transpose()
+    }
+
+    fn decode_next(&mut self) -> io::Result<Option<u8>> {
+        match self.next_byte()? {
+            Some(b'%') => {}
+            x => return Ok(x),
+        }
+
+        let mut sequence = Vec::with_capacity(2);
+        while sequence.len() < 2 {
+            match self.next_byte()? {
+                Some(x) if (x as char).is_ascii_hexdigit() => sequence.push(x),
+                Some(x) => {
+                    sequence.push(x);
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        if sequence.len() == 2 && sequence.iter().all(|&x| (x as char).is_ascii_hexdigit()) {
+            let hex = |x: u8| (x as char).to_digit(16).unwrap_or(0) as u8;
+            return Ok(Some(hex(sequence[0]) << 4 | hex(sequence[1])));
+        }
+        self.pending.extend(sequence.iter().rev());
+        Ok(Some(b'%'))
+    }
+}
+
+impl<R: Read> Read for PercentDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.decode_next()? {
+                Some(x) => {
+                    buf[n] = x;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(n)
+    }
+}
@@ -1,8 +1,110 @@
 use chrono::prelude::*;
 use fs2::FileExt;
+use percent_encoding::percent_decode;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use url::Url;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximal length of the sanitized filename in bytes, leaving room for the extension
+/// appended by `normalize_image_filename`.
+pub const MAX_FILENAME_LENGTH: usize = 200;
+
+/// Device names reserved on Windows regardless of the extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make the client supplied filename safe to store in the upload directory.
+///
+/// Strips directory components, removes control characters, replaces characters
+/// reserved on Windows with "_", removes leading and trailing dots and spaces (so the
+/// name can't be "..", hidden or temporary), escapes Windows device names and limits
+/// the name length to `MAX_FILENAME_LENGTH` keeping the extension.
+/// Returns the sanitized filename and the list of applied changes, the filename may be
+/// empty if nothing is left of it.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///
+///     assert_eq!(file_utils::sanitize_filename("photo.jpg"), (String::from("photo.jpg"), vec![]));
+///     assert_eq!(
+///         file_utils::sanitize_filename("../../etc/passwd"),
+///         (String::from("passwd"), vec!["directory components removed"])
+///     );
+///     assert_eq!(file_utils::sanitize_filename("..").0, "");
+///     assert_eq!(file_utils::sanitize_filename("con.jpg").0, "_con.jpg");
+///     assert_eq!(file_utils::sanitize_filename("a<b>\0.png").0, "a_b_.png");
+/// ```
+pub fn sanitize_filename(filename: &str) -> (String, Vec<&'static str>) {
+    log::trace!("sanitize_filename({:?}) ...", filename);
+
+    let mut changes = Vec::new();
+
+    let mut name = filename;
+    if let Some(i) = name.rfind(&['/', '\\'][..]) {
+        name = &name[i + 1..];
+        changes.push("directory components removed");
+    }
+
+    let mut result = String::with_capacity(name.len());
+    let (mut control, mut reserved) = (false, false);
+    for c in name.chars() {
+        if c.is_control() {
+            control = true;
+        } else if "<>:\"|?*".contains(c) {
+            reserved = true;
+            result.push('_');
+        } else {
+            result.push(c);
+        }
+    }
+    if control {
+        changes.push("control characters removed");
+    }
+    if reserved {
+        changes.push("reserved characters replaced");
+    }
+
+    let trimmed = result.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.len() != result.len() {
+        result = trimmed.to_string();
+        changes.push("leading and trailing dots and spaces removed");
+    }
+
+    let stem = result.split('.').next().unwrap_or("").trim_end();
+    if RESERVED_NAMES.iter().any(|x| x.eq_ignore_ascii_case(stem)) {
+        result.insert(0, '_');
+        changes.push("reserved device name escaped");
+    }
+
+    if result.len() > MAX_FILENAME_LENGTH {
+        let ext = match result.rfind('.') {
+            Some(i) if result.len() - i <= 16 => result[i..].to_string(),
+            _ => String::new(),
+        };
+        let mut end = MAX_FILENAME_LENGTH - ext.len();
+        while !result.is_char_boundary(end) {
+            end -= 1;
+        }
+        result = format!("{}{}", &result[..end], ext);
+        changes.push("filename truncated");
+    }
+
+    log::debug!(
+        "sanitize_filename({:?}) => {:?}, {:?}",
+        filename,
+        result,
+        changes
+    );
+    (result, changes)
+}
 
 /// Try to normalize specified image filename with respect of mime type.
 ///
@@ -38,32 +140,14 @@ pub fn normalize_image_filename(filename: &str, content_type: &str) -> String {
         &filename,
         &content_type
     );
-    fn ext_for(content_type: &str) -> &str {
-        let pair: Vec<&str> = content_type.split("/").collect();
-        if content_type.to_lowercase().starts_with("image/") && pair.len() == 2 {
-            match pair[1] {
-                "jpeg" => "jpg",
-                "pjpeg" => "jpg",
-                "svg+xml" => "svg",
-                "tiff" => "tif",
-                "vnd.microsoft.icon" => "ico",
-                "vnd.wap.wbmp" => "wbmp",
-                "*" => "bin",
-                x => x,
-            }
-        } else {
-            "bin"
-        }
-    }
-
     let result = if filename.trim().len() == 0 {
         format!(
             "untitled@{}.{}",
             Utc::now().format("%y%m%d%H%M%S%6f"),
-            ext_for(content_type)
+            extension_for(content_type)
         )
     } else if !filename.contains('.') {
-        format!("{}.{}", filename, ext_for(content_type))
+        format!("{}.{}", filename, extension_for(content_type))
     } else {
         filename.to_string()
     };
@@ -77,6 +161,375 @@ pub fn normalize_image_filename(filename: &str, content_type: &str) -> String {
     result
 }
 
+/// Infer filename extension for the image of the specified MIME type.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///
+///     assert_eq!(file_utils::extension_for("image/jpeg"), "jpg");
+///     assert_eq!(file_utils::extension_for("image/png"), "png");
+///     assert_eq!(file_utils::extension_for("text/html"), "bin");
+/// ```
+pub fn extension_for(content_type: &str) -> &str {
+    let pair: Vec<&str> = content_type.split('/').collect();
+    if content_type.to_lowercase().starts_with("image/") && pair.len() == 2 {
+        match pair[1] {
+            "jpeg" => "jpg",
+            "pjpeg" => "jpg",
+            "svg+xml" => "svg",
+            "tiff" => "tif",
+            "vnd.microsoft.icon" => "ico",
+            "vnd.wap.wbmp" => "wbmp",
+            "x-portable-anymap" => "pnm",
+            "vnd.radiance" => "hdr",
+            "x-tga" => "tga",
+            "*" => "bin",
+            x => x,
+        }
+    } else {
+        "bin"
+    }
+}
+
+/// Infer MIME type of the stored image from its filename extension.
+///
+/// Performs the reverse of the extension mapping done by `normalize_image_filename`,
+/// unknown extensions are reported as "application/octet-stream".
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///
+///     assert_eq!(file_utils::content_type_for("photo.JPG"), "image/jpeg");
+///     assert_eq!(file_utils::content_type_for("icon.ico"), "image/vnd.microsoft.icon");
+///     assert_eq!(file_utils::content_type_for("data.bin"), "application/octet-stream");
+///     assert_eq!(file_utils::content_type_for("no-extension"), "application/octet-stream");
+/// ```
+pub fn content_type_for(filename: &str) -> &'static str {
+    let ext = match filename.rfind('.') {
+        Some(pos) => filename[pos + 1..].to_lowercase(),
+        None => String::new(),
+    };
+
+    match &ext[..] {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "tif" | "tiff" => "image/tiff",
+        "ico" => "image/vnd.microsoft.icon",
+        "wbmp" => "image/vnd.wap.wbmp",
+        "pnm" => "image/x-portable-anymap",
+        "hdr" => "image/vnd.radiance",
+        "tga" => "image/x-tga",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Open stored image data for read.
+///
+/// Opens the image file at the specified path and places a shared file lock on it,
+/// so concurrent writers can't modify the data while it is being read.
+/// The lock is held until the returned file is closed.
+///
+/// # Examples
+///
+/// ```rust
+///    use std::io::Read;
+///    use trlogic_test::file_utils;
+///
+///    let mut file_path = std::env::temp_dir();
+///    file_path.push("image-for-read.bin");
+///    file_utils::write_image_data(&b"IMAGE DATA"[..], &file_path).unwrap();
+///
+///    let mut buffer = String::new();
+///    file_utils::open_image_data(&file_path).unwrap().read_to_string(&mut buffer).unwrap();
+///    assert_eq!(buffer, "IMAGE DATA");
+/// ```
+pub fn open_image_data(source: &Path) -> io::Result<fs::File> {
+    log::trace!("open_image_data(\"{}\") ...", source.display());
+
+    let file = fs::OpenOptions::new().read(true).open(source);
+    if let Err(e) = &file {
+        log::warn!(
+            "I/O ERROR \"{}\" while {} file opening for read!",
+            e.to_string(),
+            &source.to_string_lossy()
+        );
+    };
+    let file = file?;
+
+    let lock = FileExt::lock_shared(&file);
+    if let Err(e) = &lock {
+        log::warn!(
+            "I/O ERROR \"{}\" while attempt to place shared lock on {} file!",
+            e.to_string(),
+            &source.to_string_lossy()
+        );
+    }
+    lock?;
+
+    log::debug!("open_image_data(\"{}\") => Ok(_)", source.display());
+    Ok(file)
+}
+
+/// Get a strong entity tag for the stored file.
+///
+/// The tag is built from the file size and modification time, so it changes whenever
+/// the file is rewritten with `write_image_data`.
+///
+/// # Examples
+///
+/// ```rust
+///    use trlogic_test::file_utils;
+///
+///    let mut file_path = std::env::temp_dir();
+///    file_path.push("image-for-etag.bin");
+///    file_utils::write_image_data(&b"IMAGE DATA"[..], &file_path).unwrap();
+///
+///    let etag = file_utils::etag_for(&std::fs::metadata(&file_path).unwrap());
+///    assert!(etag.starts_with("\"a-") && etag.ends_with('"'));
+/// ```
+pub fn etag_for(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    format!(
+        "\"{:x}-{:x}.{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    )
+}
+
+/// Format the time as HTTP-date (RFC 7231), i.e. for Last-Modified header.
+///
+/// # Examples
+///
+/// ```rust
+///    use std::time::{Duration, UNIX_EPOCH};
+///    use trlogic_test::file_utils;
+///
+///    let time = UNIX_EPOCH + Duration::from_secs(1_560_000_000);
+///    assert_eq!(file_utils::http_date(time), "Sat, 08 Jun 2019 13:20:00 GMT");
+/// ```
+pub fn http_date(time: std::time::SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Get the filename from the Content-Disposition header value (RFC 6266).
+///
+/// The extended "filename*" parameter in UTF-8 or ISO-8859-1 charset takes precedence
+/// over the plain "filename" one. The filename isn't sanitized.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///
+///     assert_eq!(
+///         file_utils::filename_from_content_disposition("attachment; filename=\"my \\\"cat\\\".jpg\""),
+///         Some(String::from("my \"cat\".jpg"))
+///     );
+///     assert_eq!(
+///         file_utils::filename_from_content_disposition(
+///             "inline; filename=photo.jpg; filename*=UTF-8''na%C3%AFve%20photo.jpg"
+///         ),
+///         Some(String::from("na\u{ef}ve photo.jpg"))
+///     );
+///     assert_eq!(file_utils::filename_from_content_disposition("inline"), None);
+/// ```
+pub fn filename_from_content_disposition(header: &str) -> Option<String> {
+    let mut filename = None;
+    let mut extended = None;
+    for (name, value) in header_params(header) {
+        if name.eq_ignore_ascii_case("filename") {
+            filename = Some(value);
+        } else if name.eq_ignore_ascii_case("filename*") {
+            extended = decode_ext_value(&value);
+        }
+    }
+    extended.or(filename).filter(|x| !x.is_empty())
+}
+
+/// Parse the parameters following the value of the header, i.e. `type; name="value"`.
+fn header_params(header: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = header.chars().skip_while(|&c| c != ';').peekable();
+
+    loop {
+        while chars.next_if(|&c| c == ';' || c.is_whitespace()).is_some() {}
+
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ';') {
+            name.push(c);
+        }
+        let name = name.trim_end().to_string();
+        if name.is_empty() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            while chars.next_if(|&c| c != ';').is_some() {}
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ';') {
+                value.push(c);
+            }
+            value.truncate(value.trim_end().len());
+        }
+        params.push((name, value));
+    }
+
+    params
+}
+
+/// Decode the extended parameter value (RFC 8187), i.e. `UTF-8'en'%C3%A9t%C3%A9`.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes: Vec<u8> = percent_decode(encoded.as_bytes()).collect();
+
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+/// Get the filename from the last segment of the URL path.
+///
+/// The query and the fragment are ignored, the segment is percent-decoded.
+/// Returns an empty string if the path ends with "/".
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///     use url::Url;
+///
+///     let url = Url::parse("https://cdn/x/my%20photo.jpg?w=200&sig=abc#top").unwrap();
+///     assert_eq!(file_utils::filename_from_url(&url), "my photo.jpg");
+///
+///     let url = Url::parse("https://cdn/x/?name=photo.jpg").unwrap();
+///     assert_eq!(file_utils::filename_from_url(&url), "");
+/// ```
+pub fn filename_from_url(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut x| x.next_back())
+        .map(|x| {
+            percent_decode(x.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .unwrap_or_default()
+}
+
+/// Get a unique temporary path next to the target file.
+///
+/// Temporary files are hidden and have ".tmp" extension, so they can be written
+/// and then atomically renamed to the target path.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::path::Path;
+///     use trlogic_test::file_utils;
+///
+///     let tmp_path = file_utils::temp_path_for(Path::new("uploads/photo.jpg"));
+///     assert_eq!(tmp_path.parent(), Some(Path::new("uploads")));
+///     assert!(tmp_path.to_string_lossy().ends_with(".tmp"));
+///     assert_ne!(tmp_path, file_utils::temp_path_for(Path::new("uploads/photo.jpg")));
+/// ```
+pub fn temp_path_for(target: &Path) -> PathBuf {
+    let counter = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let filename = target.file_name().unwrap_or_default().to_string_lossy();
+
+    target.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        filename,
+        std::process::id(),
+        counter
+    ))
+}
+
+/// Remove the temporary files left in the directory.
+///
+/// Temporary files made with `temp_path_for`, with or without an extension appended,
+/// aren't renamed to the targets when the process is stopped while writing them, so they
+/// are removed before the directory is used by the service. Returns the number of the removed files, a missing directory
+/// has none.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::file_utils;
+///
+///     let mut dir = std::env::temp_dir();
+///     dir.push("test-remove-temp-files");
+///     let _ = std::fs::remove_dir_all(&dir);
+///     assert_eq!(file_utils::remove_temp_files(&dir).unwrap(), 0);
+///
+///     std::fs::create_dir_all(&dir).unwrap();
+///     std::fs::write(file_utils::temp_path_for(&dir.join("photo.jpg")), b"DATA").unwrap();
+///     std::fs::write(dir.join(".photo.jpg.1-0.tmp"), b"DATA").unwrap();
+///     std::fs::write(dir.join(".photo.jpg.1-1.tmp.png"), b"DATA").unwrap();
+///     std::fs::write(dir.join("photo.tmp"), b"DATA").unwrap();
+///     assert_eq!(file_utils::remove_temp_files(&dir).unwrap(), 3);
+///     assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+///
+///     std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn remove_temp_files(dir: &Path) -> io::Result<usize> {
+    log::trace!("remove_temp_files(\"{}\") ...", dir.display());
+
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let filename = entry.file_name();
+        let filename = filename.to_string_lossy();
+        let temporary = filename.starts_with('.')
+            && filename.rsplit('.').take(2).any(|x| x == "tmp")
+            && entry.file_type()?.is_file();
+        if temporary {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    log::debug!("remove_temp_files => {} files", removed);
+    Ok(removed)
+}
+
 /// Save image data to disk storage.
 ///
 /// Saves image data to the specified path on the disk storage.
@@ -98,16 +551,74 @@ pub fn normalize_image_filename(filename: &str, content_type: &str) -> String {
 ///        image_data.len() as u64
 ///    );
 /// ```
-pub fn write_image_data<R: io::Read>(mut source: R, target: &Path) -> io::Result<u64> {
+pub fn write_image_data<R: io::Read>(source: R, target: &Path) -> io::Result<u64> {
     log::trace!("write_image_data(R, \"{}\") ...", target.display());
 
-    let file = fs::OpenOptions::new().write(true).create(true).open(target);
+    let result = write_image_file(
+        source,
+        target,
+        fs::OpenOptions::new().write(true).create(true),
+    );
+
+    log::debug!(
+        "write_image_data(R, \"{}\") => {:?}",
+        target.display(),
+        result
+    );
+    result
+}
+
+/// Save image data to a new file on disk storage.
+///
+/// Same as `write_image_data`, but fails with `io::ErrorKind::AlreadyExists` error
+/// if the file exists, so concurrent uploads can't replace each other's data.
+///
+/// # Examples
+///
+/// ```rust
+///    use trlogic_test::file_utils;
+///
+///    let mut file_path = std::env::temp_dir();
+///    file_path.push("image-created.bin");
+///    let _ = std::fs::remove_file(&file_path);
+///
+///    assert_eq!(file_utils::create_image_data(&b"IMAGE"[..], &file_path).unwrap(), 5);
+///    assert_eq!(
+///        file_utils::create_image_data(&b"IMAGE"[..], &file_path).unwrap_err().kind(),
+///        std::io::ErrorKind::AlreadyExists
+///    );
+/// ```
+pub fn create_image_data<R: io::Read>(source: R, target: &Path) -> io::Result<u64> {
+    log::trace!("create_image_data(R, \"{}\") ...", target.display());
+
+    let result = write_image_file(
+        source,
+        target,
+        fs::OpenOptions::new().write(true).create_new(true),
+    );
+
+    log::debug!(
+        "create_image_data(R, \"{}\") => {:?}",
+        target.display(),
+        result
+    );
+    result
+}
+
+fn write_image_file<R: io::Read>(
+    mut source: R,
+    target: &Path,
+    options: &fs::OpenOptions,
+) -> io::Result<u64> {
+    let file = options.open(target);
     if let Err(e) = &file {
-        log::warn!(
-            "I/O ERROR \"{}\" while {} file opening for write!",
-            e.to_string(),
-            &target.to_string_lossy()
-        );
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            log::warn!(
+                "I/O ERROR \"{}\" while {} file opening for write!",
+                e.to_string(),
+                &target.to_string_lossy()
+            );
+        }
     };
     let mut file = file?;
 
@@ -133,7 +644,153 @@ pub fn write_image_data<R: io::Read>(mut source: R, target: &Path) -> io::Result
         );
     }
 
-    let lock = file.unlock();
+    let lock = FileExt::unlock(&file);
+    if let Err(e) = &lock {
+        log::warn!(
+            "I/O ERROR \"{}\" while attempt to free exclusive lock on {} file!",
+            e.to_string(),
+            &target.to_string_lossy()
+        );
+    }
+
+    result
+}
+
+/// Move image data from the temporary file to disk storage.
+///
+/// The temporary file is expected to be made with `temp_path_for` next to the target.
+/// If `create_new` is set, fails with `io::ErrorKind::AlreadyExists` error when the target
+/// exists, like `create_image_data`, otherwise the stored image is replaced atomically.
+///
+/// # Examples
+///
+/// ```rust
+///    use trlogic_test::file_utils;
+///
+///    let mut file_path = std::env::temp_dir();
+///    file_path.push("image-committed.bin");
+///    let _ = std::fs::remove_file(&file_path);
+///
+///    let tmp_path = file_utils::temp_path_for(&file_path);
+///    std::fs::write(&tmp_path, b"IMAGE").unwrap();
+///    file_utils::commit_image_data(&tmp_path, &file_path, true).unwrap();
+///    assert!(!tmp_path.exists());
+///    assert_eq!(std::fs::read(&file_path).unwrap(), b"IMAGE");
+/// ```
+pub fn commit_image_data(tmp_path: &Path, target: &Path, create_new: bool) -> io::Result<()> {
+    log::trace!(
+        "commit_image_data(\"{}\", \"{}\", {}) ...",
+        tmp_path.display(),
+        target.display(),
+        create_new
+    );
+
+    let result = if create_new {
+        fs::hard_link(tmp_path, target).and_then(|_| fs::remove_file(tmp_path))
+    } else {
+        fs::rename(tmp_path, target)
+    };
+
+    if let Err(e) = &result {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            log::warn!(
+                "I/O ERROR \"{}\" while moving {} file to {}!",
+                e,
+                tmp_path.display(),
+                target.display()
+            );
+        }
+    }
+
+    log::debug!("commit_image_data => {:?}", result);
+    result
+}
+
+/// Get the filename with the number suffix inserted before the extension.
+///
+/// # Examples
+///
+/// ```rust
+///    use trlogic_test::file_utils;
+///
+///    assert_eq!(file_utils::numbered_filename("photo.jpg", 2), "photo-2.jpg");
+///    assert_eq!(file_utils::numbered_filename("photo", 1), "photo-1");
+/// ```
+pub fn numbered_filename(filename: &str, number: usize) -> String {
+    match filename.rfind('.') {
+        Some(i) if i > 0 => format!("{}-{}{}", &filename[..i], number, &filename[i..]),
+        _ => format!("{}-{}", filename, number),
+    }
+}
+
+/// Remove image data from disk storage.
+///
+/// Removes the image file at the specified path, returning the size of removed data.
+/// An exclusive file lock is acquired before removal, so the file isn't removed while
+/// it is being written or read. The lock isn't waited for, if the file is locked
+/// the removal fails with `io::ErrorKind::WouldBlock` error.
+///
+/// # Examples
+///
+/// ```rust
+///    use trlogic_test::file_utils;
+///
+///    let mut file_path = std::env::temp_dir();
+///    file_path.push("image-for-remove.bin");
+///    file_utils::write_image_data(&b"IMAGE DATA"[..], &file_path).unwrap();
+///
+///    let file = file_utils::open_image_data(&file_path).unwrap();
+///    assert_eq!(
+///        file_utils::remove_image_data(&file_path).unwrap_err().kind(),
+///        std::io::ErrorKind::WouldBlock
+///    );
+///    drop(file);
+///
+///    assert_eq!(file_utils::remove_image_data(&file_path).unwrap(), 10);
+///    assert!(!file_path.exists());
+/// ```
+pub fn remove_image_data(target: &Path) -> io::Result<u64> {
+    log::trace!("remove_image_data(\"{}\") ...", target.display());
+
+    let file = fs::OpenOptions::new().write(true).open(target);
+    if let Err(e) = &file {
+        log::warn!(
+            "I/O ERROR \"{}\" while {} file opening for remove!",
+            e.to_string(),
+            &target.to_string_lossy()
+        );
+    };
+    let file = file?;
+
+    let lock = FileExt::try_lock_exclusive(&file);
+    if let Err(e) = &lock {
+        log::warn!(
+            "I/O ERROR \"{}\" while attempt to place exclusive lock on {} file!",
+            e.to_string(),
+            &target.to_string_lossy()
+        );
+    }
+    lock.map_err(|e| {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            io::Error::new(io::ErrorKind::WouldBlock, "image file is locked")
+        } else {
+            e
+        }
+    })?;
+
+    let result = file
+        .metadata()
+        .and_then(|metadata| fs::remove_file(target).map(|_| metadata.len()));
+
+    if let Err(e) = &result {
+        log::warn!(
+            "I/O ERROR: \"{}\" while removing image file {}!",
+            e,
+            target.display()
+        );
+    }
+
+    let lock = FileExt::unlock(&file);
     if let Err(e) = &lock {
         log::warn!(
             "I/O ERROR \"{}\" while attempt to free exclusive lock on {} file!",
@@ -143,7 +800,7 @@ pub fn write_image_data<R: io::Read>(mut source: R, target: &Path) -> io::Result
     }
 
     log::debug!(
-        "write_image_data(R, \"{}\") => {:?}",
+        "remove_image_data(\"{}\") => {:?}",
         target.display(),
         result
     );
@@ -159,6 +816,38 @@ mod tests {
     use std::sync::Mutex;
     use std::thread;
 
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(
+            super::sanitize_filename("C:\\Users\\me\\photo.jpg"),
+            (
+                String::from("photo.jpg"),
+                vec!["directory components removed"]
+            )
+        );
+        assert_eq!(
+            super::sanitize_filename("pho\0to\n.png"),
+            (
+                String::from("photo.png"),
+                vec!["control characters removed"]
+            )
+        );
+        assert_eq!(
+            super::sanitize_filename(".hidden.png "),
+            (
+                String::from("hidden.png"),
+                vec!["leading and trailing dots and spaces removed"]
+            )
+        );
+        assert_eq!(super::sanitize_filename("LPT1").0, "_LPT1");
+        assert_eq!(super::sanitize_filename("/").0, "");
+
+        let (filename, changes) = super::sanitize_filename(&format!("{}.jpeg", "я".repeat(150)));
+        assert!(filename.len() <= super::MAX_FILENAME_LENGTH);
+        assert!(filename.ends_with("я.jpeg"));
+        assert_eq!(changes, ["filename truncated"]);
+    }
+
     #[test]
     fn test_normalize_image_filename() {
         // Test for empty filename.
@@ -222,6 +911,102 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_content_type_for() {
+        assert_eq!(super::content_type_for("a.jpg"), "image/jpeg");
+        assert_eq!(super::content_type_for("a.jpeg"), "image/jpeg");
+        assert_eq!(super::content_type_for("a.PNG"), "image/png");
+        assert_eq!(super::content_type_for("a.svg"), "image/svg+xml");
+        assert_eq!(super::content_type_for("a.tif"), "image/tiff");
+        assert_eq!(super::content_type_for("a.ico"), "image/vnd.microsoft.icon");
+        assert_eq!(super::content_type_for("a.wbmp"), "image/vnd.wap.wbmp");
+        assert_eq!(super::content_type_for("a.bin"), "application/octet-stream");
+        assert_eq!(
+            super::content_type_for("a.~unknown~"),
+            "application/octet-stream"
+        );
+        assert_eq!(super::content_type_for("a"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_filename_from_content_disposition() {
+        let filename = |header: &str| super::filename_from_content_disposition(header);
+
+        // Test for plain parameter, quoted or token.
+        assert_eq!(
+            filename("attachment; filename=\"photo.jpg\""),
+            Some(String::from("photo.jpg"))
+        );
+        assert_eq!(
+            filename("attachment;filename=photo.jpg ; size=100"),
+            Some(String::from("photo.jpg"))
+        );
+        assert_eq!(
+            filename("attachment; FileName = \"a; b=c.jpg\""),
+            Some(String::from("a; b=c.jpg"))
+        );
+        assert_eq!(
+            filename("inline; filename=\"\\\\dir\\\\photo.jpg\""),
+            Some(String::from("\\dir\\photo.jpg"))
+        );
+
+        // Test for extended parameter (takes precedence regardless of the order).
+        assert_eq!(
+            filename("attachment; filename*=utf-8'en'%E2%82%AC%20rates.png; filename=rates.png"),
+            Some(String::from("\u{20ac} rates.png"))
+        );
+        assert_eq!(
+            filename("attachment; filename*=ISO-8859-1''caf%E9.jpg"),
+            Some(String::from("caf\u{e9}.jpg"))
+        );
+
+        // Test for extended parameter which can't be decoded (plain one is used).
+        assert_eq!(
+            filename("attachment; filename=photo.jpg; filename*=KOI8-R''%F0%C9.jpg"),
+            Some(String::from("photo.jpg"))
+        );
+        assert_eq!(
+            filename("attachment; filename=photo.jpg; filename*=UTF-8''%FF.jpg"),
+            Some(String::from("photo.jpg"))
+        );
+        assert_eq!(
+            filename("attachment; filename=photo.jpg; filename*=photo.png"),
+            Some(String::from("photo.jpg"))
+        );
+
+        // Test for missing or empty filename.
+        assert_eq!(filename(""), None);
+        assert_eq!(filename("attachment"), None);
+        assert_eq!(filename("attachment; name=photo.jpg"), None);
+        assert_eq!(filename("attachment; filename"), None);
+        assert_eq!(filename("attachment; filename=\"\""), None);
+        assert_eq!(filename("attachment; filename*=UTF-8''"), None);
+    }
+
+    #[test]
+    fn test_filename_from_url() {
+        let filename = |url: &str| super::filename_from_url(&url::Url::parse(url).unwrap());
+
+        assert_eq!(filename("https://cdn/x/photo.jpg"), "photo.jpg");
+        assert_eq!(
+            filename("https://cdn/x/photo.jpg?w=200&sig=abc"),
+            "photo.jpg"
+        );
+        assert_eq!(filename("https://cdn/x/photo.jpg#top"), "photo.jpg");
+        assert_eq!(
+            filename("https://cdn/x/%D1%84%D0%BE%D1%82%D0%BE%201.jpg"),
+            "\u{444}\u{43e}\u{442}\u{43e} 1.jpg"
+        );
+        assert_eq!(filename("https://cdn/x/a%2Fb.jpg"), "a/b.jpg");
+        assert_eq!(filename("https://cdn/x/%FF.jpg"), "\u{fffd}.jpg");
+
+        // Test for URL without filename in the path.
+        assert_eq!(filename("https://cdn/x/"), "");
+        assert_eq!(filename("https://cdn"), "");
+        assert_eq!(filename("https://cdn/?file=photo.jpg"), "");
+        assert_eq!(filename("data:image/png;base64,AAAA"), "");
+    }
+
     #[test]
     fn test_write_image_data() {
         let mut file_path = std::env::temp_dir();
@@ -0,0 +1,627 @@
+use lazy_static::lazy_static;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use url::{Host, Position, Url};
+
+use super::settings::{DownloadSettings, HostRule};
+
+lazy_static! {
+    static ref TLS_CONFIG: Arc<rustls::ClientConfig> = {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        Arc::new(config)
+    };
+}
+
+/// Maximal size of the response status line and headers.
+const MAX_HEAD_SIZE: u64 = 64 * 1024;
+/// Maximal number of the response headers.
+const MAX_HEADERS: usize = 64;
+/// Maximal number of host name resolutions in progress, including the abandoned ones.
+const MAX_RESOLUTIONS: usize = 32;
+/// User-Agent header of the requests, some origins reject requests without it.
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+static RESOLUTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Connection to the origin, plain or TLS.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// HTTP response with the body being read from the connection.
+pub struct Response {
+    /// URL of the response after the redirects.
+    pub url: Url,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Number of the requests made, redirects and retries included.
+    pub attempts: u32,
+    /// Response body with the transfer encoding decoded.
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    /// Get the header value, header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Response {{ url: \"{}\", status: {}, headers: {:?}, attempts: {} }}",
+            self.url, self.status, self.headers, self.attempts
+        )
+    }
+}
+
+/// Download failure: the reason and the request made before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    /// URL of the last request.
+    pub url: String,
+    /// Number of the requests made, redirects and retries included.
+    pub attempts: u32,
+    pub reason: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+/// Make a GET request to the URL.
+///
+/// Redirects are followed up to the maximal number. Connection errors and HTTP 5xx
+/// responses are retried with the exponential backoff, the last 5xx response is returned
+/// when the retries are exhausted. Redirects aren't counted as retries.
+/// Addresses of every request, redirects included, are checked with `check_addr`.
+/// If the deadline is specified, timeouts are shortened to it and reading of the body
+/// fails with `io::ErrorKind::TimedOut` error after it.
+/// Requests are sent with the `User-Agent` header, interim 1xx responses are skipped.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::io::{BufRead, BufReader, Read, Write};
+///     use std::net::TcpListener;
+///     use trlogic_test::http_client;
+///     use trlogic_test::settings::DownloadSettings;
+///
+///     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+///     let url = format!("http://localhost:{}/image", listener.local_addr().unwrap().port());
+///     let origin = std::thread::spawn(move || {
+///         let (stream, _) = listener.accept().unwrap();
+///         let mut reader = BufReader::new(stream.try_clone().unwrap());
+///         let mut head = String::new();
+///         while reader.read_line(&mut head).unwrap() > 2 {}
+///         let mut stream = stream;
+///         stream
+///             .write_all(b"HTTP/1.1 100 Continue\r\n\r\n\
+///                 HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+///                 HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody")
+///             .unwrap();
+///         head
+///     });
+///
+///     let mut settings = DownloadSettings::default();
+///     settings.allow_hosts = vec!["localhost".parse().unwrap()];
+///     let mut response = http_client::get(&url, &settings, None).unwrap();
+///     let mut body = String::new();
+///     response.body.read_to_string(&mut body).unwrap();
+///     assert_eq!((response.status, &body[..]), (200, "body"));
+///     assert_eq!(response.header("Link"), None);
+///     assert!(origin.join().unwrap().contains("\r\nUser-Agent: trlogic_test/"));
+/// ```
+pub fn get(
+    url: &str,
+    settings: &DownloadSettings,
+    deadline: Option<Instant>,
+) -> Result<Response, Error> {
+    log::trace!("get(\"{}\", {:?}) ...", url, deadline);
+
+    let mut attempts = 0;
+    let mut redirects = 0;
+    let mut retries = 0;
+    let mut url = Url::parse(url).map_err(|e| Error {
+        url: url.to_string(),
+        attempts,
+        reason: format!("invalid URL: {}", e),
+    })?;
+
+    let response = loop {
+        attempts += 1;
+        let fail = |reason: String| Error {
+            url: url.to_string(),
+            attempts,
+            reason,
+        };
+
+        let retry = match request(&url, settings, deadline) {
+            Ok(response) if is_redirect(response.status) => {
+                let location = match response.header("Location") {
+                    Some(x) => url
+                        .join(x)
+                        .map_err(|e| fail(format!("invalid redirect: {}", e)))?,
+                    None => break response,
+                };
+                redirects += 1;
+                if redirects > settings.max_redirects {
+                    return Err(fail(String::from("too many redirects")));
+                }
+                log::debug!("get: redirect to \"{}\"", location);
+                url = location;
+                continue;
+            }
+            Ok(response) if response.status >= 500 && retries < settings.retries => {
+                format!("HTTP status {}", response.status)
+            }
+            Ok(response) => break response,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::InvalidInput
+                    || e.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                return Err(fail(e.to_string()));
+            }
+            Err(e) if retries < settings.retries => e.to_string(),
+            Err(e) => return Err(fail(e.to_string())),
+        };
+
+        let delay = settings.retry_delay * 2u32.saturating_pow(retries);
+        if matches!(deadline, Some(x) if Instant::now() + delay >= x) {
+            log::warn!(
+                "Download of \"{}\" failed with \"{}\", no time to retry!",
+                url,
+                retry
+            );
+            return Err(fail(String::from("download deadline exceeded")));
+        }
+        log::warn!(
+            "Download of \"{}\" failed with \"{}\", retry in {:?}!",
+            url,
+            retry,
+            delay
+        );
+        std::thread::sleep(delay);
+        retries += 1;
+    };
+
+    let response = Response {
+        url,
+        attempts,
+        ..response
+    };
+    log::debug!("get => {:?}", response);
+    Ok(response)
+}
+
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Make a single GET request to the URL.
+///
+/// Unsupported URLs, HTTPS ones with IP address host included, are reported with
+/// `io::ErrorKind::InvalidInput` error, hosts without allowed addresses with
+/// `io::ErrorKind::PermissionDenied` one.
+fn request(
+    url: &Url,
+    settings: &DownloadSettings,
+    deadline: Option<Instant>,
+) -> io::Result<Response> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason);
+
+    let https = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        _ => return Err(invalid("unsupported URL scheme")),
+    };
+    let host = url.host().ok_or_else(|| invalid("URL without host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| invalid("URL without port"))?;
+    // Certificates can be verified for domain names only.
+    let domain = match &host {
+        Host::Domain(x) => Some(*x),
+        _ => None,
+    };
+    if https && domain.is_none() {
+        return Err(invalid("HTTPS URL with IP address"));
+    }
+
+    let tcp = connect(&host, port, settings, deadline)?;
+    tcp.set_read_timeout(Some(timeout(settings.read_timeout, deadline)?))?;
+    tcp.set_write_timeout(Some(timeout(settings.read_timeout, deadline)?))?;
+    let deadline_tcp = match deadline {
+        Some(x) => Some((x, tcp.try_clone()?)),
+        None => None,
+    };
+
+    let mut stream: Box<dyn Stream> = if let (true, Some(domain)) = (https, domain) {
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(domain)
+            .map_err(|_| invalid("invalid domain name"))?;
+        let session = rustls::ClientSession::new(&TLS_CONFIG, dns_name);
+        Box::new(rustls::StreamOwned::new(session, tcp))
+    } else {
+        Box::new(tcp)
+    };
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: image/*\r\nConnection: close\r\n\r\n",
+        &url[Position::BeforePath..Position::AfterQuery],
+        host_header,
+        USER_AGENT
+    )?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, headers) = read_head(&mut reader)?;
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.to_lowercase())
+    };
+    let chunked = matches!(header("Transfer-Encoding"), Some(x) if x.contains("chunked"));
+    let content_length = header("Content-Length").and_then(|x| x.parse::<u64>().ok());
+
+    let body: Box<dyn Read + Send> = match content_length {
+        _ if chunked => Box::new(ChunkedReader {
+            decoder: chunked_transfer::Decoder::new(reader),
+            done: false,
+        }),
+        Some(length) => Box::new(reader.take(length)),
+        None => Box::new(reader),
+    };
+    let body: Box<dyn Read + Send> = match deadline_tcp {
+        Some((deadline, tcp)) => Box::new(DeadlineReader {
+            body,
+            tcp,
+            read_timeout: settings.read_timeout,
+            deadline,
+        }),
+        None => body,
+    };
+
+    Ok(Response {
+        url: url.clone(),
+        status,
+        headers,
+        attempts: 1,
+        body,
+    })
+}
+
+/// Connect to the host trying all its allowed addresses.
+///
+/// The connection is made to the checked address, so the host can't be resolved to
+/// another address in between. The host name resolution is limited by the connect
+/// timeout too, see `resolve`.
+fn connect(
+    host: &Host<&str>,
+    port: u16,
+    settings: &DownloadSettings,
+    deadline: Option<Instant>,
+) -> io::Result<TcpStream> {
+    let (domain, addrs): (_, Vec<SocketAddr>) = match host {
+        Host::Domain(domain) => {
+            let resolve_timeout = timeout(settings.connect_timeout, deadline)?;
+            (Some(*domain), resolve(domain, port, resolve_timeout)?)
+        }
+        Host::Ipv4(ip) => (None, vec![SocketAddr::new((*ip).into(), port)]),
+        Host::Ipv6(ip) => (None, vec![SocketAddr::new((*ip).into(), port)]),
+    };
+
+    let mut denied = None;
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|x| match check_addr(domain, x.ip(), settings) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Download from {} is blocked, {}!", x, e);
+                denied.get_or_insert(e);
+                false
+            }
+        })
+        .collect();
+    if let Some(e) = denied.filter(|_| addrs.is_empty()) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+    }
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host not resolved");
+    for addr in addrs {
+        let connect_timeout = timeout(settings.connect_timeout, deadline)?;
+        match TcpStream::connect_timeout(&addr, connect_timeout) {
+            Ok(x) => return Ok(x),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Resolve the host name to the socket addresses.
+///
+/// The system resolver can't be interrupted, so the name is resolved on a worker thread
+/// and the resolution is abandoned with `io::ErrorKind::TimedOut` error after the timeout.
+/// Abandoned resolutions keep their threads until they're complete, so the number of
+/// resolutions in progress is limited by `MAX_RESOLUTIONS`, over the limit the name isn't
+/// resolved.
+fn resolve(domain: &str, port: u16, timeout: Duration) -> io::Result<Vec<SocketAddr>> {
+    if RESOLUTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_RESOLUTIONS {
+        RESOLUTIONS.fetch_sub(1, Ordering::SeqCst);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "too many host name resolutions in progress",
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let domain = domain.to_string();
+    std::thread::spawn(move || {
+        let addrs = (&domain[..], port).to_socket_addrs().map(Iterator::collect);
+        RESOLUTIONS.fetch_sub(1, Ordering::SeqCst);
+        let _ = tx.send(addrs);
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(addrs) => addrs,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "host name resolution timed out",
+        )),
+    }
+}
+
+/// Shorten the timeout to the deadline.
+///
+/// Fails with `io::ErrorKind::TimedOut` error if the deadline is passed.
+fn timeout(timeout: Duration, deadline: Option<Instant>) -> io::Result<Duration> {
+    let timeout = match deadline {
+        Some(x) => timeout.min(x.saturating_duration_since(Instant::now())),
+        None => timeout,
+    };
+    if timeout == Duration::from_secs(0) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "download deadline exceeded",
+        ));
+    }
+    Ok(timeout)
+}
+
+/// Reader of the response body shortening the connection read timeout to the deadline.
+struct DeadlineReader {
+    body: Box<dyn Read + Send>,
+    /// Clone of the connection to set the read timeout of.
+    tcp: TcpStream,
+    read_timeout: Duration,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_timeout = timeout(self.read_timeout, Some(self.deadline))?;
+        self.tcp.set_read_timeout(Some(read_timeout))?;
+        // Transfer decoders may report the read timeout as invalid data.
+        self.body
+            .read(buf)
+            .map_err(|e| match timeout(self.read_timeout, Some(self.deadline)) {
+                Err(deadline) => deadline,
+                Ok(_) => e,
+            })
+    }
+}
+
+/// Reader of the chunked response body.
+///
+/// The decoder fails if it's read again after the last chunk, so the end of the body
+/// is remembered and reported to every next read.
+struct ChunkedReader<R> {
+    decoder: chunked_transfer::Decoder<R>,
+    done: bool,
+}
+
+impl<R: Read> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let read = self.decoder.read(buf)?;
+        self.done = read == 0;
+        Ok(read)
+    }
+}
+
+/// Check whether the download from the host address is allowed.
+///
+/// Hosts matching the deny list are denied, hosts matching the allow list are allowed,
+/// other hosts are allowed if the address is a public one. Rules are matched against
+/// both the domain name, if any, and the address.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::http_client;
+///     use trlogic_test::settings::DownloadSettings;
+///
+///     let mut settings = DownloadSettings::default();
+///     let localhost = "127.0.0.1".parse().unwrap();
+///     assert!(http_client::check_addr(Some("localhost"), localhost, &settings).is_err());
+///
+///     settings.allow_hosts = vec!["localhost".parse().unwrap()];
+///     assert!(http_client::check_addr(Some("localhost"), localhost, &settings).is_ok());
+///     assert!(http_client::check_addr(None, localhost, &settings).is_err());
+///
+///     settings.deny_hosts = vec!["127.0.0.0/8".parse().unwrap()];
+///     assert!(http_client::check_addr(Some("localhost"), localhost, &settings).is_err());
+/// ```
+pub fn check_addr(
+    domain: Option<&str>,
+    addr: IpAddr,
+    settings: &DownloadSettings,
+) -> Result<(), String> {
+    let matches = |rule: &HostRule| {
+        rule.matches_addr(addr) || matches!(domain, Some(x) if rule.matches_domain(x))
+    };
+
+    if settings.deny_hosts.iter().any(matches) {
+        Err(format!("address {} is denied", addr))
+    } else if settings.allow_hosts.iter().any(matches) || is_public(addr) {
+        Ok(())
+    } else {
+        Err(format!("address {} is not public", addr))
+    }
+}
+
+/// Check whether the address is a public one, i.e. it isn't a loopback, private,
+/// link-local, multicast or other special-purpose address.
+///
+/// IPv6 addresses embedding IPv4 ones, i.e. IPv4-mapped, IPv4-compatible, NAT64 and 6to4
+/// ones, are checked as the embedded IPv4 addresses. Teredo addresses are public if both
+/// the server and the client IPv4 addresses are public.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::http_client;
+///
+///     assert!(http_client::is_public("93.184.216.34".parse().unwrap()));
+///     assert!(!http_client::is_public("169.254.169.254".parse().unwrap()));
+///     assert!(!http_client::is_public("192.168.0.1".parse().unwrap()));
+///     assert!(!http_client::is_public("192.0.0.170".parse().unwrap()));
+///     assert!(!http_client::is_public("198.19.0.1".parse().unwrap()));
+///     assert!(!http_client::is_public("240.0.0.1".parse().unwrap()));
+///     assert!(!http_client::is_public("::1".parse().unwrap()));
+///     assert!(!http_client::is_public("::ffff:127.0.0.1".parse().unwrap()));
+///     assert!(!http_client::is_public("::10.0.0.1".parse().unwrap()));
+///     assert!(!http_client::is_public("64:ff9b::192.168.0.1".parse().unwrap()));
+///     assert!(http_client::is_public("64:ff9b::93.184.216.34".parse().unwrap()));
+///     assert!(!http_client::is_public("2002:7f00:1::1".parse().unwrap()));
+///     assert!(!http_client::is_public("2002:a9fe:a9fe::1".parse().unwrap()));
+///     assert!(http_client::is_public("2002:5db8:d822::1".parse().unwrap()));
+///     assert!(!http_client::is_public("2001:0:4136:e378:8000:63bf:80ff:fffe".parse().unwrap()));
+///     assert!(!http_client::is_public("2001:0:a00:1:8000:63bf:a247:27dd".parse().unwrap()));
+///     assert!(http_client::is_public("2001:0:4136:e378:8000:63bf:a247:27dd".parse().unwrap()));
+///     assert!(!http_client::is_public("2001:db8::1".parse().unwrap()));
+///     assert!(!http_client::is_public("203.0.113.1".parse().unwrap()));
+///     assert!(!http_client::is_public("fd00::1".parse().unwrap()));
+///     assert!(http_client::is_public("2606:2800:220:1::1".parse().unwrap()));
+/// ```
+pub fn is_public(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(x) => {
+            let [a, b, c, _] = x.octets();
+            !(x.is_loopback()
+                || x.is_private()
+                || x.is_link_local()
+                || x.is_multicast()
+                || x.is_unspecified()
+                || a == 0
+                || a >= 240
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 192 && b == 0 && (c == 0 || c == 2))
+                || (a == 198 && b & 0xfe == 18)
+                || (a == 198 && b == 51 && c == 100)
+                || (a == 203 && b == 0 && c == 113))
+        }
+        IpAddr::V6(x) => match x.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..]
+            | [0, 0, 0, 0, 0, 0, ..]
+            | [0x64, 0xff9b, 0, 0, 0, 0, ..] => {
+                let [.., a, b, c, d] = x.octets();
+                is_public(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+            }
+            // 6to4 address embeds IPv4 address right after the prefix.
+            [0x2002, high, low, ..] => is_public(ipv4_from_segments(high, low)),
+            // Teredo address embeds the server address and the inverted client address.
+            [0x2001, 0, server_high, server_low, _, _, client_high, client_low] => {
+                is_public(ipv4_from_segments(server_high, server_low))
+                    && is_public(ipv4_from_segments(!client_high, !client_low))
+            }
+            // Documentation prefix.
+            [0x2001, 0xdb8, ..] => false,
+            [segment, ..] => {
+                !(x.is_multicast() || segment & 0xfe00 == 0xfc00 || segment & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Make IPv4 address from the two IPv6 address segments.
+fn ipv4_from_segments(high: u16, low: u16) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+}
+
+/// Read the response status line and headers.
+///
+/// Interim 1xx responses, i.e. "100 Continue" or "103 Early Hints", are skipped,
+/// the final response head is returned.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Vec<(String, String)>)> {
+    loop {
+        let (status, headers) = read_response_head(reader)?;
+        if !(100..200).contains(&status) || status == 101 {
+            return Ok((status, headers));
+        }
+        log::debug!("Interim response {} is skipped", status);
+    }
+}
+
+/// Read the status line and headers of one response.
+fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Vec<(String, String)>)> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let mut head = Vec::new();
+    loop {
+        let read = reader
+            .by_ref()
+            .take(MAX_HEAD_SIZE - head.len() as u64)
+            .read_until(b'\n', &mut head)?;
+        if read == 0 {
+            return Err(invalid("incomplete response headers"));
+        }
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => (),
+        _ => return Err(invalid("invalid response headers")),
+    }
+
+    let status = response
+        .code
+        .ok_or_else(|| invalid("invalid response status"))?;
+    let headers = response
+        .headers
+        .iter()
+        .map(|x| {
+            (
+                x.name.to_string(),
+                String::from_utf8_lossy(x.value).trim().to_string(),
+            )
+        })
+        .collect();
+    Ok((status, headers))
+}
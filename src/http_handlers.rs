@@ -1,27 +1,72 @@
-use base64;
-use mrq;
+use chrono::prelude::*;
 use multipart::server::{FieldHeaders, Multipart, MultipartData, MultipartField};
-use rouille::input::multipart::get_multipart_input;
-use rouille::{router, try_or_400};
-use rouille::{Request, Response};
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use rouille::router;
+use rouille::{Request, Response, ResponseBody};
 use serde_derive::{Deserialize, Serialize};
-use std::io::Read;
-use std::path::PathBuf;
-
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::data_uri;
 use super::file_utils;
+use super::http_client;
+use super::image_info;
+use super::jobs;
+use super::json_stream::JsonReader;
+use super::listing::{self, Cursor, ImageFile, ListQuery};
+use super::settings::{Collision, DecodeCheck, Settings, TypeMismatch};
 use super::thumbnail;
+use super::variants::{self, Filter, Fit, Resize, Variant};
 
 /// Top level HTTP request router.
-pub fn route(request: &Request, file_path: &str) -> Response {
+pub fn route(request: &Request, file_path: &str, settings: &Settings) -> Response {
     log::trace!("route({:?}) ...", request);
 
+    // The prefix is stripped manually by the expanded macro.
+    #[allow(clippy::manual_strip)]
     let response = router!(request,
         (GET) (/images) => {
-            handle_images_json_get(file_path)
+            handle_images_json_get(request, file_path)
         },
 
         (POST) (/images) => {
-            route_images_post_by_content_type(request, file_path)
+            route_images_post_by_content_type(request, file_path, settings)
+        },
+
+        (GET) (/images/{filename: String}) => {
+            handle_image_get(request, file_path, settings, &filename)
+        },
+
+        (GET) (/images/{filename: String}/thumbnail) => {
+            handle_thumbnail_get(request, file_path, settings, &filename)
+        },
+
+        (HEAD) (/images) => {
+            handle_images_json_get(request, file_path)
+        },
+
+        (HEAD) (/images/{filename: String}) => {
+            handle_image_get(request, file_path, settings, &filename)
+        },
+
+        (HEAD) (/images/{filename: String}/thumbnail) => {
+            handle_thumbnail_get(request, file_path, settings, &filename)
+        },
+
+        (PUT) (/images/{filename: String}) => {
+            handle_raw_image_upload(request, file_path, settings, &filename)
+        },
+
+        (GET) (/jobs/{id: String}) => {
+            handle_job_get(file_path, &id)
+        },
+
+        (DELETE) (/images/{filename: String}) => {
+            handle_image_delete(file_path, &filename)
         },
 
         _ => rouille::Response::empty_404()
@@ -39,702 +84,4216 @@ pub fn route(request: &Request, file_path: &str) -> Response {
     response
 }
 
-///Get response with sorted image files list in json array.
-pub fn handle_images_json_get(file_path: &str) -> Response {
+/// Stored image description returned in the images list.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageListEntry {
+    pub filename: String,
+    pub size: u64,
+    /// Modification time in RFC 3339 format.
+    pub modified: String,
+    /// Image format detected from the data, if it is recognized.
+    pub format: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub content_type: String,
+    pub thumbnail_url: String,
+    /// Thumbnail state: "ready", "pending", "failed" or "missing".
+    pub thumbnail: String,
+}
+
+/// Page of the stored images list.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageListPage {
+    pub images: Vec<ImageListEntry>,
+    /// Cursor of the next page, absent on the last page.
+    pub next: Option<String>,
+}
+
+///Get response with a page of stored images list in json.
+///
+/// Every image is described with its size, modification time, detected format and
+/// dimensions, and the thumbnail state. Directories and hidden (temporary) files
+/// are skipped.
+/// The page size is set with "limit" query parameter, the next page is requested with
+/// "cursor" parameter set to the "next" value of the previous page. The list is sorted
+/// with "sort" (name, mtime or size) and "order" (asc or desc) parameters, and filtered
+/// with "prefix", "glob", "content_type", "min_size", "max_size", "modified_after" and
+/// "modified_before" parameters.
+/// If the query parameters are invalid – returns a HTTP 400 "Bad Request" error response,
+/// if the upload directory can't be read – returns a HTTP 500 "Internal Server Error"
+/// error response.
+pub fn handle_images_json_get(request: &Request, file_path: &str) -> Response {
     log::trace!("handle_images_json_get...");
 
-    let mut dir_list = match std::fs::read_dir(&file_path) {
-        Ok(x) => x.map(|x| x.unwrap().file_name()).collect::<Vec<_>>(),
+    let query = match list_query_from_request(request) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
 
+    let files_list = match stored_images_list(file_path) {
+        Ok(x) => x,
         Err(e) => {
             log::warn!(
                 "I/O ERROR: \"{}\" while reading directory {}",
                 e.to_string(),
                 file_path
             );
-            panic!(e);
+            return error_response(500, "I/O error");
         }
     };
-    dir_list[..].sort();
 
+    let (files_list, next) = match query.select(files_list) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
+
+    let page = ImageListPage {
+        images: files_list
+            .into_iter()
+            .map(|(filename, metadata)| image_list_entry(file_path, filename, &metadata))
+            .collect(),
+        next: next.map(|x| x.encode()),
+    };
+
+    let response = Response::json(&page);
+    log::debug!("handle_images_json_get => {:?}", page);
+    response
+}
+
+/// Parse images list query parameters from the request query string.
+fn list_query_from_request(request: &Request) -> Result<ListQuery, String> {
+    let mut query = ListQuery::default();
+
+    if let Some(limit) = request.get_param("limit") {
+        query.limit = match limit.parse::<usize>() {
+            Ok(x) if (1..=listing::MAX_LIMIT).contains(&x) => x,
+            _ => {
+                return Err(format!(
+                    "invalid limit \"{}\", 1..{} expected",
+                    limit,
+                    listing::MAX_LIMIT
+                ))
+            }
+        };
+    }
+
+    if let Some(sort) = request.get_param("sort") {
+        query.sort = sort.parse()?;
+    }
+
+    if let Some(order) = request.get_param("order") {
+        query.descending = match &order.to_lowercase()[..] {
+            "asc" => false,
+            "desc" => true,
+            _ => return Err(format!("invalid order \"{}\", asc or desc expected", order)),
+        };
+    }
+
+    if let Some(cursor) = request.get_param("cursor") {
+        query.cursor = Some(Cursor::decode(&cursor)?);
+    }
+
+    query.prefix = request.get_param("prefix");
+    query.glob = request.get_param("glob");
+    query.content_type = request.get_param("content_type").map(|x| x.to_lowercase());
+
+    let size_param = |name: &str| match request.get_param(name) {
+        Some(x) => x
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("invalid {} \"{}\"", name, x)),
+        None => Ok(None),
+    };
+    query.min_size = size_param("min_size")?;
+    query.max_size = size_param("max_size")?;
+
+    let time_param = |name: &str| match request.get_param(name) {
+        Some(x) => parse_time(&x)
+            .map(Some)
+            .ok_or_else(|| format!("invalid {} \"{}\", RFC 3339 date expected", name, x)),
+        None => Ok(None),
+    };
+    query.modified_after = time_param("modified_after")?;
+    query.modified_before = time_param("modified_before")?;
+
+    log::debug!("list_query_from_request => {:?}", query);
+    Ok(query)
+}
+
+/// Parse the time in RFC 3339 format, or the date ("YYYY-MM-DD") meaning its midnight UTC.
+fn parse_time(time: &str) -> Option<std::time::SystemTime> {
+    if let Ok(x) = DateTime::parse_from_rfc3339(time) {
+        return Some(x.into());
+    }
+
+    NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .ok()
+        .map(|x| DateTime::<Utc>::from_utc(x.and_hms(0, 0, 0), Utc).into())
+}
+
+/// Read the list of stored image files with their metadata.
+fn stored_images_list(file_path: &str) -> io::Result<Vec<ImageFile>> {
     let mut files_list = Vec::new();
 
-    for item in dir_list {
-        match item.into_string() {
-            Ok(filename) => files_list.push(filename),
-            Err(filename) => log::warn!("UTF-8 incompatible file name {:?} is ignored", filename),
+    for item in fs::read_dir(file_path)? {
+        let item = item?;
+
+        let filename = match item.file_name().into_string() {
+            Ok(x) => x,
+            Err(filename) => {
+                log::warn!("UTF-8 incompatible file name {:?} is ignored", filename);
+                continue;
+            }
+        };
+        if filename.starts_with('.') {
+            continue;
+        }
+
+        match fs::metadata(item.path()) {
+            Ok(metadata) if metadata.is_file() => files_list.push((filename, metadata)),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
         }
     }
 
-    let response = Response::json(&files_list);
-    log::debug!("handle_images_json_get => {:?}", &files_list[..]);
-    response
+    Ok(files_list)
 }
 
-/// Route a HTTP POST request with respect to the Content-Type header.
+/// Describe the stored image for the images list.
+fn image_list_entry(file_path: &str, filename: String, metadata: &fs::Metadata) -> ImageListEntry {
+    let image_path: PathBuf = [file_path, &filename].iter().collect();
+
+    // Only the headers are read, the images are never decoded for the list.
+    let info = file_utils::open_image_data(&image_path)
+        .ok()
+        .and_then(|file| image_info::read_file(&file).ok());
+
+    ImageListEntry {
+        size: metadata.len(),
+        modified: metadata
+            .modified()
+            .map(|x| DateTime::<Utc>::from(x).to_rfc3339())
+            .unwrap_or_default(),
+        format: info.map(|x| image_info::format_name(x.format).to_string()),
+        width: info.map(|x| x.width),
+        height: info.map(|x| x.height),
+        content_type: file_utils::content_type_for(&filename).to_string(),
+        thumbnail_url: format!(
+            "/images/{}/thumbnail",
+            utf8_percent_encode(&filename, PATH_SEGMENT_ENCODE_SET)
+        ),
+        thumbnail: thumbnail::status(&image_path).name().to_string(),
+        filename,
+    }
+}
+
+/// Resolve a path to the stored image by its filename.
 ///
-/// Attempts to route a POST request to resource with respect to the Content-Type
-/// header, acceptable types are "application/json" and "multipart/form-data".
-/// If any other type is specified – returns a HTTP 406 "Not Acceptable" error response.
-/// If Content-Type isn't specified – returns a HTTP 400 "Bad Request" error response.
-pub fn route_images_post_by_content_type(request: &Request, file_path: &str) -> Response {
-    match request.header("Content-Type") {
-        Some(content_type) => match &content_type
-            .to_lowercase()
-            .split(';')
-            .collect::<Vec<&str>>()[0][..]
-        {
-            "application/json" => handle_json_images_post(request, file_path),
-            "multipart/form-data" => handle_multipart_images_post(request, file_path),
-            _ => Response::empty_406(),
-        },
-        None => Response::empty_400(),
+/// Returns `None` if the filename is unsafe to join with the upload path,
+/// i.e. it is empty, relative or contains path separators.
+fn stored_image_path(file_path: &str, filename: &str) -> Option<PathBuf> {
+    if filename.is_empty()
+        || filename == "."
+        || filename == ".."
+        || filename.contains(&['/', '\\', '\0'][..])
+    {
+        log::warn!("Unsafe image filename {:?} is rejected", filename);
+        return None;
     }
+
+    Some([file_path, filename].iter().collect())
 }
 
+/// Error details returned in the body of an error response.
 #[derive(Debug, Deserialize, Serialize)]
-pub struct ImageUploadResult {
-    pub filename: String,
-    pub content_type: String,
-    pub size: u64,
+pub struct ErrorResult {
     pub success: bool,
     pub reason: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct ImageUploadRequest {
-    filename: Option<String>,
-    content_type: Option<String>,
-    url: Option<String>,
-    data: Option<String>,
+/// Make an error response with the JSON body describing the reason.
+fn error_response(status_code: u16, reason: &str) -> Response {
+    Response::json(&ErrorResult {
+        success: false,
+        reason: reason.to_string(),
+    })
+    .with_status_code(status_code)
 }
 
-/// Handle a request with a body containing JSON with an array of base64-encoded images
-/// or URLs to download.
+/// Get response with the stored image data.
 ///
-/// Handles a request with a body containg JSON with an array of base64-encoded images
-/// or URLS to download, saving valid images to disk storage.
-/// Returning JSON array with info about successfully saved images.
-/// In case of severe errors returns a HTTP 400 Bad request error.
-pub fn handle_json_images_post(request: &Request, file_path: &str) -> Response {
-    log::trace!("handle_json_images_post...");
-
-    let upload_requests: Vec<ImageUploadRequest> = try_or_400!(rouille::input::json_input(request));
-    log::debug!("upload_requests = {:?}", upload_requests);
-
-    let mut results = Vec::<ImageUploadResult>::new();
-    let mut file_path: PathBuf = [file_path, "placeholder.bin"].iter().collect();
-
-    for mut item in upload_requests {
-        let image_from = if item.data.is_some() {
-            image_from_base64_data
-        } else if item.url.is_some() {
-            image_from_url
-        } else {
-            results.push(ImageUploadResult {
-                filename: item.filename.unwrap_or_else(String::new),
-                content_type: item.content_type.unwrap_or_else(String::new),
-                size: 0,
-                success: false,
-                reason: String::from("nor url or data are specified"),
-            });
-
-            continue;
-        };
+/// Streams the stored image file with Content-Type inferred from the filename extension.
+/// The file is kept under a shared lock while the response is sent.
+/// If "w" and "h" query parameters are specified, a resized variant of the image is
+/// streamed instead, optional "fit" (fill, contain or cover) and "filter" (nearest,
+/// triangle, catmullrom, gaussian or lanczos3) parameters control the resizing.
+/// If "format" (png, jpeg, gif, bmp or ico) query parameter is specified, the image
+/// is converted to the format, "quality" parameter sets JPEG encoding quality.
+/// Conditional requests are supported, see `image_file_response`.
+/// If there is no such image – returns a HTTP 404 "Not Found" error response.
+pub fn handle_image_get(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+    filename: &str,
+) -> Response {
+    log::trace!("handle_image_get(\"{}\")...", filename);
+
+    let image_path = match stored_image_path(file_path, filename) {
+        Some(x) => x,
+        None => return Response::empty_404(),
+    };
 
-        match image_from(&mut item) {
-            Ok((filename, content_type, data)) => {
-                file_path.set_file_name(&filename);
+    match std::fs::metadata(&image_path) {
+        Ok(ref metadata) if metadata.is_file() => (),
+        _ => {
+            log::debug!("handle_image_get(\"{}\") => 404", filename);
+            return Response::empty_404();
+        }
+    }
 
-                let (success, size, err) = file_utils::write_image_data(&data[..], &file_path)
-                    .and_then(|size| Ok((true, size, "ok")))
-                    .or_else::<(), _>(|_| Ok((false, 0, "I/O error")))
-                    .unwrap();
+    let image_path = match variant_from_request(request, settings) {
+        Ok(Some(variant)) => match variants::make(&image_path, &variant, &settings.pixel_limits) {
+            Ok(x) => x,
+            Err(image::ImageError::IoError(_)) => return error_response(500, "I/O error"),
+            Err(e) => return error_response(415, &format!("can't make image variant: {}", e)),
+        },
+        Ok(None) => image_path,
+        Err(e) => return error_response(400, &e),
+    };
 
-                results.push(ImageUploadResult {
-                    filename,
-                    content_type: content_type,
-                    size,
-                    success,
-                    reason: err.to_string(),
-                });
+    let content_type =
+        file_utils::content_type_for(&image_path.file_name().unwrap_or_default().to_string_lossy());
 
-                {
-                    let file_path = file_path.clone();
-                    std::thread::spawn(move || {
-                        thumbnail::make(&file_path.to_string_lossy());
-                    });
-                }
-            }
-            Err(e) => {
-                results.push(ImageUploadResult {
-                    filename: item.filename.unwrap_or_else(String::new),
-                    content_type: item.content_type.unwrap_or_else(String::new),
-                    size: 0,
-                    success: false,
-                    reason: e.to_string(),
-                });
-            }
-        };
-    }
+    let response = match file_utils::open_image_data(&image_path) {
+        Ok(file) => image_file_response(request, content_type, file),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Response::empty_404(),
+        Err(_) => error_response(500, "I/O error"),
+    };
 
-    log::debug!("handle_json_images_post => results = {:?}", results);
-    Response::json(&results)
+    log::debug!("handle_image_get(\"{}\") => {:?}", filename, response);
+    response
 }
 
-/// Handle a multipart request with body containing binary images data array.
+/// Make a response streaming the opened image file with HTTP caching headers.
 ///
-/// Handles a request body parts containing MIME of "image/*" type, other
-/// parts are skipped, saving valid images to disk storage.
-/// Returning JSON array with info about successfully saved images.
-/// In case of severe errors returns a HTTP 400 Bad request error.
-pub fn handle_multipart_images_post(request: &Request, file_path: &str) -> Response {
-    log::trace!("handle_multipart_images_post...");
-    let mut multipart_items = match get_multipart_input(request) {
-        Ok(m) => m,
+/// Sets strong ETag based on the file size and modification time, Last-Modified and
+/// Cache-Control requiring revalidation, because stored images can be overwritten.
+/// If the client's copy is still valid according to If-None-Match header (or
+/// If-Modified-Since when If-None-Match isn't specified) – returns a HTTP 304
+/// "Not Modified" response without body.
+fn image_file_response(request: &Request, content_type: &'static str, file: fs::File) -> Response {
+    let metadata = match file.metadata() {
+        Ok(x) => x,
         Err(e) => {
-            log::warn!("Multipart data parsing error: {}", e.to_string());
-            return Response::empty_400();
+            log::warn!(
+                "I/O ERROR \"{}\" while reading file metadata!",
+                e.to_string()
+            );
+            return error_response(500, "I/O error");
         }
     };
 
-    let mut results = Vec::<ImageUploadResult>::new();
-    let mut file_path: PathBuf = [file_path, "placeholder.bin"].iter().collect();
-
-    while let Some(mut item) = multipart_items.next() {
-        match image_from_multipart_field(&mut item) {
-            Ok((filename, content_type, data)) => {
-                file_path.set_file_name(&filename);
-
-                let (success, size, err) = file_utils::write_image_data(data, &file_path)
-                    .and_then(|size| Ok((true, size, "ok")))
-                    .or_else::<(), _>(|_| Ok((false, 0, "I/O error")))
-                    .unwrap();
-
-                results.push(ImageUploadResult {
-                    filename,
-                    content_type: content_type,
-                    size,
-                    success,
-                    reason: err.to_string(),
-                });
+    let etag = file_utils::etag_for(&metadata);
+    let modified = metadata.modified().ok();
+
+    let not_modified = match request.header("If-None-Match") {
+        Some(header) => header
+            .split(',')
+            .map(str::trim)
+            .any(|x| x == "*" || x.trim_start_matches("W/") == etag),
+        None => match (request.header("If-Modified-Since"), modified) {
+            (Some(header), Some(modified)) => match DateTime::parse_from_rfc2822(header) {
+                Ok(since) => DateTime::<Utc>::from(modified).timestamp() <= since.timestamp(),
+                Err(_) => false,
+            },
+            _ => false,
+        },
+    };
 
-                {
-                    let file_path = file_path.clone();
-                    std::thread::spawn(move || {
-                        thumbnail::make(&file_path.to_string_lossy());
-                    });
-                }
-            }
-            Err((headers, err)) => {
-                results.push(ImageUploadResult {
-                    filename: headers.name.to_string(),
-                    content_type: headers
-                        .content_type
-                        .clone()
-                        .map(|x| x.to_string())
-                        .unwrap_or(String::new()),
-                    size: 0,
-                    success: false,
-                    reason: err.to_string(),
-                });
+    let ranges = match request.header("Range") {
+        Some(header) if request.method() == "GET" => {
+            let range_applies = match request.header("If-Range") {
+                Some(x) if x.starts_with('"') => x == etag,
+                Some(x) => modified.map(file_utils::http_date) == Some(x.to_string()),
+                None => true,
+            };
+            if range_applies {
+                parse_byte_ranges(header, metadata.len())
+            } else {
+                None
             }
         }
+        _ => None,
+    };
+
+    let mut response = if not_modified {
+        drop(file);
+        Response::empty_204().with_status_code(304)
+    } else if let Some(ranges) = ranges {
+        byte_ranges_response(content_type, file, metadata.len(), ranges)
+    } else {
+        Response::from_file(content_type, file).with_unique_header("Accept-Ranges", "bytes")
+    };
+
+    response = response
+        .with_unique_header("ETag", etag)
+        .with_unique_header("Cache-Control", "public, no-cache");
+    if let Some(modified) = modified {
+        response = response.with_unique_header("Last-Modified", file_utils::http_date(modified));
     }
 
-    log::debug!("handle_multipart_images_post => results = {:?}", results);
-    Response::json(&results)
+    response
 }
 
-/// Decode an image from multipart/form-data field and
-/// return a (filename, content-type, image-data-reader) tuple.
-fn image_from_multipart_field<'a, 'b>(
-    item: &'b mut MultipartField<&'b mut Multipart<rouille::RequestBody<'a>>>,
-) -> Result<
-    (
-        String,
-        String,
-        &'b mut MultipartData<&'b mut Multipart<rouille::RequestBody<'a>>>,
-    ),
-    (&'b FieldHeaders, &'b str),
-> {
-    log::trace!("image_from_multipart_field...");
-    let headers = &item.headers;
+/// Maximal number of byte ranges served in one response.
+const MAX_BYTE_RANGES: usize = 16;
 
-    if let Some(content_type) = &headers.content_type {
-        let content_type = content_type.to_string();
-        if content_type.starts_with("image/") {
-            let filename = if let Some(filename) = &headers.filename {
-                &filename[..]
-            } else {
-                &headers.name[..]
+/// Parse the Range header value into a list of inclusive byte ranges within the size.
+///
+/// Overlapping and adjacent ranges are merged, so each byte is sent once at most.
+/// Returns `None` if the header is malformed or has more than `MAX_BYTE_RANGES` ranges
+/// and must be ignored, and an empty list if none of the ranges is satisfiable.
+fn parse_byte_ranges(header: &str, size: u64) -> Option<Vec<(u64, u64)>> {
+    let header = header.trim();
+    if !header.starts_with("bytes=") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in header["bytes=".len()..].split(',').map(str::trim) {
+        if spec.is_empty() {
+            continue;
+        }
+
+        let mut pair = spec.splitn(2, '-').map(str::trim);
+        let (first, last) = (pair.next()?, pair.next()?);
+
+        if first.is_empty() {
+            let suffix = last.parse::<u64>().ok()?;
+            if suffix > 0 && size > 0 {
+                ranges.push((size.saturating_sub(suffix), size - 1));
+            }
+        } else {
+            let first = first.parse::<u64>().ok()?;
+            let last = match last {
+                "" => u64::MAX,
+                x => x.parse::<u64>().ok()?,
             };
-            let filename = file_utils::normalize_image_filename(&filename, &content_type);
+            if last < first {
+                return None;
+            }
+            if first < size {
+                ranges.push((first, std::cmp::min(last, size - 1)));
+            }
+        }
+        if ranges.len() > MAX_BYTE_RANGES {
+            return None;
+        }
+    }
 
-            log::debug!(
-                "image_from_multipart_field => (\"{}\", \"{}\", _)",
-                filename,
-                content_type
-            );
-            return Ok((filename, content_type, &mut item.data));
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
         }
     }
 
-    log::debug!("image_from_multipart_field => Err(_, \"no image data\")");
-    Err((headers, "no image data"))
+    Some(merged)
 }
 
-/// Decode a base64-encoded image data and
-/// return a (filename, content-type, image-data-reader) tuple.
-fn image_from_base64_data(
-    item: &mut ImageUploadRequest,
-) -> Result<(String, String, Vec<u8>), String> {
-    log::trace!("image_from_base64_data...");
-
-    if let Some(data) = &item.data {
-        let content_type = item
-            .content_type
-            .take()
-            .unwrap_or(String::from("application/octet-stream"));
-        let filename = if let Some(filename) = &item.filename {
-            file_utils::normalize_image_filename(&filename, &content_type)
-        } else {
-            file_utils::normalize_image_filename("", &content_type)
-        };
+/// Make a HTTP 206 "Partial Content" response with the byte ranges of the file.
+///
+/// A single range is sent as is with Content-Range header, several ranges are sent
+/// as "multipart/byteranges" body. The file is read lazily, so the shared lock is held
+/// until the response is sent.
+/// If there are no satisfiable ranges – returns a HTTP 416 "Range Not Satisfiable"
+/// error response.
+fn byte_ranges_response(
+    content_type: &'static str,
+    mut file: fs::File,
+    size: u64,
+    ranges: Vec<(u64, u64)>,
+) -> Response {
+    log::trace!("byte_ranges_response({:?}) ...", ranges);
+
+    if ranges.is_empty() {
+        return error_response(416, "range not satisfiable")
+            .with_unique_header("Content-Range", format!("bytes */{}", size));
+    }
 
-        return match base64::decode(&data) {
-            Ok(data) => {
-                log::debug!(
-                    "image_from_base64_data => (\"{}\", \"{}\", _)",
-                    filename,
-                    content_type
-                );
-                Ok((filename, content_type, data))
-            }
+    if ranges.len() == 1 {
+        let (first, last) = ranges[0];
+        if let Err(e) = file.seek(SeekFrom::Start(first)) {
+            log::warn!("I/O ERROR \"{}\" while seeking in file!", e.to_string());
+            return error_response(500, "I/O error");
+        }
 
-            Err(e) => {
-                let e = e.to_string();
-                log::debug!("image_from_base64_data => Err({})", e);
-                Err(e)
-            }
+        return Response {
+            status_code: 206,
+            headers: vec![
+                ("Content-Type".into(), content_type.into()),
+                (
+                    "Content-Range".into(),
+                    format!("bytes {}-{}/{}", first, last, size).into(),
+                ),
+            ],
+            data: ResponseBody::from_reader(file.take(last - first + 1)),
+            upgrade: None,
         };
     }
 
-    log::debug!("image_from_base64_data => Err(\"no image data\")");
-    Err("no image data".to_string())
+    let boundary = format!("{:016x}", Utc::now().timestamp_nanos());
+    let mut parts: VecDeque<_> = ranges
+        .into_iter()
+        .map(|(first, last)| ByteRange {
+            head: io::Cursor::new(
+                format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, first, last, size
+                )
+                .into_bytes(),
+            ),
+            first,
+            remaining: last - first + 1,
+        })
+        .collect();
+    parts.push_back(ByteRange {
+        head: io::Cursor::new(format!("\r\n--{}--\r\n", boundary).into_bytes()),
+        first: 0,
+        remaining: 0,
+    });
+
+    Response {
+        status_code: 206,
+        headers: vec![(
+            "Content-Type".into(),
+            format!("multipart/byteranges; boundary={}", boundary).into(),
+        )],
+        data: ResponseBody::from_reader(ByteRangesReader { file, parts }),
+        upgrade: None,
+    }
 }
 
-/// Download an image specified by URL to buffer and
-/// return a (filename, content-type, image-data-reader) tuple.
-fn image_from_url(item: &mut ImageUploadRequest) -> Result<(String, String, Vec<u8>), String> {
-    log::trace!("image_from_url...");
+/// Part of the "multipart/byteranges" body: the part headers followed by the file bytes.
+struct ByteRange {
+    head: io::Cursor<Vec<u8>>,
+    first: u64,
+    remaining: u64,
+}
 
-    if let Some(url) = &item.url {
-        match mrq::get(&url[..]).send() {
-            Ok(mut response) => {
-                let content_type = response
-                    .headers
-                    .get("Content-Type")
-                    .unwrap_or(&item.content_type.take().unwrap_or_else(String::new))
-                    .to_string();
-                if content_type.starts_with("image/") {
-                    if let Some(content_length) = response.headers.get("Content-Length") {
-                        if let Ok(content_length) = content_length.parse::<usize>() {
-                            let filename = if let Some(filename) = &item.filename {
-                                file_utils::normalize_image_filename(&filename, &content_type)
-                            } else {
-                                file_utils::normalize_image_filename(
-                                    &url.split('/').last().unwrap_or("").to_string(),
-                                    &content_type,
-                                )
-                            };
-
-                            let mut buffer = vec![0u8; content_length];
-
-                            let result = response
-                                .body
-                                .read_exact(&mut buffer[..])
-                                .and_then(|_| Ok((filename, content_type, buffer)))
-                                .or_else(|e| Err(e.to_string()));
-
-                            match &result {
-                                Ok((filename, content_type, _)) => log::debug!(
-                                    "image_from_url(\"{}\") => Ok((\"{}\", \"{}\"))",
-                                    url,
-                                    filename,
-                                    content_type
-                                ),
-                                Err(e) => {
-                                    log::debug!("image_from_url(\"{}\") => Err(\"{}\")", url, e)
-                                }
-                            }
+/// Reader of the "multipart/byteranges" body, seeking the file to each part lazily.
+struct ByteRangesReader {
+    file: fs::File,
+    parts: VecDeque<ByteRange>,
+}
 
-                            return result;
-                        }
-                    }
-                    let e = String::from("invalid content length in response");
-                    log::debug!("image_from_url(\"{}\") => Err(\"{}\")", url, e);
-                    return Err(e);
+impl Read for ByteRangesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let size = part.head.read(buf)?;
+            if size > 0 {
+                if part.head.position() == part.head.get_ref().len() as u64 {
+                    self.file.seek(SeekFrom::Start(part.first))?;
                 }
-                let e = String::from("not an image");
-                log::debug!("image_from_url(\"{}\") => Err(\"{}\")", url, e);
-                return Err(e);
+                return Ok(size);
             }
-            Err(e) => {
-                let e = e.to_string();
-                log::debug!("image_from_url(\"{}\") => Err(\"{}\")", url, e);
-                return Err(e);
+
+            if part.remaining > 0 {
+                let limit = std::cmp::min(buf.len() as u64, part.remaining) as usize;
+                let size = self.file.read(&mut buf[..limit])?;
+                if size == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                part.remaining -= size as u64;
+                return Ok(size);
             }
+
+            self.parts.pop_front();
         }
-    }
 
-    let e = String::from("image URL not specified");
-    log::debug!("image_from_url => Err(\"{}\")", e);
-    Err(e)
+        Ok(0)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use image::ImageDecoder;
-    use rouille::input::multipart::get_multipart_input;
-    use std::io::Read;
-
-    #[test]
-    fn test_image_from_multipart_field() {
-        let http_rq = mock::multipart_formdata_request();
-        let mut multipart_items = get_multipart_input(&http_rq).unwrap();
+/// Parse image variant parameters from the request query string.
+///
+/// Returns `Ok(None)` if neither resizing nor format conversion is requested.
+/// For resizing both width and height must be specified, and the size must be one
+/// of the allowed in settings.
+fn variant_from_request(request: &Request, settings: &Settings) -> Result<Option<Variant>, String> {
+    let width = request.get_param("w");
+    let height = request.get_param("h");
+    let fit = request.get_param("fit");
+    let filter = request.get_param("filter");
+    let format = request.get_param("format");
+    let quality = request.get_param("quality");
+
+    if width.is_none()
+        && height.is_none()
+        && fit.is_none()
+        && filter.is_none()
+        && format.is_none()
+        && quality.is_none()
+    {
+        return Ok(None);
+    }
 
-        let mut item = multipart_items.next().unwrap();
-        let (filename, content_type, data) = super::image_from_multipart_field(&mut item).unwrap();
-        assert_eq!(filename, "sample.jpg");
-        assert_eq!(content_type, "image/jpeg");
-        let mut buffer = [0u8; 15];
-        data.read_exact(&mut buffer).unwrap();
-        assert_eq!(&buffer, b"JPEG IMAGE DATA");
+    let mut variant = Variant::default();
 
-        let mut item = multipart_items.next().unwrap();
-        let (filename, content_type, data) = super::image_from_multipart_field(&mut item).unwrap();
-        assert_eq!(filename, "file-from-name.png");
-        assert_eq!(content_type, "image/png");
-        let mut buffer = [0u8; 14];
-        data.read_exact(&mut buffer).unwrap();
-        assert_eq!(&buffer, b"PNG IMAGE DATA");
+    if width.is_some() || height.is_some() || fit.is_some() || filter.is_some() {
+        let (width, height) = match (width, height) {
+            (Some(width), Some(height)) => match (width.parse::<u32>(), height.parse::<u32>()) {
+                (Ok(width), Ok(height)) => (width, height),
+                _ => return Err(format!("invalid image size {}x{}", width, height)),
+            },
+            _ => return Err(String::from("both w and h must be specified")),
+        };
 
-        let mut item = multipart_items.next().unwrap();
-        match super::image_from_multipart_field(&mut item) {
-            Err((_, msg)) => assert_eq!(msg, "no image data"),
-            _ => panic!("text/plain is not an image!"),
+        if !settings.allowed_sizes.contains(&(width, height)) {
+            return Err(format!("image size {}x{} is not allowed", width, height));
         }
+
+        variant.resize = Some(Resize {
+            width,
+            height,
+            fit: fit.map_or(Ok(Fit::Contain), |x| x.parse())?,
+            filter: filter.map_or(Ok(Filter::Lanczos3), |x| x.parse())?,
+        });
     }
 
-    #[test]
-    fn test_image_from_base64_data() {
-        let mut uprq = super::ImageUploadRequest {
-            content_type: None,
-            data: None,
-            filename: None,
-            url: None,
+    if let Some(format) = format {
+        variant.format = Some(format.parse()?);
+    }
+
+    if let Some(quality) = quality {
+        variant.quality = match quality.parse::<u8>() {
+            Ok(x) if (1..=100).contains(&x) => variants::quality_level(x),
+            _ => return Err(format!("invalid quality \"{}\", 1..100 expected", quality)),
         };
+    }
 
-        match super::image_from_base64_data(&mut uprq) {
-            Err(e) => assert_eq!(e, "no image data"),
-            _ => panic!("data == None isn't an image!"),
+    log::debug!("variant_from_request => {:?}", variant);
+    Ok(Some(variant))
+}
+
+/// Get response with the thumbnail of the stored image.
+///
+/// Streams the thumbnail if it is generated already, conditional requests are supported
+/// as for the images themselves. While the generation is pending –
+/// returns a HTTP 202 "Accepted" response with Retry-After header.
+/// If there is no such image or the thumbnail can't be generated for it – returns
+/// a HTTP 404 "Not Found" error response.
+pub fn handle_thumbnail_get(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+    filename: &str,
+) -> Response {
+    log::trace!("handle_thumbnail_get(\"{}\")...", filename);
+
+    let image_path = match stored_image_path(file_path, filename) {
+        Some(x) => x,
+        None => return Response::empty_404(),
+    };
+
+    if !image_path.is_file() {
+        log::debug!("handle_thumbnail_get(\"{}\") => 404", filename);
+        return Response::empty_404();
+    }
+
+    let response = match thumbnail::status(&image_path) {
+        thumbnail::Status::Ready(thumbnail_path) => {
+            match file_utils::open_image_data(&thumbnail_path) {
+                Ok(file) => {
+                    image_file_response(request, file_utils::content_type_for(filename), file)
+                }
+                Err(_) => error_response(500, "I/O error"),
+            }
+        }
+
+        thumbnail::Status::Pending => thumbnail_pending_response(),
+
+        thumbnail::Status::Missing => {
+            thumbnail::spawn(image_path, settings.pixel_limits);
+            thumbnail_pending_response()
+        }
+
+        thumbnail::Status::Failed => Response::empty_404(),
+    };
+
+    log::debug!("handle_thumbnail_get(\"{}\") => {:?}", filename, response);
+    response
+}
+
+fn thumbnail_pending_response() -> Response {
+    Response::empty_204()
+        .with_status_code(202)
+        .with_unique_header("Retry-After", "1")
+}
+
+/// Get response with the upload job progress in JSON.
+///
+/// Returns the job with the results of the processed items and the thumbnail
+/// states of the stored images. If there is no such job – returns a HTTP 404
+/// "Not Found" error response.
+pub fn handle_job_get(file_path: &str, id: &str) -> Response {
+    log::trace!("handle_job_get(\"{}\")...", id);
+
+    let mut job = match jobs::Job::load(Path::new(file_path), id) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return error_response(404, "job not found")
+        }
+        Err(e) => {
+            log::warn!("I/O ERROR \"{}\" while loading job {}!", e, id);
+            return error_response(500, "I/O error");
+        }
+    };
+
+    for item in &mut job.items {
+        let stored = item.result.as_ref().filter(|x| x.success);
+        if let Some(image_path) = stored.and_then(|x| stored_image_path(file_path, &x.filename)) {
+            item.thumbnail = Some(thumbnail::status(&image_path).name().to_string());
+        }
+    }
+
+    log::debug!("handle_job_get(\"{}\") => {:?}", id, job.state);
+    Response::json(&job)
+}
+
+/// Delete the stored image with its thumbnail and all cached variants.
+///
+/// Returns JSON with info about the removed image.
+/// If there is no such image – returns a HTTP 404 "Not Found" error response,
+/// if the image is being read or written – returns a HTTP 423 "Locked" error response
+/// instead of waiting. The pending thumbnail generation is cancelled.
+pub fn handle_image_delete(file_path: &str, filename: &str) -> Response {
+    log::trace!("handle_image_delete(\"{}\")...", filename);
+
+    let mut result = ImageUploadResult {
+        filename: filename.to_string(),
+        content_type: file_utils::content_type_for(filename).to_string(),
+        reason: String::from("not found"),
+        ..ImageUploadResult::default()
+    };
+
+    let image_path = match stored_image_path(file_path, filename) {
+        Some(ref x) if x.is_file() => x.clone(),
+        _ => return Response::json(&result).with_status_code(404),
+    };
+
+    let status_code = match file_utils::remove_image_data(&image_path)
+        .and_then(|size| thumbnail::remove(&image_path).map(|_| size))
+        .and_then(|size| variants::remove(&image_path).map(|_| size))
+    {
+        Ok(size) => {
+            result.size = size;
+            result.success = true;
+            result.reason = String::from("ok");
+            200
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => 404,
+        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            result.reason = String::from("image is in use");
+            423
+        }
+        Err(_) => {
+            result.reason = String::from("I/O error");
+            500
+        }
+    };
+
+    log::debug!("handle_image_delete(\"{}\") => {:?}", filename, result);
+    Response::json(&result).with_status_code(status_code)
+}
+
+/// Route a HTTP POST request with respect to the Content-Type header.
+///
+/// Attempts to route a POST request to resource with respect to the Content-Type
+/// header, acceptable types are "application/json", "multipart/form-data" and "image/*".
+/// If any other type is specified – returns a HTTP 406 "Not Acceptable" error response.
+/// If Content-Type isn't specified – returns a HTTP 400 "Bad Request" error response.
+pub fn route_images_post_by_content_type(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+) -> Response {
+    match request.header("Content-Type") {
+        Some(content_type) => match &content_type
+            .to_lowercase()
+            .split(';')
+            .collect::<Vec<&str>>()[0][..]
+        {
+            "application/json" => handle_json_images_post(request, file_path, settings),
+            _ if async_from_request(request) != Ok(false) => error_response(
+                400,
+                "asynchronous upload is supported for JSON requests only",
+            ),
+            "multipart/form-data" => handle_multipart_images_post(request, file_path, settings),
+            x if x.starts_with("image/") => {
+                handle_raw_image_upload(request, file_path, settings, "")
+            }
+            _ => Response::empty_406(),
+        },
+        None => Response::empty_400(),
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImageUploadResult {
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+    pub success: bool,
+    pub reason: String,
+    /// Client supplied filename if it was changed to be stored safely.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    /// Non-fatal issues found while storing the image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Outcome of the image download by URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<Download>,
+}
+
+/// Outcome of the image download by URL.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Download {
+    /// URL of the last request, after the redirects.
+    pub url: String,
+    /// Number of the requests made, redirects and retries included.
+    pub attempts: u32,
+    /// HTTP status of the last response, if there was any.
+    pub status: Option<u16>,
+}
+
+/// Download failure reason and the outcome, if any request was made.
+type DownloadError = (String, Option<Download>);
+
+/// Image taken from an upload request item, ready to be stored.
+struct ImageSource<R> {
+    filename: String,
+    content_type: String,
+    data: R,
+    original_filename: Option<String>,
+    warnings: Vec<String>,
+    download: Option<Download>,
+}
+
+impl<R> ImageSource<R> {
+    /// Make an image source with the client supplied filename sanitized and normalized
+    /// with respect of the content type.
+    fn new(filename: &str, content_type: String, data: R) -> Self {
+        let (sanitized, changes) = file_utils::sanitize_filename(filename);
+
+        ImageSource {
+            filename: file_utils::normalize_image_filename(&sanitized, &content_type),
+            content_type,
+            data,
+            original_filename: if changes.is_empty() {
+                None
+            } else {
+                Some(filename.to_string())
+            },
+            warnings: changes
+                .iter()
+                .map(|x| format!("filename sanitized: {}", x))
+                .collect(),
+            download: None,
+        }
+    }
+
+    fn map<T, F: FnOnce(R) -> T>(self, f: F) -> ImageSource<T> {
+        ImageSource {
+            filename: self.filename,
+            content_type: self.content_type,
+            data: f(self.data),
+            original_filename: self.original_filename,
+            warnings: self.warnings,
+            download: self.download,
+        }
+    }
+}
+
+/// Number of leading bytes of the image data used to detect the image format
+/// and to read the image dimensions before the data is stored.
+const HEADER_LENGTH: u64 = 64 * 1024;
+
+/// Check the image format detected by the magic bytes against the declared content type.
+///
+/// Both declared and detected types must be allowed by the settings.
+/// The detected format takes precedence: the content type is replaced with the detected
+/// one and the filename extension is changed accordingly. Unrecognized data and
+/// the declared type mismatches are reported as warnings or cause the upload rejection
+/// with respect of the policy. Unrecognized data with the filename extension of a type
+/// not allowed is stored with ".bin" extension, so it's never served as that type.
+fn check_image_type(
+    result: &mut ImageUploadResult,
+    magic: &[u8],
+    settings: &Settings,
+) -> Result<(), String> {
+    let policy = settings.type_mismatch;
+
+    let declared = result.content_type.to_lowercase();
+    let undeclared = declared.is_empty() || declared == "application/octet-stream";
+    let canonical =
+        file_utils::content_type_for(&format!(".{}", file_utils::extension_for(&declared)));
+    if !undeclared && !settings.allows_type(canonical) {
+        return Err(format!(
+            "content type {} is not allowed",
+            result.content_type
+        ));
+    }
+
+    let detected = match image_info::guess_format(magic) {
+        Some(format) => image_info::content_type(format),
+        None => {
+            let reason = String::from("image format not recognized");
+            return match policy {
+                TypeMismatch::Reject => Err(reason),
+                TypeMismatch::Flag => {
+                    result.warnings.push(reason);
+                    // The image is served with the content type of the filename extension,
+                    // so the data of unknown format is never served as a type not allowed.
+                    if !settings.allows_type(file_utils::content_type_for(&result.filename)) {
+                        result.filename =
+                            replace_extension(&result.filename, "application/octet-stream");
+                        result.content_type = String::from("application/octet-stream");
+                        result
+                            .warnings
+                            .push(String::from("filename extension changed to .bin"));
+                    }
+                    Ok(())
+                }
+            };
+        }
+    };
+
+    if !settings.allows_type(detected) {
+        return Err(format!("detected content type {} is not allowed", detected));
+    }
+
+    if !undeclared && canonical != detected {
+        let reason = format!(
+            "declared content type {} doesn't match detected {}",
+            result.content_type, detected
+        );
+        match policy {
+            TypeMismatch::Reject => return Err(reason),
+            TypeMismatch::Flag => result.warnings.push(reason),
+        }
+    }
+
+    if file_utils::content_type_for(&result.filename) != detected {
+        result.filename = replace_extension(&result.filename, detected);
+    }
+    result.content_type = detected.to_string();
+
+    Ok(())
+}
+
+/// Replace the filename extension with the one of the content type.
+fn replace_extension(filename: &str, content_type: &str) -> String {
+    let stem = match filename.rfind('.') {
+        Some(i) => &filename[..i],
+        None => filename,
+    };
+    if stem.is_empty() {
+        file_utils::normalize_image_filename(stem, content_type)
+    } else {
+        format!("{}.{}", stem, file_utils::extension_for(content_type))
+    }
+}
+
+/// Write the image data to a temporary file next to the image path and verify it.
+///
+/// Returns the temporary file path and the data size, or the failure reason.
+/// Unless the decode check is off, the image dimensions are checked against the pixel
+/// limits and the image is decoded. The temporary file is removed if the image is
+/// too large, can't be decoded or the data can't be read.
+fn stage_image<R: Read>(
+    data: R,
+    image_path: &Path,
+    settings: &Settings,
+) -> Result<(PathBuf, u64), String> {
+    let tmp_path = file_utils::temp_path_for(image_path);
+
+    let size = match file_utils::write_image_data(data, &tmp_path) {
+        Ok(x) => x,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(io_error_reason(&e));
+        }
+    };
+    if size > settings.max_image_size {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(String::from("image too large"));
+    }
+    if settings.decode_check == DecodeCheck::Off {
+        return Ok((tmp_path, size));
+    }
+
+    let verified = fs::File::open(&tmp_path)
+        .map_err(image::ImageError::from)
+        .and_then(|file| {
+            let info = image_info::read_file(&file)?;
+            if let Err(reason) = settings.pixel_limits.check(info.width, info.height) {
+                return Ok(Err(reason));
+            }
+            if settings.decode_check == DecodeCheck::Full {
+                image_info::decode_file(&file, info.format)?;
+            }
+            Ok(Ok(()))
+        });
+    let reason = match verified {
+        Ok(Ok(())) => return Ok((tmp_path, size)),
+        Ok(Err(reason)) => reason,
+        Err(e) => format!("invalid image data: {}", e),
+    };
+
+    log::debug!("stage_image => Err(\"{}\")", reason);
+    let _ = fs::remove_file(&tmp_path);
+    Err(reason)
+}
+
+/// Maximal number of suffixes tried to find a free filename with the rename policy.
+const MAX_RENAME_ATTEMPTS: usize = 1000;
+
+/// Store the image data to disk storage and start the thumbnail generation.
+///
+/// If there is a stored image with the same filename, it is overwritten, or the upload
+/// is rejected, or the filename is changed with respect of the collision policy.
+/// The image format is detected by the magic bytes and checked against the declared
+/// content type, see `check_image_type`. Images with the dimensions over the pixel limits
+/// are rejected. If the decode check is on, the image is decoded before it is stored,
+/// undecodable images are rejected with the decoder error reason.
+/// The data is written to a temporary file and moved to the image path only when it's
+/// complete and verified, so a failed upload never damages the stored image.
+/// Returns info about the stored image, or the failure reason.
+fn store_image<R: Read>(
+    file_path: &str,
+    source: ImageSource<R>,
+    settings: &Settings,
+    collision: Collision,
+) -> ImageUploadResult {
+    let mut result = ImageUploadResult {
+        filename: source.filename,
+        content_type: source.content_type,
+        original_filename: source.original_filename,
+        warnings: source.warnings,
+        download: source.download,
+        ..ImageUploadResult::default()
+    };
+
+    let mut data = source.data;
+    let mut header = Vec::new();
+    if let Err(e) = data.by_ref().take(HEADER_LENGTH).read_to_end(&mut header) {
+        log::warn!("I/O ERROR \"{}\" while reading uploaded image data!", e);
+        result.reason = io_error_reason(&e);
+        return result;
+    }
+    if let Err(reason) = check_image_type(&mut result, &header, settings) {
+        result.reason = reason;
+        return result;
+    }
+    // Headers of most images fit in the leading bytes, others are checked
+    // with the decode check only.
+    if let Ok(info) = image_info::ImageInfo::read(io::Cursor::new(&header)) {
+        if let Err(reason) = settings.pixel_limits.check(info.width, info.height) {
+            result.reason = reason;
+            return result;
+        }
+    }
+    // One byte over the limit is enough to detect that the image is too large.
+    let mut data = io::Cursor::new(header)
+        .chain(data)
+        .take(settings.max_image_size + 1);
+
+    let mut image_path = match stored_image_path(file_path, &result.filename) {
+        Some(x) => x,
+        None => {
+            result.reason = String::from("invalid filename");
+            return result;
+        }
+    };
+
+    // The data is written to a temporary file and verified, only then it is moved
+    // to the image path, so the stored image is kept intact if the upload fails.
+    let (tmp_path, size) = match stage_image(&mut data, &image_path, settings) {
+        Ok(x) => x,
+        Err(reason) => {
+            result.reason = reason;
+            return result;
+        }
+    };
+    let store = |path: &PathBuf, create_new: bool| {
+        file_utils::commit_image_data(&tmp_path, path, create_new).map(|_| size)
+    };
+
+    let written = match collision {
+        Collision::Overwrite => store(&image_path, false),
+        Collision::Reject => store(&image_path, true),
+        Collision::Rename => {
+            let requested = result.filename.clone();
+            let mut number = 0;
+            let written = loop {
+                match store(&image_path, true) {
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::AlreadyExists
+                            && number < MAX_RENAME_ATTEMPTS =>
+                    {
+                        number += 1;
+                        result.filename = file_utils::numbered_filename(&requested, number);
+                        image_path.set_file_name(&result.filename);
+                    }
+                    written => break written,
+                }
+            };
+
+            if number > 0 && written.is_ok() {
+                result.original_filename.get_or_insert(requested);
+                result
+                    .warnings
+                    .push(String::from("filename changed to avoid collision"));
+            }
+            written
+        }
+    };
+
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    match written {
+        Ok(size) => {
+            result.size = size;
+            result.success = true;
+            result.reason = String::from("ok");
+            thumbnail::spawn(image_path, settings.pixel_limits);
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            result.reason = String::from("file already exists")
+        }
+        Err(ref e) => result.reason = io_error_reason(e),
+    }
+
+    result
+}
+
+/// Get the upload failure reason for the error of the image data reading or writing.
+fn io_error_reason(e: &io::Error) -> String {
+    if is_timeout(e) {
+        String::from("download timed out")
+    } else {
+        String::from("I/O error")
+    }
+}
+
+/// Check whether the error is a read timeout, socket timeouts are reported as
+/// `io::ErrorKind::WouldBlock` on some platforms.
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+#[derive(Debug)]
+struct ImageUploadRequest {
+    filename: Option<String>,
+    content_type: Option<String>,
+    url: Option<String>,
+    /// Image data decoded while the request is read, or the decoding failure reason.
+    data: Option<Result<StagedData, String>>,
+    /// Filename collision policy overriding the request one.
+    on_collision: Option<Collision>,
+}
+
+/// Image data of the JSON upload request item decoded to a temporary file in the upload
+/// directory, the file is removed when the data is dropped.
+#[derive(Debug)]
+struct StagedData {
+    /// Media type of the "data:" URI, if any.
+    content_type: Option<String>,
+    file: Option<fs::File>,
+    path: PathBuf,
+}
+
+impl Read for StagedData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for StagedData {
+    fn drop(&mut self) {
+        // Open files can't be removed on some platforms.
+        self.file = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Handle a request with a body containing JSON with an array of base64-encoded images
+/// or URLs to download.
+///
+/// Handles a request with a body containg JSON with an array of base64-encoded images
+/// or URLS to download, saving valid images to disk storage.
+/// Returning JSON array with info about successfully saved images.
+/// The body is parsed incrementally and the image data is decoded as it is read,
+/// see `read_upload_requests`, so large requests aren't kept in memory.
+/// Filename collisions are resolved with the policy set by the item "on_collision" field,
+/// or the request "on_collision" query parameter, or the settings.
+/// If the request body is too large, or there are too many images, returns a HTTP 413
+/// "Payload Too Large" error, images too large are reported as item failures.
+/// Items are processed concurrently by up to the download concurrency limit at once,
+/// the results are returned in the order of the items. Downloads not complete before
+/// the batch timeout are aborted and reported as item failures.
+/// If "async" query parameter is true, the items are processed in the background and
+/// a HTTP 202 "Accepted" response with the upload job is returned, see `handle_job_get`.
+/// If the maximal number of the upload jobs are running, returns a HTTP 503
+/// "Service Unavailable" error with Retry-After header.
+/// In case of severe errors returns a HTTP 400 Bad request error.
+pub fn handle_json_images_post(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+) -> Response {
+    log::trace!("handle_json_images_post...");
+
+    let collision = match collision_from_request(request, settings) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
+    let asynchronous = match async_from_request(request) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
+    if asynchronous && jobs::running() >= settings.max_jobs {
+        return jobs_limit_response();
+    }
+
+    let upload_requests = match read_upload_requests(request, file_path, settings) {
+        Ok(x) => x,
+        Err(response) => return response,
+    };
+    log::debug!("upload_requests = {:?}", upload_requests);
+
+    if asynchronous {
+        return start_upload_job(upload_requests, file_path, settings, collision);
+    }
+
+    let results = Mutex::new(Vec::new());
+    results
+        .lock()
+        .unwrap()
+        .resize_with(upload_requests.len(), || None);
+    upload_json_items(upload_requests, file_path, settings, collision, &|i, x| {
+        results.lock().unwrap()[i] = Some(x)
+    });
+    let results: Vec<ImageUploadResult> = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect();
+
+    log::debug!("handle_json_images_post => results = {:?}", results);
+    Response::json(&results)
+}
+
+/// Read the JSON array of the upload request items from the request body.
+///
+/// The body is parsed incrementally, "data" values and "data:" URIs in "url" values
+/// are decoded straight to temporary files as they are read, see `stage_data`,
+/// so the memory use doesn't depend on the body size.
+/// Returns the items, or an error response: HTTP 413 "Payload Too Large" if the body
+/// is too large or there are too many items, HTTP 400 "Bad Request" if the body can't
+/// be read or isn't a valid JSON array of the items.
+fn read_upload_requests(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+) -> Result<Vec<ImageUploadRequest>, Response> {
+    let too_large = || error_response(413, "request body too large");
+
+    if body_size_exceeded(request, settings.max_body_size) {
+        return Err(too_large());
+    }
+    let body = match request.data() {
+        Some(x) => x.take(settings.max_body_size + 1),
+        None => return Err(error_response(400, "request body can't be read")),
+    };
+
+    let mut json = JsonReader::new(io::BufReader::new(body));
+    let mut items = Vec::new();
+    let result = read_upload_items(&mut json, file_path, settings, &mut items);
+    // One byte over the limit is read only if the body is too large.
+    if json.get_ref().get_ref().limit() == 0 {
+        return Err(too_large());
+    }
+
+    match result {
+        Ok(true) => Ok(items),
+        Ok(false) => {
+            let reason = format!(
+                "too many images in request, {} allowed",
+                settings.max_batch_items
+            );
+            Err(error_response(413, &reason))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            Err(error_response(400, &format!("invalid request: {}", e)))
+        }
+        Err(e) => {
+            log::warn!("I/O ERROR \"{}\" while reading request body!", e);
+            Err(error_response(400, "request body can't be read"))
+        }
+    }
+}
+
+/// Read the upload request items of the JSON array.
+///
+/// Returns `false` if there are more items than allowed, the rest isn't read then.
+fn read_upload_items<R: BufRead>(
+    json: &mut JsonReader<R>,
+    file_path: &str,
+    settings: &Settings,
+    items: &mut Vec<ImageUploadRequest>,
+) -> io::Result<bool> {
+    json.expect(b'[')?;
+    while json.next_element(b']', items.is_empty())? {
+        if items.len() == settings.max_batch_items {
+            return Ok(false);
+        }
+        items.push(read_upload_item(json, file_path, settings)?);
+    }
+    json.expect_end()?;
+    Ok(true)
+}
+
+/// Read the upload request item object, unknown fields are skipped.
+///
+/// Failures to decode the image data are item failures, not the request ones.
+fn read_upload_item<R: BufRead>(
+    json: &mut JsonReader<R>,
+    file_path: &str,
+    settings: &Settings,
+) -> io::Result<ImageUploadRequest> {
+    let mut item = ImageUploadRequest {
+        filename: None,
+        content_type: None,
+        url: None,
+        data: None,
+        on_collision: None,
+    };
+    // Data embedded in the URL needs no download, it's used unless there is "data".
+    let mut url_data = None;
+
+    json.expect(b'{')?;
+    let mut first = true;
+    while json.next_element(b'}', first)? {
+        first = false;
+        match &json.read_key()?[..] {
+            "filename" => item.filename = json.read_optional_string()?,
+            "content_type" => item.content_type = json.read_optional_string()?,
+            "on_collision" => {
+                item.on_collision = match json.read_optional_string()? {
+                    Some(x) => Some(
+                        x.parse()
+                            .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                    ),
+                    None => None,
+                }
+            }
+            "data" => {
+                item.data = None;
+                if !json.read_null()? {
+                    let mut value = json.string_reader()?;
+                    item.data = Some(stage_data(&mut value, file_path, settings.max_image_size));
+                    value.finish()?;
+                }
+            }
+            "url" => {
+                item.url = None;
+                url_data = None;
+                if !json.read_null()? {
+                    let mut value = json.string_reader()?;
+                    let mut scheme = Vec::new();
+                    value.by_ref().take(5).read_to_end(&mut scheme)?;
+                    if scheme.eq_ignore_ascii_case(b"data:") {
+                        let uri = io::Cursor::new(scheme).chain(&mut value);
+                        url_data = Some(stage_data(uri, file_path, settings.max_image_size));
+                        value.finish()?;
+                    } else {
+                        item.url = Some(value.into_string(scheme)?);
+                    }
+                }
+            }
+            _ => json.skip_value()?,
+        }
+    }
+
+    if item.data.is_none() {
+        item.data = url_data;
+    }
+    Ok(item)
+}
+
+/// Decode the base64 data or the "data:" URI to a temporary file in the upload directory.
+///
+/// The data is decoded as it is read, so it isn't kept in memory, decoding of the data
+/// larger than the limit is stopped early. Returns the staged data, or the failure reason.
+fn stage_data<R: Read>(value: R, file_path: &str, max_size: u64) -> Result<StagedData, String> {
+    log::trace!("stage_data(R, \"{}\") ...", file_path);
+
+    let (content_type, decoded) = data_uri::decoder(value).map_err(|e| e.to_string())?;
+    let mut staged = StagedData {
+        content_type,
+        file: None,
+        path: file_utils::temp_path_for(&Path::new(file_path).join("data")),
+    };
+
+    let size = match file_utils::write_image_data(decoded.take(max_size + 1), &staged.path) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Err(e.to_string()),
+        Err(_) => return Err(String::from("I/O error")),
+    };
+    if size > max_size {
+        log::debug!("stage_data => Err(\"image too large\")");
+        return Err(String::from("image too large"));
+    }
+    staged.file = Some(fs::File::open(&staged.path).map_err(|_| String::from("I/O error"))?);
+
+    log::debug!("stage_data => {} bytes", size);
+    Ok(staged)
+}
+
+/// Start the upload job processing the JSON upload request items in the background.
+///
+/// Returns a HTTP 202 "Accepted" response with the job and its URL in Location header.
+fn start_upload_job(
+    upload_requests: Vec<ImageUploadRequest>,
+    file_path: &str,
+    settings: &Settings,
+    collision: Collision,
+) -> Response {
+    let job = jobs::Job::new(upload_requests.len());
+    let location = format!("/jobs/{}", job.id);
+    let response = Response::json(&job)
+        .with_status_code(202)
+        .with_unique_header("Location", location);
+
+    let (file_path, settings) = (file_path.to_string(), settings.clone());
+    let max_jobs = settings.max_jobs;
+    let started = jobs::spawn(job, PathBuf::from(&file_path), max_jobs, move |done| {
+        upload_json_items(upload_requests, &file_path, &settings, collision, done)
+    });
+
+    match started {
+        Ok(()) => response,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => jobs_limit_response(),
+        Err(_) => error_response(500, "I/O error"),
+    }
+}
+
+/// Get HTTP 503 "Service Unavailable" response for the upload job not started because
+/// the maximal number of the jobs are running.
+fn jobs_limit_response() -> Response {
+    error_response(503, "too many upload jobs running").with_unique_header("Retry-After", "1")
+}
+
+/// Store the images of the JSON upload request items.
+///
+/// Items are taken from the queue by up to the download concurrency limit of workers,
+/// every result is reported to `done` with the item index as soon as it is ready.
+fn upload_json_items(
+    upload_requests: Vec<ImageUploadRequest>,
+    file_path: &str,
+    settings: &Settings,
+    collision: Collision,
+    done: &(dyn Fn(usize, ImageUploadResult) + Sync),
+) {
+    let deadline = Instant::now() + settings.download.batch_timeout;
+    let workers = settings
+        .download
+        .concurrency
+        .max(1)
+        .min(upload_requests.len());
+    let queue = Mutex::new(upload_requests.into_iter().enumerate());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((i, item)) => done(
+                        i,
+                        upload_json_item(item, file_path, settings, collision, deadline),
+                    ),
+                    None => break,
+                }
+            });
+        }
+    });
+}
+
+/// Store the image of the JSON upload request item.
+///
+/// Downloads of the images by URL are aborted after the deadline.
+fn upload_json_item(
+    mut item: ImageUploadRequest,
+    file_path: &str,
+    settings: &Settings,
+    collision: Collision,
+    deadline: Instant,
+) -> ImageUploadResult {
+    let source = if item.data.is_some() {
+        image_from_staged_data(&mut item)
+            .map(|x| x.map(|data| Box::new(data) as Box<dyn Read + Send>))
+            .map_err(|e| (e, None))
+    } else if item.url.is_some() {
+        image_from_url(&mut item, settings, Some(deadline))
+    } else {
+        return ImageUploadResult {
+            filename: item.filename.unwrap_or_default(),
+            content_type: item.content_type.unwrap_or_default(),
+            reason: String::from("nor url or data are specified"),
+            ..ImageUploadResult::default()
+        };
+    };
+
+    match source {
+        Ok(source) => store_image(
+            file_path,
+            source,
+            settings,
+            item.on_collision.unwrap_or(collision),
+        ),
+        Err((e, download)) => ImageUploadResult {
+            filename: item.filename.unwrap_or_default(),
+            content_type: item.content_type.unwrap_or_default(),
+            reason: e,
+            download,
+            ..ImageUploadResult::default()
+        },
+    }
+}
+
+/// Handle a multipart request with body containing binary images data array.
+///
+/// Handles a request body parts containing MIME of "image/*" type, other
+/// parts are skipped, saving valid images to disk storage.
+/// Returning JSON array with info about successfully saved images.
+/// Filename collisions are resolved with the policy set by the preceding "on_collision"
+/// text field, or the request "on_collision" query parameter, or the settings.
+/// If the request body is too large returns a HTTP 413 "Payload Too Large" error,
+/// images too large and images over the request limit are reported as item failures.
+/// In case of severe errors returns a HTTP 400 Bad request error.
+pub fn handle_multipart_images_post(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+) -> Response {
+    log::trace!("handle_multipart_images_post...");
+
+    let mut collision = match collision_from_request(request, settings) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
+
+    if body_size_exceeded(request, settings.max_body_size) {
+        return error_response(413, "request body too large");
+    }
+
+    let boundary = match multipart_boundary(request) {
+        Some(x) => x,
+        None => {
+            log::warn!("Multipart data parsing error: no boundary specified");
+            return Response::empty_400();
+        }
+    };
+    // Content-Length isn't specified for chunked requests, so the body is limited as it's read.
+    let mut body = match request.data() {
+        Some(x) => LimitedBody::new(x, settings.max_body_size),
+        None => return Response::empty_400(),
+    };
+    let mut multipart_items = Multipart::with_body(&mut body, boundary);
+
+    let mut results = Vec::<ImageUploadResult>::new();
+    let mut images = 0;
+
+    while let Ok(Some(mut item)) = multipart_items.read_entry() {
+        if &*item.headers.name == "on_collision" && item.headers.content_type.is_none() {
+            let mut value = String::new();
+            let read = item
+                .data
+                .by_ref()
+                .take(MAX_TEXT_FIELD_LENGTH)
+                .read_to_string(&mut value);
+            match read.map(|_| value.parse()) {
+                Ok(Ok(x)) => collision = x,
+                _ => results.push(ImageUploadResult {
+                    filename: item.headers.name.to_string(),
+                    reason: format!("invalid collision policy \"{}\"", value),
+                    ..ImageUploadResult::default()
+                }),
+            }
+            continue;
+        }
+
+        match image_from_multipart_field(&mut item) {
+            Ok(source) => {
+                images += 1;
+                results.push(if images > settings.max_batch_items {
+                    ImageUploadResult {
+                        filename: source.filename,
+                        content_type: source.content_type,
+                        reason: String::from("too many images in request"),
+                        ..ImageUploadResult::default()
+                    }
+                } else {
+                    store_image(file_path, source, settings, collision)
+                });
+            }
+            Err((headers, err)) => {
+                results.push(ImageUploadResult {
+                    filename: headers.name.to_string(),
+                    content_type: headers
+                        .content_type
+                        .clone()
+                        .map(|x| x.to_string())
+                        .unwrap_or(String::new()),
+                    reason: err.to_string(),
+                    ..ImageUploadResult::default()
+                });
+            }
+        }
+    }
+
+    if body.exceeded() {
+        return error_response(413, "request body too large");
+    }
+
+    log::debug!("handle_multipart_images_post => results = {:?}", results);
+    Response::json(&results)
+}
+
+/// Maximal length of the multipart text field value read.
+const MAX_TEXT_FIELD_LENGTH: u64 = 64;
+
+/// Get the boundary of the multipart request body from the Content-Type header.
+fn multipart_boundary(request: &Request) -> Option<String> {
+    let content_type = request.header("Content-Type")?;
+    let start = content_type.find("boundary=")? + "boundary=".len();
+    let value = content_type[start..].split(';').next().unwrap_or("").trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.trim_matches('"').to_string())
+    }
+}
+
+/// Handle a request with a body containing raw image data.
+///
+/// Streams the request body to disk storage under the specified filename sanitized
+/// for safe storing, infering extension from the Content-Type header if it is partial,
+/// or generating the filename if it is missing. Returning JSON with info about the saved image.
+/// Filename collisions are resolved with the policy set by "on_collision" query parameter
+/// or the settings, a rejected upload is reported with HTTP 409 "Conflict" status.
+/// If Content-Type isn't "image/*" – returns a HTTP 406 "Not Acceptable" error response,
+/// if it isn't specified – returns a HTTP 400 "Bad Request" error response.
+/// If the request body is too large returns a HTTP 413 "Payload Too Large" error.
+pub fn handle_raw_image_upload(
+    request: &Request,
+    file_path: &str,
+    settings: &Settings,
+    filename: &str,
+) -> Response {
+    log::trace!("handle_raw_image_upload(\"{}\")...", filename);
+
+    let collision = match collision_from_request(request, settings) {
+        Ok(x) => x,
+        Err(e) => return error_response(400, &e),
+    };
+
+    let content_type = match request.header("Content-Type") {
+        Some(x) => media_type(x),
+        None => return Response::empty_400(),
+    };
+    if !content_type.starts_with("image/") {
+        return Response::empty_406();
+    }
+    if body_size_exceeded(request, settings.max_body_size) {
+        return error_response(413, "request body too large");
+    }
+
+    let mut data = match request.data() {
+        Some(x) => LimitedBody::new(x, settings.max_body_size),
+        None => {
+            let result = ImageUploadResult {
+                filename: filename.to_string(),
+                content_type,
+                reason: String::from("no image data"),
+                ..ImageUploadResult::default()
+            };
+            return Response::json(&result).with_status_code(400);
+        }
+    };
+
+    let source = ImageSource::new(filename, content_type, &mut data);
+    let result = store_image(file_path, source, settings, collision);
+    if data.exceeded() {
+        return error_response(413, "request body too large");
+    }
+    let status_code = match (result.success, &result.reason[..]) {
+        (true, _) => 200,
+        (false, "I/O error") => 500,
+        (false, "file already exists") => 409,
+        (false, "image too large") => 413,
+        (false, x) if x.starts_with("image dimensions") => 413,
+        (false, "image format not recognized") => 415,
+        (false, x) if x.starts_with("declared content type") => 415,
+        (false, x) if x.ends_with("is not allowed") => 415,
+        (false, x) if x.starts_with("invalid image data") => 422,
+        _ => 400,
+    };
+
+    log::debug!("handle_raw_image_upload => result = {:?}", result);
+    Response::json(&result).with_status_code(status_code)
+}
+
+/// Request body reader failing once more data than the limit is read, so the body
+/// cut at the limit is never taken for the complete one.
+struct LimitedBody<R>(io::Take<R>);
+
+impl<R: Read> LimitedBody<R> {
+    fn new(body: R, limit: u64) -> Self {
+        // One byte over the limit is enough to detect that the body is too large.
+        LimitedBody(body.take(limit + 1))
+    }
+
+    /// Check whether the body is larger than the limit.
+    fn exceeded(&self) -> bool {
+        self.0.limit() == 0
+    }
+}
+
+impl<R: Read> Read for LimitedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        if self.exceeded() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "request body too large",
+            ));
+        }
+        Ok(read)
+    }
+}
+
+/// Get the lowercase media type of the Content-Type header value without parameters,
+/// so it can be compared with the allowed content types.
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase()
+}
+
+/// Check whether the request body size from Content-Length header exceeds the limit.
+fn body_size_exceeded(request: &Request, limit: u64) -> bool {
+    matches!(
+        request.header("Content-Length").and_then(|x| x.parse::<u64>().ok()),
+        Some(x) if x > limit
+    )
+}
+
+/// Get whether the upload is asynchronous from the "async" query parameter.
+fn async_from_request(request: &Request) -> Result<bool, String> {
+    match request.get_param("async").as_ref().map(|x| &x[..]) {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") => Ok(true),
+        Some(x) => Err(format!(
+            "invalid async parameter \"{}\", true or false expected",
+            x
+        )),
+    }
+}
+
+/// Get the filename collision policy from "on_collision" query parameter, or the settings.
+fn collision_from_request(request: &Request, settings: &Settings) -> Result<Collision, String> {
+    match request.get_param("on_collision") {
+        Some(x) => x.parse(),
+        None => Ok(settings.collision),
+    }
+}
+
+/// Decode an image from multipart/form-data field and
+/// return an image source with the image data reader.
+fn image_from_multipart_field<'b, R: Read>(
+    item: &'b mut MultipartField<&'b mut Multipart<R>>,
+) -> Result<ImageSource<&'b mut MultipartData<&'b mut Multipart<R>>>, (&'b FieldHeaders, &'b str)> {
+    log::trace!("image_from_multipart_field...");
+    let headers = &item.headers;
+
+    if let Some(content_type) = &headers.content_type {
+        let content_type = media_type(&content_type.to_string());
+        if content_type.starts_with("image/") {
+            let filename = if let Some(filename) = &headers.filename {
+                &filename[..]
+            } else {
+                &headers.name[..]
+            };
+            let source = ImageSource::new(filename, content_type, &mut item.data);
+
+            log::debug!(
+                "image_from_multipart_field => (\"{}\", \"{}\", _)",
+                source.filename,
+                source.content_type
+            );
+            return Ok(source);
+        }
+    }
+
+    log::debug!("image_from_multipart_field => Err(_, \"no image data\")");
+    Err((headers, "no image data"))
+}
+
+/// Return an image source with the image data decoded from base64 or "data:" URI
+/// while the request was read, see `stage_data`.
+///
+/// The media type of the "data:" URI takes precedence over the declared content type.
+fn image_from_staged_data(
+    item: &mut ImageUploadRequest,
+) -> Result<ImageSource<StagedData>, String> {
+    log::trace!("image_from_staged_data...");
+
+    match item.data.take() {
+        Some(Ok(data)) => {
+            let declared = item.content_type.take();
+            let content_type = data.content_type.clone().or(declared).map_or_else(
+                || String::from("application/octet-stream"),
+                |x| media_type(&x),
+            );
+            let filename = item.filename.as_ref().map_or("", |x| &x[..]);
+            let source = ImageSource::new(filename, content_type, data);
+            log::debug!(
+                "image_from_staged_data => (\"{}\", \"{}\", _)",
+                source.filename,
+                source.content_type
+            );
+            Ok(source)
+        }
+        Some(Err(e)) => {
+            log::debug!("image_from_staged_data => Err({})", e);
+            Err(e)
+        }
+        None => {
+            log::debug!("image_from_staged_data => Err(\"no image data\")");
+            Err("no image data".to_string())
+        }
+    }
+}
+
+/// Download an image specified by URL and
+/// return an image source with the response body reader.
+///
+/// The body is read to the end of the response, chunked transfer encoding is supported,
+/// so the image data is streamed from the origin without buffering.
+/// Content-Length, if specified, is checked against the maximal image size.
+/// Redirects, timeouts and retries are handled with respect of the download settings,
+/// the download outcome is returned both with the image source and the failure reason.
+/// Hosts with non-public addresses are blocked unless allowed, see `http_client::check_addr`.
+/// Unless the filename is specified, it's taken from the Content-Disposition header
+/// or from the path of the final URL, a stub name is generated if both are missing.
+fn image_from_url(
+    item: &mut ImageUploadRequest,
+    settings: &Settings,
+    deadline: Option<Instant>,
+) -> Result<ImageSource<Box<dyn Read + Send>>, DownloadError> {
+    log::trace!("image_from_url...");
+
+    let url = match &item.url {
+        Some(x) => x,
+        None => {
+            let e = String::from("image URL not specified");
+            log::debug!("image_from_url => Err(\"{}\")", e);
+            return Err((e, None));
+        }
+    };
+
+    let declared = item.content_type.take();
+
+    let response = match http_client::get(url, &settings.download, deadline) {
+        Ok(x) => x,
+        Err(e) => {
+            log::debug!("image_from_url(\"{}\") => Err({:?})", url, e);
+            let download = Download {
+                url: e.url,
+                attempts: e.attempts,
+                status: None,
+            };
+            return Err((e.reason, Some(download)));
+        }
+    };
+
+    let download = Download {
+        url: response.url.to_string(),
+        attempts: response.attempts,
+        status: Some(response.status),
+    };
+    let fail = |e: String| {
+        log::debug!("image_from_url(\"{}\") => Err(\"{}\")", url, e);
+        Err((e, Some(download.clone())))
+    };
+
+    if response.status < 200 || response.status >= 300 {
+        return fail(format!("HTTP status {}", response.status));
+    }
+
+    let content_type = media_type(
+        response
+            .header("Content-Type")
+            .or_else(|| declared.as_ref().map(|x| &x[..]))
+            .unwrap_or_default(),
+    );
+    if !content_type.starts_with("image/") {
+        return fail(String::from("not an image"));
+    }
+
+    match response.header("Content-Length").map(|x| x.parse::<u64>()) {
+        Some(Ok(x)) if x > settings.max_image_size => return fail(String::from("image too large")),
+        Some(Err(_)) => return fail(String::from("invalid content length in response")),
+        _ => (),
+    }
+
+    let filename = match &item.filename {
+        Some(filename) => filename.clone(),
+        None => response
+            .header("Content-Disposition")
+            .and_then(file_utils::filename_from_content_disposition)
+            .unwrap_or_else(|| file_utils::filename_from_url(&response.url)),
+    };
+    let mut source = ImageSource::new(&filename, content_type, response.body);
+    source.download = Some(download);
+
+    log::debug!(
+        "image_from_url(\"{}\") => Ok((\"{}\", \"{}\", _))",
+        url,
+        source.filename,
+        source.content_type
+    );
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{Collision, DecodeCheck, HostRule, PixelLimits, Settings, TypeMismatch};
+    use image::{GenericImageView, ImageDecoder};
+    use rouille::input::multipart::get_multipart_input;
+    use std::io::Read;
+
+    #[test]
+    fn test_image_from_multipart_field() {
+        let http_rq = mock::multipart_formdata_request();
+        let mut multipart_items = get_multipart_input(&http_rq).unwrap();
+
+        let mut item = multipart_items.next().unwrap();
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_multipart_field(&mut item).unwrap();
+        assert_eq!(filename, "sample.jpg");
+        assert_eq!(content_type, "image/jpeg");
+        let mut buffer = [0u8; 15];
+        data.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"JPEG IMAGE DATA");
+
+        let mut item = multipart_items.next().unwrap();
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_multipart_field(&mut item).unwrap();
+        assert_eq!(filename, "file-from-name.png");
+        assert_eq!(content_type, "image/png");
+        let mut buffer = [0u8; 14];
+        data.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"PNG IMAGE DATA");
+
+        let mut item = multipart_items.next().unwrap();
+        match super::image_from_multipart_field(&mut item) {
+            Err((_, msg)) => assert_eq!(msg, "no image data"),
+            _ => panic!("text/plain is not an image!"),
+        }
+
+        // Test for content type with parameters.
+        let body = "\
+                    --boundary\r\n\
+                    Content-Disposition: form-data; name=\"file\"; filename=\"a.jpg\"\r\n\
+                    Content-Type: Image/JPEG; charset=binary\r\n\
+                    \r\n\
+                    JPEG IMAGE DATA\r\n\
+                    --boundary--";
+        let headers = vec![(
+            String::from("Content-Type"),
+            String::from("multipart/form-data; boundary=boundary"),
+        )];
+        let http_rq =
+            rouille::Request::fake_http("POST", "/images", headers, body.as_bytes().to_vec());
+        let mut multipart_items = get_multipart_input(&http_rq).unwrap();
+        let mut item = multipart_items.next().unwrap();
+        let source = super::image_from_multipart_field(&mut item).unwrap();
+        assert_eq!(source.content_type, "image/jpeg");
+    }
+
+    #[test]
+    fn test_image_from_staged_data() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-staged-data-t4n8wd");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy();
+        let stage =
+            |data: &str, max_size| Some(super::stage_data(data.as_bytes(), &file_path, max_size));
+        let read = |mut data: super::StagedData| {
+            let mut decoded = Vec::new();
+            data.read_to_end(&mut decoded).unwrap();
+            decoded
+        };
+
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+
+        match super::image_from_staged_data(&mut uprq) {
+            Err(e) => assert_eq!(e, "no image data"),
+            _ => panic!("data == None isn't an image!"),
+        }
+
+        uprq.data = stage("VEVTVCBKUEVHIERBVEE=", 1024);
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(read(data), b"TEST JPEG DATA".to_vec());
+        assert!(filename.starts_with("untitled@") && filename.ends_with(".bin"));
+        assert_eq!(content_type, "application/octet-stream");
+
+        uprq.filename = Some(String::from("test.jpg"));
+        uprq.data = stage("VEVTVCBKUEVHIERBVEE=", 1024);
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(read(data), b"TEST JPEG DATA".to_vec());
+        assert_eq!(filename, "test.jpg");
+        assert_eq!(content_type, "application/octet-stream");
+
+        uprq.content_type = Some(String::from("image/jpeg"));
+        uprq.data = stage("VEVTVCBKUEVHIERBVEE=", 1024);
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(read(data), b"TEST JPEG DATA".to_vec());
+        assert_eq!(filename, "test.jpg");
+        assert_eq!(content_type, "image/jpeg");
+
+        uprq.filename = Some(String::from("../../evil:name.jpg"));
+        uprq.data = stage("VEVTVCBKUEVHIERBVEE=", 1024);
+
+        let source = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(source.filename, "evil_name.jpg");
+        assert_eq!(
+            source.original_filename,
+            Some(String::from("../../evil:name.jpg"))
+        );
+        assert_eq!(
+            source.warnings,
+            [
+                "filename sanitized: directory components removed",
+                "filename sanitized: reserved characters replaced"
+            ]
+        );
+        assert_eq!(read(source.data), b"TEST JPEG DATA".to_vec());
+
+        uprq.filename = None;
+        uprq.content_type = Some(String::from("image/jpeg"));
+        uprq.data = stage("data:image/png;base64,VEVTVCBQTkcg\r\nREFUQQ", 1024);
+
+        let source = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(source.content_type, "image/png");
+        assert!(source.filename.ends_with(".png"));
+        assert_eq!(read(source.data), b"TEST PNG DATA".to_vec());
+
+        uprq.data = stage("data:;base64,-_-_", 1024);
+
+        let source = super::image_from_staged_data(&mut uprq).unwrap();
+        assert_eq!(source.content_type, "application/octet-stream");
+        assert_eq!(read(source.data), [0xfb, 0xff, 0xbf]);
+
+        uprq.data = stage("data:image/png;base64", 1024);
+
+        match super::image_from_staged_data(&mut uprq) {
+            Err(e) => assert_eq!(e, "invalid data URI"),
+            _ => panic!("data URI without data isn't an image!"),
+        }
+
+        uprq.data = stage("VEVTV!", 1024);
+
+        match super::image_from_staged_data(&mut uprq) {
+            Err(e) => assert_eq!(e, "invalid base64 data"),
+            _ => panic!("invalid base64 data is decoded!"),
+        }
+
+        uprq.data = stage("data:image/png;base64,VEVTVCBQTkcgREFUQQ", 8);
+
+        match super::image_from_staged_data(&mut uprq) {
+            Err(e) => assert_eq!(e, "image too large"),
+            _ => panic!("image larger than the limit is decoded!"),
+        }
+
+        // Staged data files are removed with the data.
+        assert_eq!(std::fs::read_dir(&tmp_path).unwrap().count(), 0);
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_json_item_data_uri() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-json-data-uri-x7v3kq");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        let item = br#"{
+            "filename": "canvas",
+            "url": "data:image\/jpeg;base64,VEVTVCBKUEVH\nIERBVEE=",
+            "unknown": [{"url": null}]
+        }"#;
+        let uprq = super::read_upload_item(
+            &mut super::JsonReader::new(&item[..]),
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        )
+        .unwrap();
+        assert_eq!(uprq.url, None);
+        let result = super::upload_json_item(
+            uprq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+            Collision::Overwrite,
+            std::time::Instant::now(),
+        );
+        assert!(result.success);
+        assert_eq!(result.filename, "canvas.jpg");
+        assert_eq!(result.content_type, "image/jpeg");
+        assert_eq!(result.download, None);
+
+        tmp_path.push("canvas.jpg");
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), b"TEST JPEG DATA");
+        tmp_path.pop();
+
+        while super::thumbnail::status(&tmp_path.join("canvas.jpg"))
+            == super::thumbnail::Status::Pending
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_upload_requests() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-read-upload-requests-m3q9zs");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let read = |body: &str| {
+            let http_rq = rouille::Request::fake_http("POST", "/images", vec![], body.into());
+            super::read_upload_requests(&http_rq, &file_path, &Settings::default())
+        };
+        let decoded = |item: super::ImageUploadRequest| match item.data {
+            Some(Ok(mut data)) => {
+                let mut decoded = Vec::new();
+                data.read_to_end(&mut decoded).unwrap();
+                Ok(decoded)
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(String::from("no image data")),
+        };
+
+        let mut items = match read(
+            r#"[
+                {
+                    "filename": "a\u002ejpg",
+                    "content_type": null,
+                    "extra": {"data": [1, true]},
+                    "data": "VEVTVCBKUEVH\r\nIERBVEE="
+                },
+                {"url": "http://localhost/image.png", "data": null, "on_collision": "rename"},
+                {"data": "VEVTV!"},
+                {"data": "\/\/8="}
+            ]"#,
+        ) {
+            Ok(x) => x.into_iter(),
+            Err(_) => panic!("valid request is rejected!"),
+        };
+
+        let item = items.next().unwrap();
+        assert_eq!(item.filename, Some(String::from("a.jpg")));
+        assert_eq!(item.content_type, None);
+        assert_eq!(decoded(item).unwrap(), b"TEST JPEG DATA");
+
+        let item = items.next().unwrap();
+        assert_eq!(item.url, Some(String::from("http://localhost/image.png")));
+        assert_eq!(item.on_collision, Some(Collision::Rename));
+        assert!(item.data.is_none());
+
+        assert_eq!(
+            decoded(items.next().unwrap()).unwrap_err(),
+            "invalid base64 data"
+        );
+        assert_eq!(decoded(items.next().unwrap()).unwrap(), [0xff, 0xff]);
+        assert!(items.next().is_none());
+
+        let status = |body: &str| read(body).err().map(|x| x.status_code);
+        assert_eq!(status("[]"), None);
+        assert_eq!(status(r#"[{"data": "VEVTVA=="}"#), Some(400));
+        assert_eq!(status(r#"[{"data": "VEVTVA==}]"#), Some(400));
+        assert_eq!(status(r#"[{"on_collision": "sometimes"}]"#), Some(400));
+        assert_eq!(status(r#"{"data": "VEVTVA=="}"#), Some(400));
+        assert_eq!(status("[] []"), Some(400));
+
+        // Staged data of the rejected requests is removed too.
+        assert_eq!(std::fs::read_dir(&tmp_path).unwrap().count(), 0);
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_image_from_url() {
+        let settings = Settings::default();
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "image URL not specified"),
+            _ => panic!("url == None isn't an image!"),
+        }
+
+        uprq.url = Some(String::from("https://ya.ru"));
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "not an image"),
+            _ => panic!("url pointing to html page isn't an image!"),
+        }
+
+        uprq.url = Some(String::from("https://placehold.co/321/png"));
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "png.png");
+        assert_eq!(content_type, "image/png");
+        let img = image::png::PNGDecoder::new(data).unwrap();
+        assert_eq!(img.dimensions(), (321, 321));
+
+        uprq.url = Some(String::from("https://via.placeholder.com/123.jpg"));
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "123.jpg");
+        assert_eq!(content_type, "image/jpeg");
+        let img = image::jpeg::JPEGDecoder::new(data).unwrap();
+        assert_eq!(img.dimensions(), (123, 123));
+    }
+
+    #[test]
+    fn test_image_from_url_self_hosted() {
+        let settings = local_origin_settings();
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "image URL not specified"),
+            _ => panic!("url == None isn't an image!"),
+        }
+
+        uprq.url = Some(String::from(
+            "http://qerqcqwer3454fdsgdfgsdfg/not-exist-server",
+        ));
+
+        if super::image_from_url(&mut uprq, &settings, None).is_ok() {
+            panic!("url pointing to not existent server isn't an image!")
+        }
+
+        let (join_handle, srv_tx) = mock::test_http_server(8888);
+        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
+
+        uprq.url = Some(String::from("http://localhost:8888/not-exist-url"));
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "HTTP status 404"),
+            _ => panic!("url pointing to invalid resource isn't an image!"),
+        }
+
+        uprq.url = Some(String::from("http://localhost:8888/unknown-content-type"));
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "not an image"),
+            _ => panic!("url pointing to a resource with unknown Content-Type isn't an image!"),
+        }
+
+        uprq.content_type = Some(String::from("image/jpeg"));
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "unknown-content-type.jpg");
+        assert_eq!(content_type, "image/jpeg");
+        assert_eq!(read_all(data), b"Hello!");
+
+        uprq.url = Some(String::from("http://localhost:8888/"));
+
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert_eq!(e, "not an image"),
+            _ => panic!("url pointing to html or text resource isn't an image!"),
+        }
+
+        uprq.content_type = None;
+        uprq.url = Some(String::from("http://localhost:8888/image"));
+
+        let super::ImageSource {
+            filename,
+            content_type,
+            data,
+            ..
+        } = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "image.jpg");
+        assert_eq!(content_type, "image/jpeg");
+        assert_eq!(read_all(data), b"TEST JPEG DATA");
+
+        uprq.url = Some(String::from("http://localhost:8888/image-params"));
+
+        let source = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(source.filename, "image-params.jpg");
+        assert_eq!(source.content_type, "image/jpeg");
+        assert!(settings.allows_type(&source.content_type));
+
+        uprq.url = Some(String::from(
+            "http://localhost:8888/image?w=200&sig=abc#top",
+        ));
+
+        let super::ImageSource { filename, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "image.jpg");
+
+        uprq.url = Some(String::from("http://localhost:8888/disposition?id=1"));
+
+        let super::ImageSource {
+            filename,
+            original_filename,
+            ..
+        } = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "my cat_.png");
+        assert_eq!(original_filename, Some(String::from("my cat?.png")));
+
+        uprq.url = Some(String::from("http://localhost:8888/redirect?to=image"));
+
+        let super::ImageSource { filename, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "image.jpg");
+
+        uprq.url = Some(String::from(
+            "http://localhost:8888/gallery/?name=image.jpg",
+        ));
+
+        let super::ImageSource { filename, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert!(filename.starts_with("untitled@") && filename.ends_with(".jpg"));
+
+        uprq.url = Some(String::from("http://localhost:8888/chunked"));
+
+        let super::ImageSource { data, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(read_all(data), b"CHUNKED JPEG DATA");
+
+        uprq.url = Some(String::from("http://localhost:8888/image"));
+
+        let small = Settings {
+            max_image_size: 10,
+            ..local_origin_settings()
+        };
+        match super::image_from_url(&mut uprq, &small, None) {
+            Err((e, _)) => assert_eq!(e, "image too large"),
+            _ => panic!("image larger than the limit is downloaded!"),
+        }
+
+        uprq.filename = Some(String::from("testfile"));
+
+        let super::ImageSource { filename, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "testfile.jpg");
+
+        uprq.filename = Some(String::from("testfile.jpeg"));
+
+        let super::ImageSource { filename, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(filename, "testfile.jpeg");
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+    }
+
+    /// Settings allowing downloads from the mock server on the localhost.
+    fn local_origin_settings() -> Settings {
+        let mut settings = Settings::default();
+        settings.download.allow_hosts = vec![HostRule::Domain(String::from("localhost"))];
+        settings
+    }
+
+    #[test]
+    fn test_image_from_url_redirects_and_retries() {
+        let mut settings = local_origin_settings();
+        settings.download.max_redirects = 2;
+        settings.download.retry_delay = std::time::Duration::from_millis(10);
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+
+        let (join_handle, srv_tx) = mock::test_http_server(8891);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        uprq.url = Some(String::from("http://localhost:8891/redirect"));
+        let super::ImageSource { data, download, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(read_all(data), b"TEST JPEG DATA");
+        assert_eq!(
+            download,
+            Some(super::Download {
+                url: String::from("http://localhost:8891/image"),
+                attempts: 2,
+                status: Some(200),
+            })
+        );
+
+        uprq.url = Some(String::from("http://localhost:8891/redirect-loop"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, Some(download))) => {
+                assert_eq!(e, "too many redirects");
+                assert_eq!(download.attempts, 3);
+            }
+            _ => panic!("redirect loop isn't an image!"),
+        }
+
+        uprq.url = Some(String::from("http://localhost:8891/flaky"));
+        let super::ImageSource { data, download, .. } =
+            super::image_from_url(&mut uprq, &settings, None).unwrap();
+        assert_eq!(read_all(data), b"FLAKY JPEG DATA");
+        assert_eq!(download.unwrap().attempts, 2);
+
+        uprq.url = Some(String::from("http://localhost:8891/error"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, Some(download))) => {
+                assert_eq!(e, "HTTP status 500");
+                assert_eq!(download.attempts, 3);
+                assert_eq!(download.status, Some(500));
+            }
+            _ => panic!("server error isn't an image!"),
+        }
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+
+        uprq.url = Some(String::from("http://localhost:8891/image"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((_, Some(download))) => {
+                assert_eq!(download.attempts, 3);
+                assert_eq!(download.status, None);
+            }
+            _ => panic!("stopped server isn't an image!"),
+        }
+    }
+
+    #[test]
+    fn test_image_from_url_size_limits() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-url-size-pqmtkwzhne");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+        let settings = Settings {
+            max_image_size: 32,
+            ..local_origin_settings()
+        };
+
+        let (join_handle, srv_tx) = mock::test_http_server(8895);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        // Content-Length over the limit is rejected before the body is read.
+        uprq.url = Some(String::from("http://localhost:8895/large"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, Some(download))) => {
+                assert_eq!(e, "image too large");
+                assert_eq!(download.status, Some(200));
+            }
+            _ => panic!("image with Content-Length over the limit is downloaded!"),
+        }
+
+        // Body without Content-Length is streamed until the limit is exceeded.
+        uprq.url = Some(String::from("http://localhost:8895/large-chunked"));
+        let source = super::image_from_url(&mut uprq, &settings, None).unwrap();
+        let result = super::store_image(&file_path, source, &settings, Collision::Overwrite);
+        assert!(!result.success);
+        assert_eq!(result.reason, "image too large");
+        assert_eq!(std::fs::read_dir(&tmp_path).unwrap().count(), 0);
+
+        // Body exactly at the limit is stored.
+        let exact = Settings {
+            max_image_size: 17,
+            ..local_origin_settings()
+        };
+        uprq.url = Some(String::from("http://localhost:8895/chunked"));
+        let source = super::image_from_url(&mut uprq, &exact, None).unwrap();
+        let result = super::store_image(&file_path, source, &exact, Collision::Overwrite);
+        assert!(result.success);
+        assert_eq!(result.size, 17);
+        assert_eq!(
+            std::fs::read(tmp_path.join("chunked.jpg")).unwrap(),
+            b"CHUNKED JPEG DATA"
+        );
+
+        // The stored image is kept if its replacement is over the limit.
+        let short = Settings {
+            max_image_size: 16,
+            ..local_origin_settings()
+        };
+        let source = super::image_from_url(&mut uprq, &short, None).unwrap();
+        let result = super::store_image(&file_path, source, &short, Collision::Overwrite);
+        assert_eq!(result.reason, "image too large");
+        assert_eq!(
+            std::fs::read(tmp_path.join("chunked.jpg")).unwrap(),
+            b"CHUNKED JPEG DATA"
+        );
+        assert_eq!(std::fs::read_dir(&tmp_path).unwrap().count(), 1);
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_image_from_url_address_check() {
+        let mut uprq = super::ImageUploadRequest {
+            content_type: None,
+            data: None,
+            filename: None,
+            url: None,
+            on_collision: None,
+        };
+
+        let (join_handle, srv_tx) = mock::test_http_server(8892);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        let settings = Settings::default();
+        for url in &[
+            "http://localhost:8892/image",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/image.jpg",
+            "http://[::1]:8892/image",
+        ] {
+            uprq.url = Some(url.to_string());
+            match super::image_from_url(&mut uprq, &settings, None) {
+                Err((e, Some(download))) => {
+                    assert!(e.ends_with("is not public"), "{}: {}", url, e);
+                    assert_eq!(download.attempts, 1);
+                    assert_eq!(download.status, None);
+                }
+                _ => panic!("download from {} isn't blocked!", url),
+            }
         }
 
-        uprq.data = Some(String::from("VEVTVCBKUEVHIERBVEE="));
+        // Certificates of IP address hosts can't be verified.
+        uprq.url = Some(String::from("https://93.184.216.34/image.jpg"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, Some(download))) => {
+                assert_eq!(e, "HTTPS URL with IP address");
+                assert_eq!(download.attempts, 1);
+            }
+            _ => panic!("HTTPS download from IP address isn't rejected!"),
+        }
 
-        let (filename, content_type, data) = super::image_from_base64_data(&mut uprq).unwrap();
-        assert_eq!(data, b"TEST JPEG DATA".to_vec());
-        assert!(filename.starts_with("untitled@") && filename.ends_with(".bin"));
-        assert_eq!(content_type, "application/octet-stream");
+        let settings = local_origin_settings();
+        uprq.url = Some(String::from("http://localhost:8892/image"));
+        assert!(super::image_from_url(&mut uprq, &settings, None).is_ok());
 
-        uprq.filename = Some(String::from("test.jpg"));
+        uprq.url = Some(String::from("http://localhost:8892/redirect-private"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, Some(download))) => {
+                assert_eq!(e, "address 127.0.0.1 is not public");
+                assert_eq!(download.url, "http://127.0.0.1:1/image");
+                assert_eq!(download.attempts, 2);
+            }
+            _ => panic!("redirect to a private address isn't blocked!"),
+        }
 
-        let (filename, content_type, data) = super::image_from_base64_data(&mut uprq).unwrap();
-        assert_eq!(data, b"TEST JPEG DATA".to_vec());
-        assert_eq!(filename, "test.jpg");
-        assert_eq!(content_type, "application/octet-stream");
+        let mut settings = local_origin_settings();
+        settings.download.deny_hosts = vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+        uprq.url = Some(String::from("http://localhost:8892/image"));
+        match super::image_from_url(&mut uprq, &settings, None) {
+            Err((e, _)) => assert!(e.ends_with("is denied"), "{}", e),
+            _ => panic!("download from the denied host isn't blocked!"),
+        }
 
-        uprq.content_type = Some(String::from("image/jpeg"));
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+    }
 
-        let (filename, content_type, data) = super::image_from_base64_data(&mut uprq).unwrap();
-        assert_eq!(data, b"TEST JPEG DATA".to_vec());
-        assert_eq!(filename, "test.jpg");
-        assert_eq!(content_type, "image/jpeg");
+    #[test]
+    fn test_handle_multipart_images_post() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-multipart-ghdfhfgh4");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        let http_rq = mock::multipart_formdata_request();
+
+        super::handle_multipart_images_post(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        );
+
+        let mut dir_list = std::fs::read_dir(&tmp_path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(dir_list.len(), 2);
+        dir_list[..].sort();
+        assert_eq!(dir_list[0].to_str(), Some("file-from-name.png"));
+        assert_eq!(dir_list[1].to_str(), Some("sample.jpg"));
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
     #[test]
-    fn test_image_from_url() {
-        let mut uprq = super::ImageUploadRequest {
-            content_type: None,
-            data: None,
-            filename: None,
-            url: None,
+    fn handle_json_images_post() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-json-qwere234erfvdf");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        let (join_handle, srv_tx) = mock::test_http_server(8889);
+        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
+
+        let http_rq = mock::json_request(8889);
+
+        let (reader, _) = super::handle_json_images_post(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &local_origin_settings(),
+        )
+        .data
+        .into_reader_and_size();
+        let results: Vec<super::ImageUploadResult> = serde_json::from_reader(reader).unwrap();
+
+        assert_eq!(results[0].success, false);
+        assert_eq!(results[1].success, true);
+        assert_eq!(results[2].success, false);
+        assert_eq!(results[3].success, true);
+
+        let mut dir_list = std::fs::read_dir(&tmp_path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(dir_list.len(), 2);
+        dir_list[..].sort();
+        assert_eq!(dir_list[0].to_str(), Some("image.jpg"));
+        assert_eq!(dir_list[1].to_str(), Some("valid_base64.bin"));
+
+        for i in 0..dir_list.len() {
+            let mut buffer = String::new();
+            tmp_path.push(dir_list[i].to_str().unwrap());
+            let mut f = std::fs::File::open(&tmp_path).unwrap();
+            f.read_to_string(&mut buffer).unwrap();
+            assert_eq!(
+                buffer,
+                "TEST JPEG DATA",
+                "filename = {}",
+                dir_list[i].to_str().unwrap()
+            );
+            tmp_path.pop();
+        }
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_handle_json_images_post_concurrency() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-json-concurrency-k3h8dw");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        let (join_handle, srv_tx) = mock::test_http_server(8893);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        let post = |body: &str, settings: &Settings| -> Vec<super::ImageUploadResult> {
+            let http_rq = rouille::Request::fake_http(
+                "POST",
+                "/images",
+                vec![(
+                    String::from("Content-Type"),
+                    String::from("application/json"),
+                )],
+                body.replace("@port@", "8893").into_bytes(),
+            );
+            let (reader, _) =
+                super::handle_json_images_post(&http_rq, &tmp_path.to_string_lossy(), settings)
+                    .data
+                    .into_reader_and_size();
+            serde_json::from_reader(reader).unwrap()
         };
 
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "image URL not specified"),
-            _ => panic!("url == None isn't an image!"),
+        let body = r#"[
+            { "filename": "slow-1", "url": "http://localhost:@port@/slow" },
+            { "filename": "slow-2", "url": "http://localhost:@port@/slow" },
+            { "filename": "base64", "data": "VEVTVCBKUEVHIERBVEE=", "content_type": "image/jpeg" },
+            { "filename": "slow-3", "url": "http://localhost:@port@/slow" },
+            { "filename": "slow-4", "url": "http://localhost:@port@/slow" }
+        ]"#;
+
+        let settings = local_origin_settings();
+        let started = std::time::Instant::now();
+        let results = post(body, &settings);
+        assert!(started.elapsed() < std::time::Duration::from_millis(3000));
+        let filenames: Vec<&str> = results.iter().map(|x| &x.filename[..]).collect();
+        assert_eq!(
+            filenames,
+            [
+                "slow-1.jpg",
+                "slow-2.jpg",
+                "base64.jpg",
+                "slow-3.jpg",
+                "slow-4.jpg"
+            ]
+        );
+        assert!(results.iter().all(|x| x.success));
+
+        let mut settings = local_origin_settings();
+        settings.download.concurrency = 1;
+        settings.download.batch_timeout = std::time::Duration::from_millis(1500);
+        let results = post(body, &settings);
+        let reasons: Vec<&str> = results.iter().map(|x| &x.reason[..]).collect();
+        assert_eq!(
+            reasons,
+            [
+                "ok",
+                "download deadline exceeded",
+                "ok",
+                "download deadline exceeded",
+                "download deadline exceeded"
+            ]
+        );
+
+        let body = r#"[{ "filename": "slow-body", "url": "http://localhost:@port@/slow-body" }]"#;
+        settings.download.batch_timeout = std::time::Duration::from_millis(500);
+        let results = post(body, &settings);
+        assert_eq!(results[0].reason, "download timed out");
+        let image_path = tmp_path.join("slow-body.jpg");
+        assert!(!image_path.exists());
+
+        // The stored image with the same name is kept if the download times out.
+        std::fs::write(&image_path, b"STORED JPEG DATA").unwrap();
+        let results = post(body, &settings);
+        assert_eq!(results[0].reason, "download timed out");
+        assert_eq!(std::fs::read(&image_path).unwrap(), b"STORED JPEG DATA");
+        assert!(std::fs::read_dir(&tmp_path).unwrap().all(|x| !x
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+
+        while std::fs::read_dir(&tmp_path)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .any(|x| super::thumbnail::status(&x.path()) == super::thumbnail::Status::Pending)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
 
-        uprq.url = Some(String::from("https://ya.ru"));
+    #[test]
+    fn test_upload_job() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-upload-job-r5n2cz");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let (join_handle, srv_tx) = mock::test_http_server(8894);
+        std::thread::sleep(std::time::Duration::from_secs(2));
+
+        let body = r#"[
+            { "filename": "slow", "url": "http://localhost:8894/slow" },
+            { "filename": "base64", "data": "VEVTVCBKUEVHIERBVEE=", "content_type": "image/jpeg" },
+            { "filename": "empty" }
+        ]"#;
+        let http_rq = rouille::Request::fake_http(
+            "POST",
+            "/images?async=true",
+            vec![(
+                String::from("Content-Type"),
+                String::from("application/json"),
+            )],
+            body.as_bytes().to_vec(),
+        );
+        let response = super::route(&http_rq, &file_path, &local_origin_settings());
+        assert_eq!(response.status_code, 202);
+        let location = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "Location")
+            .map(|(_, v)| v.to_string())
+            .unwrap();
+        let (reader, _) = response.data.into_reader_and_size();
+        let job: crate::jobs::Job = serde_json::from_reader(reader).unwrap();
+        assert_eq!(location, format!("/jobs/{}", job.id));
+        assert_eq!(job.state, crate::jobs::State::Running);
+        assert_eq!(job.items.len(), 3);
+
+        let get = || {
+            let http_rq = rouille::Request::fake_http("GET", location.clone(), vec![], vec![]);
+            let response = super::route(&http_rq, &file_path, &Settings::default());
+            assert_eq!(response.status_code, 200);
+            let (reader, _) = response.data.into_reader_and_size();
+            serde_json::from_reader::<_, crate::jobs::Job>(reader).unwrap()
+        };
+        let mut job = get();
+        for _ in 0..50 {
+            if job.state == crate::jobs::State::Done {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            job = get();
+        }
+        assert_eq!(job.state, crate::jobs::State::Done);
+        assert_eq!(job.completed, 3);
+
+        let results: Vec<_> = job
+            .items
+            .iter()
+            .map(|x| x.result.as_ref().unwrap())
+            .collect();
+        assert_eq!(results[0].filename, "slow.jpg");
+        assert!(results[0].success);
+        assert_eq!(results[1].filename, "base64.jpg");
+        assert!(results[1].success);
+        assert_eq!(results[2].reason, "nor url or data are specified");
+        assert!(job.items[0].thumbnail.is_some());
+        assert_eq!(job.items[2].thumbnail, None);
+
+        let response = super::handle_job_get(&file_path, "0123abc");
+        assert_eq!(response.status_code, 404);
+        let response = super::handle_job_get(&file_path, "..");
+        assert_eq!(response.status_code, 404);
+
+        let http_rq = rouille::Request::fake_http(
+            "POST",
+            "/images?async=true",
+            vec![(String::from("Content-Type"), String::from("image/jpeg"))],
+            b"TEST JPEG DATA".to_vec(),
+        );
+        let response = super::route(&http_rq, &file_path, &Settings::default());
+        assert_eq!(response.status_code, 400);
 
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "not an image"),
-            _ => panic!("url pointing to html page isn't an image!"),
+        let no_jobs = Settings {
+            max_jobs: 0,
+            ..Settings::default()
+        };
+        let http_rq = rouille::Request::fake_http(
+            "POST",
+            "/images?async=true",
+            vec![(
+                String::from("Content-Type"),
+                String::from("application/json"),
+            )],
+            body.as_bytes().to_vec(),
+        );
+        let response = super::route(&http_rq, &file_path, &no_jobs);
+        assert_eq!(response.status_code, 503);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Retry-After" && v == "1"));
+        let response =
+            super::start_upload_job(Vec::new(), &file_path, &no_jobs, Collision::Overwrite);
+        assert_eq!(response.status_code, 503);
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+
+        while std::fs::read_dir(&tmp_path)
+            .unwrap()
+            .filter_map(|x| x.ok())
+            .any(|x| super::thumbnail::status(&x.path()) == super::thumbnail::Status::Pending)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
 
-        uprq.url = Some(String::from("https://placehold.co/321/png"));
+    #[test]
+    fn test_route_images_post_by_content_type() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-route-cvbcvbngfh");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
 
-        let (filename, content_type, data) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "png.png");
-        assert_eq!(content_type, "image/png");
-        let img = image::png::PNGDecoder::new(&data[..]).unwrap();
-        assert_eq!(img.dimensions(), (321, 321));
+        let (join_handle, srv_tx) = mock::test_http_server(8890);
+        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
 
-        uprq.url = Some(String::from("https://via.placeholder.com/123.jpg"));
+        let http_rq = mock::json_request(8890);
+
+        let response = super::route_images_post_by_content_type(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &local_origin_settings(),
+        );
+        assert_eq!(response.status_code, 200);
+
+        srv_tx.send("stop").unwrap();
+        join_handle.join().unwrap();
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let http_rq = mock::multipart_formdata_request();
+
+        let response = super::route_images_post_by_content_type(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        );
+        assert_eq!(response.status_code, 200);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let http_rq = mock::plaintext_request();
+
+        let response = super::route_images_post_by_content_type(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        );
+        assert_eq!(response.status_code, 406);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let http_rq = mock::unknown_content_request();
+
+        let response = super::route_images_post_by_content_type(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        );
+        assert_eq!(response.status_code, 400);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_handle_raw_image_upload() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-raw-upload-pl9d8s");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+        let settings = Settings::default();
+
+        let upload = |method: &str, content_type: &str, filename: &str| {
+            let http_rq = mock::raw_image_request(method, content_type, b"RAW IMAGE DATA");
+            let response =
+                super::handle_raw_image_upload(&http_rq, &file_path, &settings, filename);
+            let status_code = response.status_code;
+            let (reader, _) = response.data.into_reader_and_size();
+            let result: Result<super::ImageUploadResult, _> = serde_json::from_reader(reader);
+            (status_code, result)
+        };
+
+        let (status_code, result) = upload("PUT", "image/jpeg", "raw");
+        assert_eq!(status_code, 200);
+        let result = result.unwrap();
+        assert_eq!(result.filename, "raw.jpg");
+        assert_eq!(result.content_type, "image/jpeg");
+        assert_eq!(result.size, 14);
+        assert!(result.success);
+
+        let mut buffer = String::new();
+        tmp_path.push("raw.jpg");
+        std::fs::File::open(&tmp_path)
+            .unwrap()
+            .read_to_string(&mut buffer)
+            .unwrap();
+        tmp_path.pop();
+        assert_eq!(buffer, "RAW IMAGE DATA");
+
+        let (status_code, result) = upload("POST", "image/png; charset=binary", "");
+        assert_eq!(status_code, 200);
+        let result = result.unwrap();
+        assert!(result.filename.starts_with("untitled@") && result.filename.ends_with(".png"));
+        assert!(result.success);
+
+        let (status_code, _) = upload("PUT", "text/plain", "raw.txt");
+        assert_eq!(status_code, 406);
+
+        let (status_code, result) = upload("PUT", "image/png", "..");
+        assert_eq!(status_code, 200);
+        let result = result.unwrap();
+        assert!(result.filename.starts_with("untitled@") && result.filename.ends_with(".png"));
+        assert_eq!(result.original_filename, Some(String::from("..")));
+        assert_eq!(
+            result.warnings,
+            [
+                "filename sanitized: leading and trailing dots and spaces removed",
+                "image format not recognized"
+            ]
+        );
+
+        let http_rq = mock::raw_image_request("POST", "image/gif", b"GIF");
+        let response = super::route_images_post_by_content_type(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &Settings::default(),
+        );
+        assert_eq!(response.status_code, 200);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_upload_collision() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-upload-collision-x4nb8c");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let settings = Settings {
+            collision: crate::settings::Collision::Reject,
+            ..Settings::default()
+        };
+        let put = |url: &str, body: &[u8]| {
+            let headers = vec![(String::from("Content-Type"), String::from("image/png"))];
+            let http_rq = rouille::Request::fake_http("PUT", url, headers, body.to_vec());
+            let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "a.png");
+            let status_code = response.status_code;
+            let (reader, _) = response.data.into_reader_and_size();
+            let result: super::ImageUploadResult =
+                serde_json::from_reader(reader).unwrap_or_default();
+            (status_code, result)
+        };
+        let read =
+            |filename: &str| std::fs::read_to_string(tmp_path.join(filename)).unwrap_or_default();
+
+        let (status_code, result) = put("/images/a.png", b"FIRST");
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "a.png");
+
+        let (status_code, result) = put("/images/a.png", b"SECOND");
+        assert_eq!(status_code, 409);
+        assert_eq!(result.reason, "file already exists");
+        assert!(!result.success);
+        assert_eq!(read("a.png"), "FIRST");
+
+        let (status_code, result) = put("/images/a.png?on_collision=rename", b"THIRD");
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "a-1.png");
+        assert_eq!(result.original_filename, Some(String::from("a.png")));
+        assert_eq!(
+            result.warnings,
+            [
+                "image format not recognized",
+                "filename changed to avoid collision"
+            ]
+        );
+        assert_eq!(read("a-1.png"), "THIRD");
+
+        let (_, result) = put("/images/a.png?on_collision=rename", b"FOURTH");
+        assert_eq!(result.filename, "a-2.png");
+
+        let (status_code, _) = put("/images/a.png?on_collision=overwrite", b"FIFTH");
+        assert_eq!(status_code, 200);
+        assert_eq!(read("a.png"), "FIFTH");
+
+        let (status_code, _) = put("/images/a.png?on_collision=append", b"SIXTH");
+        assert_eq!(status_code, 400);
+
+        let body = r#"[
+            { "filename": "a.png", "data": "SlNPTg==" },
+            { "filename": "a.png", "data": "SlNPTg==", "on_collision": "rename" },
+            { "filename": "a.png", "data": "SlNPTg==", "on_collision": "overwrite" }
+        ]"#;
+        let headers = vec![(
+            String::from("Content-Type"),
+            String::from("application/json"),
+        )];
+        let http_rq = rouille::Request::fake_http("POST", "/images", headers, body.into());
+        let response = super::handle_json_images_post(&http_rq, &file_path, &settings);
+        let (reader, _) = response.data.into_reader_and_size();
+        let results: Vec<super::ImageUploadResult> = serde_json::from_reader(reader).unwrap();
+        assert_eq!(results[0].reason, "file already exists");
+        assert_eq!(results[1].filename, "a-3.png");
+        assert_eq!(results[2].filename, "a.png");
+        assert!(results[2].success);
+        assert_eq!(read("a.png"), "JSON");
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_decode_check() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-decode-check-m2c7wt");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+        let truncated = &png[..png.len() - 20];
+
+        let upload = |check: DecodeCheck, filename: &str, body: &[u8]| {
+            let settings = Settings {
+                decode_check: check,
+                ..Settings::default()
+            };
+            let http_rq = mock::raw_image_request("PUT", "image/png", body);
+            let response =
+                super::handle_raw_image_upload(&http_rq, &file_path, &settings, filename);
+            let status_code = response.status_code;
+            let (reader, _) = response.data.into_reader_and_size();
+            let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+            (status_code, result)
+        };
+
+        let (status_code, result) = upload(DecodeCheck::Full, "valid.png", &png);
+        assert_eq!(status_code, 200);
+        assert!(result.success);
+        assert_eq!(std::fs::read(tmp_path.join("valid.png")).unwrap(), png);
+
+        let (status_code, _) = upload(DecodeCheck::Headers, "truncated.png", truncated);
+        assert_eq!(status_code, 200);
+
+        let (status_code, result) = upload(DecodeCheck::Full, "valid.png", truncated);
+        assert_eq!(status_code, 422);
+        assert!(!result.success);
+        assert!(result.reason.starts_with("invalid image data: "));
+        assert_eq!(std::fs::read(tmp_path.join("valid.png")).unwrap(), png);
+
+        let (status_code, result) = upload(DecodeCheck::Headers, "page.png", b"<html></html>");
+        assert_eq!(status_code, 422);
+        assert!(!result.success);
+        assert!(!tmp_path.join("page.png").exists());
+
+        let files: Vec<_> = std::fs::read_dir(&tmp_path).unwrap().collect();
+        assert_eq!(files.len(), 2);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-size-limits-h5r1na");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let settings = Settings {
+            max_image_size: 16,
+            max_batch_items: 1,
+            max_body_size: 200,
+            ..Settings::default()
+        };
+        let parse = |response: rouille::Response| -> Vec<super::ImageUploadResult> {
+            let (reader, _) = response.data.into_reader_and_size();
+            serde_json::from_reader(reader).unwrap()
+        };
+
+        let http_rq = mock::raw_image_request("PUT", "image/png", b"EXACTLY 16 BYTES");
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "a.png");
+        assert_eq!(response.status_code, 200);
+
+        let http_rq = mock::raw_image_request("PUT", "image/png", b"SEVENTEEN BYTES!!");
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "b.png");
+        assert_eq!(response.status_code, 413);
+        assert!(!tmp_path.join("b.png").exists());
+
+        // The stored image is kept if the replacing upload fails.
+        let http_rq = mock::raw_image_request("PUT", "image/png", b"SEVENTEEN BYTES!!");
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "a.png");
+        assert_eq!(response.status_code, 413);
+        assert_eq!(
+            std::fs::read(tmp_path.join("a.png")).unwrap(),
+            b"EXACTLY 16 BYTES"
+        );
+
+        let http_rq = mock::raw_image_request("PUT", "image/png", &[b'x'; 201]);
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "c.png");
+        assert_eq!(response.status_code, 413);
+
+        let json = |body: &str| {
+            let headers = vec![(
+                String::from("Content-Type"),
+                String::from("application/json"),
+            )];
+            let http_rq = rouille::Request::fake_http("POST", "/images", headers, body.into());
+            super::handle_json_images_post(&http_rq, &file_path, &settings)
+        };
+
+        let response = json(r#"[{ "filename": "d.png", "data": "U0VWRU5URUVOIEJZVEVTISE=" }]"#);
+        assert_eq!(response.status_code, 200);
+        let results = parse(response);
+        assert_eq!(results[0].reason, "image too large");
+        assert!(!results[0].success);
+
+        let response = json(r#"[{ "data": "SlNPTg==" }, { "data": "SlNPTg==" }]"#);
+        assert_eq!(response.status_code, 413);
+
+        let response = json(&format!(r#"[{{ "data": "{}" }}]"#, "A".repeat(200)));
+        assert_eq!(response.status_code, 413);
+
+        let http_rq = mock::multipart_formdata_request();
+        let response = super::handle_multipart_images_post(&http_rq, &file_path, &settings);
+        assert_eq!(response.status_code, 413);
+
+        // Chunked bodies without Content-Length are limited as they are read.
+        let chunked = |http_rq: rouille::Request| {
+            let headers = http_rq
+                .headers()
+                .filter(|(k, _)| k != &"Content-Length")
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let mut body = Vec::new();
+            http_rq.data().unwrap().read_to_end(&mut body).unwrap();
+            rouille::Request::fake_http(http_rq.method(), http_rq.raw_url(), headers, body)
+        };
+        let large_images = Settings {
+            max_image_size: 1024,
+            ..settings.clone()
+        };
+
+        let http_rq = chunked(mock::raw_image_request("PUT", "image/png", &[b'x'; 201]));
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &large_images, "a.png");
+        assert_eq!(response.status_code, 413);
+        assert_eq!(
+            std::fs::read(tmp_path.join("a.png")).unwrap(),
+            b"EXACTLY 16 BYTES"
+        );
+
+        let http_rq = chunked(mock::multipart_formdata_request());
+        let response = super::handle_multipart_images_post(&http_rq, &file_path, &settings);
+        assert_eq!(response.status_code, 413);
+
+        let body = format!(
+            "--b\r\n\
+             Content-Disposition: form-data; name=\"on_collision\"\r\n\
+             \r\n\
+             {}\r\n\
+             --b--",
+            "x".repeat(100)
+        );
+        let http_rq = rouille::Request::fake_http(
+            "POST",
+            "/images",
+            vec![(
+                String::from("Content-Type"),
+                String::from("multipart/form-data; boundary=b"),
+            )],
+            body.into_bytes(),
+        );
+        let response = super::handle_multipart_images_post(&http_rq, &file_path, &settings);
+        assert_eq!(response.status_code, 200);
+        let results = parse(response);
+        assert_eq!(
+            results[0].reason,
+            format!("invalid collision policy \"{}\"", "x".repeat(64))
+        );
+
+        let settings = Settings {
+            max_body_size: 1024,
+            ..settings.clone()
+        };
+        let http_rq = mock::multipart_formdata_request();
+        let response = super::handle_multipart_images_post(&http_rq, &file_path, &settings);
+        assert_eq!(response.status_code, 200);
+        let results = parse(response);
+        assert!(results[0].success);
+        assert_eq!(results[1].reason, "too many images in request");
+        assert!(!results[1].success);
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_pixel_limits() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-pixel-limits-t6s0jd");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(200, 100)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+
+        let upload = |limits: PixelLimits, check: DecodeCheck| {
+            let settings = Settings {
+                pixel_limits: limits,
+                decode_check: check,
+                ..Settings::default()
+            };
+            let http_rq = mock::raw_image_request("PUT", "image/png", &png);
+            let response =
+                super::handle_raw_image_upload(&http_rq, &file_path, &settings, "big.png");
+            let status_code = response.status_code;
+            let (reader, _) = response.data.into_reader_and_size();
+            let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+            (status_code, result)
+        };
+
+        let limits = PixelLimits {
+            max_width: 100,
+            ..PixelLimits::default()
+        };
+        let (status_code, result) = upload(limits, DecodeCheck::Off);
+        assert_eq!(status_code, 413);
+        assert_eq!(
+            result.reason,
+            "image dimensions 200x100 exceed 100x16384 limit"
+        );
+        assert!(!tmp_path.join("big.png").exists());
+
+        let limits = PixelLimits {
+            max_pixels: 10000,
+            ..PixelLimits::default()
+        };
+        let (status_code, result) = upload(limits, DecodeCheck::Full);
+        assert_eq!(status_code, 413);
+        assert_eq!(
+            result.reason,
+            "image dimensions 200x100 exceed 10000 pixels limit"
+        );
+
+        let (status_code, _) = upload(PixelLimits::default(), DecodeCheck::Full);
+        assert_eq!(status_code, 200);
+
+        let image_path = tmp_path.join("big.png").to_string_lossy().to_string();
+        assert!(!crate::thumbnail::make(&image_path, &limits));
+        assert!(crate::thumbnail::make(&image_path, &PixelLimits::default()));
+        while crate::thumbnail::status(&tmp_path.join("big.png"))
+            == crate::thumbnail::Status::Pending
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let thumbnails: Vec<_> = std::fs::read_dir(tmp_path.join("thumbnails"))
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect();
+        assert_eq!(thumbnails, ["big.png"]);
+
+        // WebP frame header of the size over the limit is rejected before decoding.
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\x50\x01\0\x9d\x01\x2a".to_vec();
+        webp.extend_from_slice(&[0xff, 0x3f, 0xff, 0x3f]);
+        webp.resize(1024, 0);
+        let settings = Settings {
+            decode_check: DecodeCheck::Full,
+            ..Settings::default()
+        };
+        let http_rq = mock::raw_image_request("PUT", "image/webp", &webp);
+        let response = super::handle_raw_image_upload(&http_rq, &file_path, &settings, "bomb.webp");
+        assert_eq!(response.status_code, 413);
+        let (reader, _) = response.data.into_reader_and_size();
+        let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+        assert_eq!(
+            result.reason,
+            "image dimensions 16383x16383 exceed 50000000 pixels limit"
+        );
+        assert!(!tmp_path.join("bomb.webp").exists());
+
+        let image_path = tmp_path.join("bomb.webp");
+        std::fs::write(&image_path, &webp).unwrap();
+        let info = crate::image_info::read_file(&std::fs::File::open(&image_path).unwrap());
+        assert_eq!(info.map(|x| (x.width, x.height)).ok(), Some((16383, 16383)));
+        assert!(!crate::thumbnail::make(
+            &image_path.to_string_lossy(),
+            &PixelLimits::default()
+        ));
 
-        let (filename, content_type, data) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "123.jpg");
-        assert_eq!(content_type, "image/jpeg");
-        let img = image::jpeg::JPEGDecoder::new(&data[..]).unwrap();
-        assert_eq!(img.dimensions(), (123, 123));
+        std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
     #[test]
-    fn test_image_from_url_self_hosted() {
-        let mut uprq = super::ImageUploadRequest {
-            content_type: None,
-            data: None,
-            filename: None,
-            url: None,
+    fn test_image_type_check() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-image-type-check-q8v3ze");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+
+        let upload = |settings: &Settings, content_type: &str, filename: &str, body: &[u8]| {
+            let http_rq = mock::raw_image_request("PUT", content_type, body);
+            let response = super::handle_raw_image_upload(&http_rq, &file_path, settings, filename);
+            let status_code = response.status_code;
+            let (reader, _) = response.data.into_reader_and_size();
+            let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+            (status_code, result)
         };
 
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "image URL not specified"),
-            _ => panic!("url == None isn't an image!"),
+        let flag = Settings::default();
+        let (status_code, result) = upload(&flag, "image/jpeg", "photo.jpg", &png);
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "photo.png");
+        assert_eq!(result.content_type, "image/png");
+        assert_eq!(
+            result.warnings,
+            ["declared content type image/jpeg doesn't match detected image/png"]
+        );
+        assert_eq!(std::fs::read(tmp_path.join("photo.png")).unwrap(), png);
+
+        let (status_code, result) = upload(&flag, "image/png", "page.jpg", b"<html></html>");
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "page.jpg");
+        assert_eq!(result.warnings, ["image format not recognized"]);
+
+        // Data of unknown format is never stored with the extension of a type not allowed.
+        let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>";
+        let (status_code, result) = upload(&flag, "image/png", "evil.svg", svg);
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "evil.bin");
+        assert_eq!(result.content_type, "application/octet-stream");
+        assert_eq!(
+            result.warnings,
+            [
+                "image format not recognized",
+                "filename extension changed to .bin"
+            ]
+        );
+        assert!(!tmp_path.join("evil.svg").exists());
+        let http_rq = rouille::Request::fake_http("GET", "/images/evil.bin", vec![], vec![]);
+        let response = super::handle_image_get(&http_rq, &file_path, &flag, "evil.bin");
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "application/octet-stream"));
+
+        let reject = Settings {
+            type_mismatch: TypeMismatch::Reject,
+            ..Settings::default()
+        };
+        let (status_code, result) = upload(&reject, "image/png", "plain", &png);
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "plain.png");
+        assert!(result.warnings.is_empty());
+
+        let (status_code, result) = upload(&reject, "image/jpeg", "error.jpg", b"<html></html>");
+        assert_eq!(status_code, 415);
+        assert_eq!(result.reason, "image format not recognized");
+        assert!(!tmp_path.join("error.jpg").exists());
+
+        let (status_code, result) = upload(&reject, "image/gif", "mismatch.gif", &png);
+        assert_eq!(status_code, 415);
+        assert!(!result.success);
+        assert!(!tmp_path.join("mismatch.gif").exists());
+
+        let (status_code, result) = upload(&flag, "image/svg+xml", "xss.svg", b"<svg/>");
+        assert_eq!(status_code, 415);
+        assert_eq!(result.reason, "content type image/svg+xml is not allowed");
+
+        let (status_code, result) = upload(&flag, "image/*", "any", &png);
+        assert_eq!(status_code, 415);
+        assert_eq!(result.reason, "content type image/* is not allowed");
+
+        let mut bmp = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut bmp, image::ImageOutputFormat::BMP)
+            .unwrap();
+        let (status_code, result) = upload(&flag, "image/png", "bitmap.png", &bmp);
+        assert_eq!(status_code, 415);
+        assert_eq!(
+            result.reason,
+            "detected content type image/bmp is not allowed"
+        );
+
+        let bmp_allowed = Settings {
+            allowed_types: vec![String::from("image/bmp")],
+            ..Settings::default()
+        };
+        let (status_code, result) = upload(&bmp_allowed, "image/bmp", "bitmap", &bmp);
+        assert_eq!(status_code, 200);
+        assert_eq!(result.filename, "bitmap.bmp");
+
+        for entry in std::fs::read_dir(&tmp_path).unwrap() {
+            let image_path = entry.unwrap().path();
+            while crate::thumbnail::status(&image_path) == crate::thumbnail::Status::Pending {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
         }
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
 
-        uprq.url = Some(String::from(
-            "http://qerqcqwer3454fdsgdfgsdfg/not-exist-server",
-        ));
+    #[test]
+    fn test_handle_images_json_get() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-get-ckenvthslc");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
 
-        if let Ok(_) = super::image_from_url(&mut uprq) {
-            panic!("url pointing to not existent server isn't an image!")
-        }
+        let get = |url: &str| {
+            let http_rq = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            super::handle_images_json_get(&http_rq, &file_path)
+        };
+        let page = |response: rouille::Response| {
+            let (reader, _) = response.data.into_reader_and_size();
+            let page: super::ImageListPage = serde_json::from_reader(reader).unwrap();
+            let filenames: Vec<_> = page.images.iter().map(|x| x.filename.clone()).collect();
+            (page, filenames)
+        };
 
-        let (join_handle, srv_tx) = mock::test_http_server(8888);
-        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
+        let response = get("/images");
+        assert_eq!(response.status_code, 200);
+        let (result, _) = page(response);
+        assert_eq!(result.images.len(), 0);
+        assert_eq!(result.next, None);
 
-        uprq.url = Some(String::from("http://localhost:8888/not-exist-url"));
+        {
+            tmp_path.push("test.jpg");
+            std::fs::File::create(&tmp_path).unwrap();
+            tmp_path.pop();
 
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "not an image"),
-            _ => panic!("url pointing to invalid resource isn't an image!"),
-        }
+            tmp_path.push("test 1.png");
+            image::DynamicImage::new_rgb8(64, 48)
+                .save(&tmp_path)
+                .unwrap();
+            tmp_path.pop();
 
-        uprq.url = Some(String::from("http://localhost:8888/unknown-content-type"));
+            tmp_path.push(".test.png.tmp");
+            std::fs::File::create(&tmp_path).unwrap();
+            tmp_path.pop();
 
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "not an image"),
-            _ => panic!("url pointing to a resource with unknown Content-Type isn't an image!"),
+            tmp_path.push("thumbnails");
+            std::fs::create_dir_all(&tmp_path).unwrap();
+            tmp_path.pop();
         }
 
-        uprq.content_type = Some(String::from("image/jpeg"));
-
-        let (filename, content_type, data) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "unknown-content-type.jpg");
-        assert_eq!(content_type, "image/jpeg");
-        assert_eq!(data, b"Hello!");
-
-        uprq.url = Some(String::from("http://localhost:8888/"));
-
-        match super::image_from_url(&mut uprq) {
-            Err(e) => assert_eq!(e, "not an image"),
-            _ => panic!("url pointing to html or text resource isn't an image!"),
+        let response = get("/images");
+        assert_eq!(response.status_code, 200);
+        let (result, filenames) = page(response);
+        assert_eq!(filenames, ["test 1.png", "test.jpg"]);
+        let results = result.images;
+
+        assert_eq!(results[0].format, Some(String::from("png")));
+        assert_eq!((results[0].width, results[0].height), (Some(64), Some(48)));
+        assert_eq!(results[0].content_type, "image/png");
+        assert_eq!(results[0].thumbnail_url, "/images/test%201.png/thumbnail");
+        assert_eq!(results[0].thumbnail, "missing");
+        assert!(results[0].size > 0);
+        assert!(chrono::DateTime::parse_from_rfc3339(&results[0].modified).is_ok());
+
+        assert_eq!(results[1].size, 0);
+        assert_eq!(results[1].format, None);
+        assert_eq!(results[1].width, None);
+        assert_eq!(results[1].content_type, "image/jpeg");
+
+        let modified = std::fs::metadata(tmp_path.join("test.jpg"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            chrono::DateTime::parse_from_rfc3339(&results[1].modified).unwrap(),
+            chrono::DateTime::<chrono::Utc>::from(modified)
+        );
+
+        // Test for thumbnail state and files with UTF-8 incompatible names.
+        std::fs::File::create(tmp_path.join("thumbnails").join("test 1.png")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let filename = std::ffi::OsStr::from_bytes(b"test\xff.png");
+            std::fs::File::create(tmp_path.join(filename)).unwrap();
         }
 
-        uprq.content_type = None;
-        uprq.url = Some(String::from("http://localhost:8888/image"));
-
-        let (filename, content_type, data) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "image.jpg");
-        assert_eq!(content_type, "image/jpeg");
-        assert_eq!(data, b"TEST JPEG DATA");
-
-        uprq.filename = Some(String::from("testfile"));
-
-        let (filename, _, _) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "testfile.jpg");
-
-        uprq.filename = Some(String::from("testfile.jpeg"));
+        let (result, filenames) = page(get("/images"));
+        assert_eq!(filenames, ["test 1.png", "test.jpg"]);
+        assert_eq!(result.images[0].thumbnail, "ready");
+        assert_eq!(result.images[1].thumbnail, "missing");
+
+        let (result, filenames) = page(get("/images?limit=1&sort=size&order=desc"));
+        assert_eq!(filenames, ["test 1.png"]);
+        let cursor = result.next.unwrap();
+        let (result, filenames) = page(get(&format!(
+            "/images?limit=1&sort=size&order=desc&cursor={}",
+            cursor
+        )));
+        assert_eq!(filenames, ["test.jpg"]);
+        assert_eq!(result.next, None);
+
+        let (_, filenames) = page(get("/images?content_type=image/jpeg"));
+        assert_eq!(filenames, ["test.jpg"]);
+        let (_, filenames) = page(get("/images?glob=*.png&min_size=1"));
+        assert_eq!(filenames, ["test 1.png"]);
+        let (_, filenames) = page(get("/images?prefix=test.&modified_after=2019-01-01"));
+        assert_eq!(filenames, ["test.jpg"]);
+        let (_, filenames) = page(get("/images?modified_before=2019-01-01T00:00:00Z"));
+        assert!(filenames.is_empty());
+
+        assert_eq!(get("/images?limit=0").status_code, 400);
+        assert_eq!(get("/images?sort=color").status_code, 400);
+        assert_eq!(
+            get(&format!("/images?limit=1&cursor={}", cursor)).status_code,
+            400
+        );
+
+        // WebP dimensions are read from the headers, the frame isn't decoded.
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\x50\x01\0\x9d\x01\x2a".to_vec();
+        webp.extend_from_slice(&[0xff, 0x3f, 0xff, 0x3f]);
+        webp.resize(1024, 0);
+        std::fs::write(tmp_path.join("webp.webp"), &webp).unwrap();
+        let (result, filenames) = page(get("/images?prefix=webp"));
+        assert_eq!(filenames, ["webp.webp"]);
+        assert_eq!(result.images[0].format, Some(String::from("webp")));
+        assert_eq!(
+            (result.images[0].width, result.images[0].height),
+            (Some(16383), Some(16383))
+        );
 
-        let (filename, _, _) = super::image_from_url(&mut uprq).unwrap();
-        assert_eq!(filename, "testfile.jpeg");
+        std::fs::remove_dir_all(&tmp_path).unwrap();
 
-        srv_tx.send("stop").unwrap();
-        join_handle.join().unwrap();
+        let response = get("/images");
+        assert_eq!(response.status_code, 500);
+        let (reader, _) = response.data.into_reader_and_size();
+        let result: super::ErrorResult = serde_json::from_reader(reader).unwrap();
+        assert!(!result.success);
+        assert_eq!(result.reason, "I/O error");
     }
 
     #[test]
-    fn test_handle_multipart_images_post() {
+    fn test_handle_image_get() {
         let mut tmp_path = std::env::temp_dir();
-        tmp_path.push("test-multipart-ghdfhfgh4");
+        tmp_path.push("test-image-get-rtyvbn6");
         let _ = std::fs::remove_dir_all(&tmp_path);
         std::fs::create_dir_all(&tmp_path).unwrap();
 
-        let http_rq = mock::multipart_formdata_request();
+        tmp_path.push("test.jpg");
+        crate::file_utils::write_image_data(&b"TEST JPEG DATA"[..], &tmp_path).unwrap();
+        tmp_path.pop();
+        tmp_path.push("thumbnails");
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        tmp_path.pop();
 
-        super::handle_multipart_images_post(&http_rq, &tmp_path.to_string_lossy());
+        let settings = Settings::default();
+        let http_rq = rouille::Request::fake_http("GET", "/images/test.jpg", vec![], vec![]);
 
-        let mut dir_list = std::fs::read_dir(&tmp_path)
-            .unwrap()
-            .map(|x| x.unwrap().file_name())
-            .collect::<Vec<_>>();
-        assert_eq!(dir_list.len(), 2);
-        dir_list[..].sort();
-        assert_eq!(dir_list[0].to_str(), Some("file-from-name.png"));
-        assert_eq!(dir_list[1].to_str(), Some("sample.jpg"));
+        let response =
+            super::handle_image_get(&http_rq, &tmp_path.to_string_lossy(), &settings, "test.jpg");
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/jpeg"));
+        let (mut reader, size) = response.data.into_reader_and_size();
+        assert_eq!(size, Some(14));
+        let mut buffer = String::new();
+        reader.read_to_string(&mut buffer).unwrap();
+        assert_eq!(buffer, "TEST JPEG DATA");
+
+        let response = super::handle_image_get(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &settings,
+            "missing.jpg",
+        );
+        assert_eq!(response.status_code, 404);
+
+        let response = super::handle_image_get(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &settings,
+            "thumbnails",
+        );
+        assert_eq!(response.status_code, 404);
+
+        let response = super::handle_image_get(
+            &http_rq,
+            &tmp_path.to_string_lossy(),
+            &settings,
+            "../test.jpg",
+        );
+        assert_eq!(response.status_code, 404);
 
         std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
     #[test]
-    fn handle_json_images_post() {
+    fn test_handle_image_get_conditional() {
         let mut tmp_path = std::env::temp_dir();
-        tmp_path.push("test-json-qwere234erfvdf");
+        tmp_path.push("test-image-get-cond-q8w2ez");
         let _ = std::fs::remove_dir_all(&tmp_path);
         std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
 
-        let (join_handle, srv_tx) = mock::test_http_server(8889);
-        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
-
-        let http_rq = mock::json_request(8889);
-
-        let (reader, _) = super::handle_json_images_post(&http_rq, &tmp_path.to_string_lossy())
-            .data
-            .into_reader_and_size();
-        let results: Vec<super::ImageUploadResult> = serde_json::from_reader(reader).unwrap();
+        tmp_path.push("test.jpg");
+        crate::file_utils::write_image_data(&b"TEST JPEG DATA"[..], &tmp_path).unwrap();
+        tmp_path.pop();
 
-        assert_eq!(results[0].success, false);
-        assert_eq!(results[1].success, true);
-        assert_eq!(results[2].success, false);
-        assert_eq!(results[3].success, true);
+        let settings = Settings::default();
+        let get = |headers: Vec<(String, String)>| {
+            let http_rq = rouille::Request::fake_http("GET", "/images/test.jpg", headers, vec![]);
+            super::handle_image_get(&http_rq, &file_path, &settings, "test.jpg")
+        };
+        let header = |response: &rouille::Response, name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.to_string())
+        };
 
-        let mut dir_list = std::fs::read_dir(&tmp_path)
-            .unwrap()
-            .map(|x| x.unwrap().file_name())
-            .collect::<Vec<_>>();
-        assert_eq!(dir_list.len(), 2);
-        dir_list[..].sort();
-        assert_eq!(dir_list[0].to_str(), Some("image.jpg"));
-        assert_eq!(dir_list[1].to_str(), Some("valid_base64.bin"));
+        let response = get(vec![]);
+        assert_eq!(response.status_code, 200);
+        let etag = header(&response, "ETag").unwrap();
+        let last_modified = header(&response, "Last-Modified").unwrap();
+        assert_eq!(
+            header(&response, "Cache-Control"),
+            Some(String::from("public, no-cache"))
+        );
+
+        let response = get(vec![(String::from("If-None-Match"), etag.clone())]);
+        assert_eq!(response.status_code, 304);
+        assert_eq!(header(&response, "ETag"), Some(etag.clone()));
+        let (_, size) = response.data.into_reader_and_size();
+        assert_eq!(size, Some(0));
+
+        let response = get(vec![(
+            String::from("If-None-Match"),
+            format!("\"other\", W/{}", etag),
+        )]);
+        assert_eq!(response.status_code, 304);
+
+        let response = get(vec![(
+            String::from("If-None-Match"),
+            String::from("\"other\""),
+        )]);
+        assert_eq!(response.status_code, 200);
 
-        for i in 0..dir_list.len() {
-            let mut buffer = String::new();
-            tmp_path.push(dir_list[i].to_str().unwrap());
-            let mut f = std::fs::File::open(&tmp_path).unwrap();
-            f.read_to_string(&mut buffer).unwrap();
-            assert_eq!(
-                buffer,
-                "TEST JPEG DATA",
-                "filename = {}",
-                dir_list[i].to_str().unwrap()
-            );
-            tmp_path.pop();
-        }
+        let response = get(vec![(String::from("If-Modified-Since"), last_modified)]);
+        assert_eq!(response.status_code, 304);
 
-        srv_tx.send("stop").unwrap();
-        join_handle.join().unwrap();
+        let response = get(vec![(
+            String::from("If-Modified-Since"),
+            String::from("Thu, 01 Jan 1970 00:00:00 GMT"),
+        )]);
+        assert_eq!(response.status_code, 200);
 
         std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
     #[test]
-    fn test_route_images_post_by_content_type() {
+    fn test_parse_byte_ranges() {
+        use super::parse_byte_ranges;
+
+        assert_eq!(parse_byte_ranges("bytes=0-3", 10), Some(vec![(0, 3)]));
+        assert_eq!(parse_byte_ranges("bytes=5-", 10), Some(vec![(5, 9)]));
+        assert_eq!(parse_byte_ranges("bytes=-4", 10), Some(vec![(6, 9)]));
+        assert_eq!(parse_byte_ranges("bytes=-40", 10), Some(vec![(0, 9)]));
+        assert_eq!(
+            parse_byte_ranges("bytes=0-1, 8-20", 10),
+            Some(vec![(0, 1), (8, 9)])
+        );
+        assert_eq!(
+            parse_byte_ranges("bytes=8-9, 0-3, 2-5, 6-6", 10),
+            Some(vec![(0, 6), (8, 9)])
+        );
+        assert_eq!(parse_byte_ranges("bytes=0-,0-,0-", 10), Some(vec![(0, 9)]));
+        let header = format!("bytes={}", vec!["0-"; 17].join(","));
+        assert_eq!(parse_byte_ranges(&header, 10), None);
+        assert_eq!(parse_byte_ranges("bytes=10-", 10), Some(vec![]));
+        assert_eq!(parse_byte_ranges("bytes=3-1", 10), None);
+        assert_eq!(parse_byte_ranges("bytes=a-b", 10), None);
+        assert_eq!(parse_byte_ranges("items=0-1", 10), None);
+    }
+
+    #[test]
+    fn test_handle_image_get_range() {
         let mut tmp_path = std::env::temp_dir();
-        tmp_path.push("test-route-cvbcvbngfh");
+        tmp_path.push("test-image-get-range-m3k7ds");
         let _ = std::fs::remove_dir_all(&tmp_path);
         std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        tmp_path.push("test.png");
+        crate::file_utils::write_image_data(&b"0123456789"[..], &tmp_path).unwrap();
+        tmp_path.pop();
+
+        let settings = Settings::default();
+        let get = |headers: &[(&str, &str)]| {
+            let headers = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let http_rq = rouille::Request::fake_http("GET", "/images/test.png", headers, vec![]);
+            super::handle_image_get(&http_rq, &file_path, &settings, "test.png")
+        };
+        let header = |response: &rouille::Response, name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.to_string())
+                .unwrap_or_default()
+        };
+        let body = |response: rouille::Response| {
+            let (mut reader, _) = response.data.into_reader_and_size();
+            let mut buffer = String::new();
+            reader.read_to_string(&mut buffer).unwrap();
+            buffer
+        };
 
-        let (join_handle, srv_tx) = mock::test_http_server(8890);
-        std::thread::sleep(std::time::Duration::from_secs(2)); // Await for server warm-up or else may false test failures araise.
+        let response = get(&[]);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(header(&response, "Accept-Ranges"), "bytes");
+        let etag = header(&response, "ETag");
+
+        let response = get(&[("Range", "bytes=2-5")]);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(header(&response, "Content-Range"), "bytes 2-5/10");
+        assert_eq!(header(&response, "Content-Type"), "image/png");
+        assert_eq!(body(response), "2345");
+
+        let response = get(&[("Range", "bytes=0-1,-2")]);
+        assert_eq!(response.status_code, 206);
+        let content_type = header(&response, "Content-Type");
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let boundary = &content_type["multipart/byteranges; boundary=".len()..];
+        assert_eq!(
+            body(response),
+            format!(
+                "\r\n--{0}\r\nContent-Type: image/png\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{0}\r\nContent-Type: image/png\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{0}--\r\n",
+                boundary
+            )
+        );
 
-        let http_rq = mock::json_request(8890);
+        let response = get(&[("Range", "bytes=0-,0-,0-")]);
+        assert_eq!(response.status_code, 206);
+        assert_eq!(header(&response, "Content-Range"), "bytes 0-9/10");
+        assert_eq!(body(response), "0123456789");
 
-        let response =
-            super::route_images_post_by_content_type(&http_rq, &tmp_path.to_string_lossy());
+        let ranges = format!("bytes={}", vec!["0-"; 100].join(","));
+        let response = get(&[("Range", &ranges)]);
         assert_eq!(response.status_code, 200);
+        assert_eq!(body(response), "0123456789");
 
-        srv_tx.send("stop").unwrap();
-        join_handle.join().unwrap();
+        let response = get(&[("Range", "bytes=20-30")]);
+        assert_eq!(response.status_code, 416);
+        assert_eq!(header(&response, "Content-Range"), "bytes */10");
 
-        std::fs::remove_dir_all(&tmp_path).unwrap();
-        std::fs::create_dir_all(&tmp_path).unwrap();
-        let http_rq = mock::multipart_formdata_request();
+        let response = get(&[("Range", "bytes=2-5"), ("If-Range", &etag)]);
+        assert_eq!(response.status_code, 206);
 
-        let response =
-            super::route_images_post_by_content_type(&http_rq, &tmp_path.to_string_lossy());
+        let response = get(&[("Range", "bytes=2-5"), ("If-Range", "\"outdated\"")]);
         assert_eq!(response.status_code, 200);
+        assert_eq!(body(response), "0123456789");
 
         std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_handle_image_get_variant() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-image-get-variant-a8sd7f");
+        let _ = std::fs::remove_dir_all(&tmp_path);
         std::fs::create_dir_all(&tmp_path).unwrap();
-        let http_rq = mock::plaintext_request();
+        let file_path = tmp_path.to_string_lossy().to_string();
 
-        let response =
-            super::route_images_post_by_content_type(&http_rq, &tmp_path.to_string_lossy());
-        assert_eq!(response.status_code, 406);
+        tmp_path.push("test.png");
+        image::DynamicImage::new_rgb8(200, 100)
+            .save(&tmp_path)
+            .unwrap();
+        tmp_path.pop();
 
-        std::fs::remove_dir_all(&tmp_path).unwrap();
-        std::fs::create_dir_all(&tmp_path).unwrap();
-        let http_rq = mock::unknown_content_request();
+        let settings = Settings {
+            allowed_sizes: vec![(50, 50)],
+            ..Settings::default()
+        };
+        let get = |url: &str| {
+            let http_rq = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            super::handle_image_get(&http_rq, &file_path, &settings, "test.png")
+        };
 
-        let response =
-            super::route_images_post_by_content_type(&http_rq, &tmp_path.to_string_lossy());
-        assert_eq!(response.status_code, 400);
+        let response = get("/images/test.png?w=50&h=50&fit=cover&filter=nearest");
+        assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        let img = image::load_from_memory(&buffer).unwrap();
+        assert_eq!(img.dimensions(), (50, 50));
+
+        tmp_path.push("variants/test.png/50x50-cover-nearest.png");
+        assert!(tmp_path.is_file());
+        tmp_path.pop();
+        tmp_path.pop();
+        tmp_path.pop();
+
+        let response = get("/images/test.png?w=50&h=50");
+        assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        let img = image::load_from_memory(&buffer).unwrap();
+        assert_eq!(img.dimensions(), (50, 25));
+
+        let response = get("/images/test.png?format=jpeg&quality=80");
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/jpeg"));
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(
+            image::guess_format(&buffer).unwrap(),
+            image::ImageFormat::JPEG
+        );
+
+        let response = get("/images/test.png?w=50&h=50&format=bmp");
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/bmp"));
+
+        // Quality is rounded up to one of the levels, so few variants are cached.
+        tmp_path.push("variants/test.png/original-q90.jpg");
+        assert!(tmp_path.is_file());
+        tmp_path.pop();
+        for quality in 1..=100 {
+            let response = get(&format!("/images/test.png?format=jpeg&quality={}", quality));
+            assert_eq!(response.status_code, 200);
+        }
+        let mut cached: Vec<String> = std::fs::read_dir(&tmp_path)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|x| x.starts_with("original-q"))
+            .collect();
+        cached.sort();
+        assert_eq!(
+            cached,
+            [
+                "original-q100.jpg",
+                "original-q25.jpg",
+                "original-q50.jpg",
+                "original-q75.jpg",
+                "original-q90.jpg"
+            ]
+        );
+        tmp_path.pop();
+        tmp_path.pop();
+
+        // ICO is limited to 256x256, larger images are shrunk.
+        tmp_path.push("large.png");
+        image::DynamicImage::new_rgb8(600, 300)
+            .save(&tmp_path)
+            .unwrap();
+        tmp_path.pop();
+        let http_rq =
+            rouille::Request::fake_http("GET", "/images/large.png?format=ico", vec![], vec![]);
+        let response = super::handle_image_get(&http_rq, &file_path, &settings, "large.png");
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/vnd.microsoft.icon"));
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        let img = image::load_from_memory_with_format(&buffer, image::ImageFormat::ICO).unwrap();
+        assert_eq!(img.dimensions(), (256, 128));
+
+        assert_eq!(get("/images/test.png?format=webp").status_code, 400);
+        assert_eq!(
+            get("/images/test.png?format=jpeg&quality=0").status_code,
+            400
+        );
+        assert_eq!(
+            get("/images/test.png?format=jpeg&quality=101").status_code,
+            400
+        );
+        assert_eq!(get("/images/test.png?w=50&h=60").status_code, 400);
+        assert_eq!(get("/images/test.png?w=100&h=100").status_code, 400);
+        assert_eq!(get("/images/test.png?w=50").status_code, 400);
+        assert_eq!(
+            get("/images/test.png?w=50&h=50&fit=stretch").status_code,
+            400
+        );
+        assert_eq!(
+            get("/images/test.png?w=50&h=50&filter=bicubic").status_code,
+            400
+        );
 
         std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
     #[test]
-    fn test_handle_images_json_get() {
+    fn test_handle_image_delete() {
         let mut tmp_path = std::env::temp_dir();
-        tmp_path.push("test-get-ckenvthslc");
+        tmp_path.push("test-image-delete-f8gh3k");
         let _ = std::fs::remove_dir_all(&tmp_path);
         std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+
+        tmp_path.push("test.png");
+        image::DynamicImage::new_rgb8(20, 10)
+            .save(&tmp_path)
+            .unwrap();
+        assert!(crate::thumbnail::make(
+            &tmp_path.to_string_lossy(),
+            &Default::default()
+        ));
+        let variant = crate::variants::Variant {
+            format: Some(crate::variants::Format::Gif),
+            ..crate::variants::Variant::default()
+        };
+        crate::variants::make(&tmp_path, &variant, &Default::default()).unwrap();
+        let size = std::fs::metadata(&tmp_path).unwrap().len();
+        tmp_path.pop();
 
-        let response = super::handle_images_json_get(&tmp_path.to_string_lossy());
+        let response = super::handle_image_delete(&file_path, "test.png");
         assert_eq!(response.status_code, 200);
         let (reader, _) = response.data.into_reader_and_size();
-        let results: Vec<String> = serde_json::from_reader(reader).unwrap();
-        assert_eq!(results.len(), 0);
-
-        {
-            tmp_path.push("test.jpg");
-            std::fs::File::create(&tmp_path).unwrap();
-            tmp_path.pop();
+        let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+        assert_eq!(result.filename, "test.png");
+        assert_eq!(result.content_type, "image/png");
+        assert_eq!(result.size, size);
+        assert!(result.success);
+
+        let dir_list = |dir: &str| {
+            let mut dir_list = std::fs::read_dir(tmp_path.join(dir))
+                .unwrap()
+                .map(|x| x.unwrap().file_name())
+                .collect::<Vec<_>>();
+            dir_list[..].sort();
+            dir_list
+        };
+        assert_eq!(dir_list(""), ["thumbnails", "variants"]);
+        assert_eq!(dir_list("thumbnails").len(), 0);
+        assert_eq!(dir_list("variants").len(), 0);
 
-            tmp_path.push("test.png");
-            std::fs::File::create(&tmp_path).unwrap();
-            tmp_path.pop();
+        let response = super::handle_image_delete(&file_path, "test.png");
+        assert_eq!(response.status_code, 404);
+        let (reader, _) = response.data.into_reader_and_size();
+        let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+        assert!(!result.success);
+
+        let response = super::handle_image_delete(&file_path, "thumbnails");
+        assert_eq!(response.status_code, 404);
+
+        // Test for the image in use.
+        let image_path = tmp_path.join("test.png");
+        image::DynamicImage::new_rgb8(20, 10)
+            .save(&image_path)
+            .unwrap();
+        let file = crate::file_utils::open_image_data(&image_path).unwrap();
+        let response = super::handle_image_delete(&file_path, "test.png");
+        assert_eq!(response.status_code, 423);
+        let (reader, _) = response.data.into_reader_and_size();
+        let result: super::ImageUploadResult = serde_json::from_reader(reader).unwrap();
+        assert_eq!(result.reason, "image is in use");
+        assert!(image_path.is_file());
+        drop(file);
+
+        // Test for the image removed while its thumbnail is generated.
+        image::DynamicImage::new_rgb8(2000, 2000)
+            .save(&image_path)
+            .unwrap();
+        crate::thumbnail::spawn(image_path.clone(), Default::default());
+        let response = super::handle_image_delete(&file_path, "test.png");
+        assert_eq!(response.status_code, 200);
+        while crate::thumbnail::status(&image_path) == crate::thumbnail::Status::Pending {
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
+        assert_eq!(
+            crate::thumbnail::status(&image_path),
+            crate::thumbnail::Status::Missing
+        );
+        assert_eq!(dir_list("thumbnails").len(), 0);
 
-        let response = super::handle_images_json_get(&tmp_path.to_string_lossy());
-        assert_eq!(response.status_code, 200);
-        let (reader, _) = response.data.into_reader_and_size();
-        let results: Vec<String> = serde_json::from_reader(reader).unwrap();
-        assert_eq!(results, ["test.jpg", "test.png"]);
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_handle_thumbnail_get() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-thumbnail-get-hjkyu7");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+        let file_path = tmp_path.to_string_lossy().to_string();
+        let http_rq =
+            rouille::Request::fake_http("GET", "/images/test.png/thumbnail", vec![], vec![]);
+
+        let response =
+            super::handle_thumbnail_get(&http_rq, &file_path, &Settings::default(), "missing.png");
+        assert_eq!(response.status_code, 404);
+
+        tmp_path.push("test.png");
+        image::DynamicImage::new_rgb8(200, 150)
+            .save(&tmp_path)
+            .unwrap();
+        tmp_path.pop();
 
+        let response =
+            super::handle_thumbnail_get(&http_rq, &file_path, &Settings::default(), "test.png");
+        assert_eq!(response.status_code, 202);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Retry-After" && v == "1"));
+
+        let mut attempts = 0;
+        let response = loop {
+            let response =
+                super::handle_thumbnail_get(&http_rq, &file_path, &Settings::default(), "test.png");
+            if response.status_code != 202 || attempts == 50 {
+                break response;
+            }
+            attempts += 1;
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        };
         assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        let img = image::load_from_memory(&buffer).unwrap();
+        assert_eq!(img.dimensions(), (100, 100));
+
+        tmp_path.push("broken.png");
+        crate::file_utils::write_image_data(&b"NOT A PNG"[..], &tmp_path).unwrap();
+        assert!(!crate::thumbnail::make(
+            &tmp_path.to_string_lossy(),
+            &Default::default()
+        ));
+        tmp_path.pop();
 
         std::fs::remove_dir_all(&tmp_path).unwrap();
     }
 
+    fn read_all(mut data: Box<dyn Read>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        data.read_to_end(&mut buffer).unwrap();
+        buffer
+    }
+
     mod mock {
         use rouille::router;
+        use std::sync::atomic::{AtomicBool, Ordering};
         use std::sync::mpsc;
         use std::thread;
 
+        /// Whether the next request to `/flaky` fails.
+        static FLAKY_FAILS: AtomicBool = AtomicBool::new(true);
+
+        /// Reader of the data stalled for a while after the first bytes.
+        struct SlowReader(std::io::Cursor<Vec<u8>>);
+
+        impl std::io::Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read = self.0.read(buf)?;
+                if read == 0 {
+                    thread::sleep(std::time::Duration::from_millis(1000));
+                }
+                Ok(read)
+            }
+        }
+
+        #[allow(clippy::manual_strip)]
         pub fn test_http_server(port: u16) -> (thread::JoinHandle<()>, mpsc::Sender<&'static str>) {
             let (srv_tx, srv_rx) = mpsc::channel::<&str>();
             let join_handle = thread::spawn(move || {
@@ -760,6 +4319,84 @@ mod tests {
 
                         },
 
+                        (GET) (/image-params) => {
+                            rouille::Response::from_data("Image/JPEG; charset=binary", "TEST JPEG DATA")
+                        },
+
+                        (GET) (/gallery/) => {
+                            rouille::Response::from_data("image/jpeg", "TEST JPEG DATA")
+                        },
+
+                        (GET) (/disposition) => {
+                            rouille::Response::from_data("image/png", "TEST PNG DATA").with_additional_header(
+                                "Content-Disposition",
+                                "attachment; filename=\"cat.png\"; filename*=UTF-8''my%20cat%3F.png",
+                            )
+                        },
+
+                        (GET) (/redirect) => {
+                            rouille::Response::redirect_302("/image")
+                        },
+
+                        (GET) (/redirect-private) => {
+                            rouille::Response::redirect_302("http://127.0.0.1:1/image")
+                        },
+
+                        (GET) (/redirect-loop) => {
+                            rouille::Response::redirect_302("/redirect-loop")
+                        },
+
+                        (GET) (/flaky) => {
+                            if FLAKY_FAILS.fetch_xor(true, Ordering::SeqCst) {
+                                rouille::Response::text("Try again").with_status_code(503)
+                            } else {
+                                rouille::Response::from_data("image/jpeg", "FLAKY JPEG DATA")
+                            }
+                        },
+
+                        (GET) (/error) => {
+                            rouille::Response::text("Error").with_status_code(500)
+                        },
+
+                        (GET) (/slow) => {
+                            thread::sleep(std::time::Duration::from_millis(1000));
+                            rouille::Response::from_data("image/jpeg", "SLOW JPEG DATA")
+                        },
+
+                        (GET) (/slow-body) => {
+                            let data = SlowReader(std::io::Cursor::new(vec![0; 256 * 1024]));
+                            rouille::Response {
+                                status_code: 200,
+                                headers: vec![("Content-Type".into(), "image/jpeg".into())],
+                                data: rouille::ResponseBody::from_reader(data),
+                                upgrade: None,
+                            }
+                        },
+
+                        (GET) (/chunked) => {
+                            let data = std::io::Cursor::new(b"CHUNKED JPEG DATA".to_vec());
+                            rouille::Response {
+                                status_code: 200,
+                                headers: vec![("Content-Type".into(), "image/jpeg".into())],
+                                data: rouille::ResponseBody::from_reader(data),
+                                upgrade: None,
+                            }
+                        },
+
+                        (GET) (/large) => {
+                            rouille::Response::from_data("image/jpeg", vec![0; 64])
+                        },
+
+                        (GET) (/large-chunked) => {
+                            let data = std::io::Cursor::new(vec![0; 64]);
+                            rouille::Response {
+                                status_code: 200,
+                                headers: vec![("Content-Type".into(), "image/jpeg".into())],
+                                data: rouille::ResponseBody::from_reader(data),
+                                upgrade: None,
+                            }
+                        },
+
                         _ => rouille::Response::empty_404(),
                     )
                 }).unwrap();
@@ -801,7 +4438,7 @@ mod tests {
                 ),
                 (
                     String::from("Content-Length"),
-                    body.as_bytes().len().to_string(),
+                    body.len().to_string(),
                 ),
             ];
 
@@ -813,6 +4450,19 @@ mod tests {
             )
         }
 
+        pub fn raw_image_request(
+            method: &str,
+            content_type: &str,
+            body: &[u8],
+        ) -> rouille::Request {
+            let headers = [
+                (String::from("Content-Type"), String::from(content_type)),
+                (String::from("Content-Length"), body.len().to_string()),
+            ];
+
+            rouille::Request::fake_http(method, "/images", headers.to_vec(), body.to_vec())
+        }
+
         pub fn json_request(port: u16) -> rouille::Request {
             let body = r#"
                     [
@@ -831,7 +4481,7 @@ mod tests {
                 ),
                 (
                     String::from("Content-Length"),
-                    body.as_bytes().len().to_string(),
+                    body.len().to_string(),
                 ),
             ];
 
@@ -850,7 +4500,7 @@ mod tests {
                 (String::from("Content-Type"), String::from("text/plain")),
                 (
                     String::from("Content-Length"),
-                    body.as_bytes().len().to_string(),
+                    body.len().to_string(),
                 ),
             ];
 
@@ -867,7 +4517,7 @@ mod tests {
 
             let headers = [(
                 String::from("Content-Length"),
-                body.as_bytes().len().to_string(),
+                body.len().to_string(),
             )];
 
             rouille::Request::fake_http(
@@ -0,0 +1,207 @@
+use image::{self, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageResult};
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+/// Basic image properties read from the image headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    pub width: u64,
+    pub height: u64,
+}
+
+impl ImageInfo {
+    /// Read the image format and dimensions without decoding the pixel data.
+    ///
+    /// The format is guessed by the magic bytes at the beginning of the data, then
+    /// the decoder for the format reads the headers only, so it is cheap even for
+    /// huge images. WebP headers are parsed by `webp_dimensions`, because the WebP
+    /// decoder decodes the whole frame when it is created.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::image_info::{self, ImageInfo};
+    ///
+    ///     let mut data = Vec::new();
+    ///     image::DynamicImage::new_rgb8(64, 48)
+    ///         .write_to(&mut data, image::ImageOutputFormat::PNG)
+    ///         .unwrap();
+    ///
+    ///     let info = ImageInfo::read(std::io::Cursor::new(&data)).unwrap();
+    ///     assert_eq!(image_info::format_name(info.format), "png");
+    ///     assert_eq!((info.width, info.height), (64, 48));
+    /// ```
+    pub fn read<R: BufRead + Seek>(mut reader: R) -> ImageResult<ImageInfo> {
+        let start = reader.stream_position()?;
+        let mut magic = Vec::with_capacity(16);
+        reader.by_ref().take(16).read_to_end(&mut magic)?;
+        reader.seek(SeekFrom::Start(start))?;
+
+        let format = guess_format(&magic)
+            .ok_or_else(|| ImageError::UnsupportedError(String::from("unknown image format")))?;
+
+        let (width, height) = match format {
+            ImageFormat::PNG => image::png::PNGDecoder::new(reader)?.dimensions(),
+            ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(reader)?.dimensions(),
+            ImageFormat::GIF => image::gif::Decoder::new(reader)?.dimensions(),
+            ImageFormat::WEBP => webp_dimensions(reader)?,
+            ImageFormat::TIFF => image::tiff::TIFFDecoder::new(reader)?.dimensions(),
+            ImageFormat::BMP => image::bmp::BMPDecoder::new(reader)?.dimensions(),
+            ImageFormat::ICO => image::ico::ICODecoder::new(reader)?.dimensions(),
+            ImageFormat::PNM => image::pnm::PNMDecoder::new(reader)?.dimensions(),
+            _ => {
+                return Err(ImageError::UnsupportedError(format!(
+                    "{} image format",
+                    format_name(format)
+                )))
+            }
+        };
+
+        let info = ImageInfo {
+            format,
+            width,
+            height,
+        };
+        log::debug!("ImageInfo::read => {:?}", info);
+        Ok(info)
+    }
+}
+
+/// Length of the RIFF and the first chunk headers enough to read the WebP dimensions.
+const WEBP_HEADER_LENGTH: usize = 30;
+
+/// Read the WebP image dimensions from the headers of the first chunk.
+///
+/// Supports the lossy frame header ("VP8 "), the lossless header ("VP8L") and
+/// the extended format canvas size ("VP8X"). No pixel data is read.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::image_info;
+///
+///     let mut header = b"RIFF\x16\0\0\0WEBPVP8 \x0a\0\0\0\x50\x01\0\x9d\x01\x2a".to_vec();
+///     header.extend_from_slice(&[0xff, 0x3f, 0xff, 0x3f]);
+///     assert_eq!(image_info::webp_dimensions(&header[..]).unwrap(), (16383, 16383));
+///
+///     let header = b"RIFF\x16\0\0\0WEBPVP8L\x05\0\0\0\x2f\x3f\xc0\x0f\0";
+///     assert_eq!(image_info::webp_dimensions(&header[..]).unwrap(), (64, 64));
+///
+///     let header = b"RIFF\x16\0\0\0WEBPVP8X\x0a\0\0\0\0\0\0\0\xff\xff\0\x2f\0\0";
+///     assert_eq!(image_info::webp_dimensions(&header[..]).unwrap(), (65536, 48));
+///
+///     assert!(image_info::webp_dimensions(&b"RIFF\x16\0\0\0WEBPVP8 "[..]).is_err());
+/// ```
+pub fn webp_dimensions<R: Read>(reader: R) -> ImageResult<(u64, u64)> {
+    let mut header = Vec::with_capacity(WEBP_HEADER_LENGTH);
+    reader
+        .take(WEBP_HEADER_LENGTH as u64)
+        .read_to_end(&mut header)?;
+
+    let invalid = || ImageError::FormatError(String::from("invalid WebP header"));
+    if header.len() < 21 || !header.starts_with(b"RIFF") || &header[8..12] != b"WEBP" {
+        return Err(invalid());
+    }
+    let data = &header[20..];
+    let le = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |x, &b| (x << 8) | u64::from(b))
+    };
+
+    match &header[12..16] {
+        b"VP8 " if data.len() >= 10 && data[3..6] == [0x9d, 0x01, 0x2a] => {
+            Ok((le(&data[6..8]) & 0x3fff, le(&data[8..10]) & 0x3fff))
+        }
+        b"VP8L" if data.len() >= 5 && data[0] == 0x2f => {
+            let bits = le(&data[1..5]);
+            Ok(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" if data.len() >= 10 => Ok((le(&data[4..7]) + 1, le(&data[7..10]) + 1)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Guess the image format by the magic bytes at the beginning of the data.
+///
+/// Unlike `image::guess_format` recognizes WebP images in the RIFF container.
+///
+/// # Examples
+///
+/// ```rust
+///     use image::ImageFormat;
+///     use trlogic_test::image_info;
+///
+///     assert_eq!(image_info::guess_format(b"\xff\xd8\xff\xe0"), Some(ImageFormat::JPEG));
+///     assert_eq!(image_info::guess_format(b"RIFF\x24\0\0\0WEBPVP8 "), Some(ImageFormat::WEBP));
+///     assert_eq!(image_info::guess_format(b"<!DOCTYPE html>"), None);
+/// ```
+pub fn guess_format(data: &[u8]) -> Option<ImageFormat> {
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return Some(ImageFormat::WEBP);
+    }
+
+    image::guess_format(data).ok()
+}
+
+/// Get the short lowercase name of the image format.
+pub fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::PNG => "png",
+        ImageFormat::JPEG => "jpeg",
+        ImageFormat::GIF => "gif",
+        ImageFormat::WEBP => "webp",
+        ImageFormat::PNM => "pnm",
+        ImageFormat::TIFF => "tiff",
+        ImageFormat::TGA => "tga",
+        ImageFormat::BMP => "bmp",
+        ImageFormat::ICO => "ico",
+        ImageFormat::HDR => "hdr",
+    }
+}
+
+/// Get the MIME type of the image format.
+///
+/// # Examples
+///
+/// ```rust
+///     use image::ImageFormat;
+///     use trlogic_test::image_info;
+///
+///     assert_eq!(image_info::content_type(ImageFormat::JPEG), "image/jpeg");
+///     assert_eq!(image_info::content_type(ImageFormat::ICO), "image/vnd.microsoft.icon");
+/// ```
+pub fn content_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::PNG => "image/png",
+        ImageFormat::JPEG => "image/jpeg",
+        ImageFormat::GIF => "image/gif",
+        ImageFormat::WEBP => "image/webp",
+        ImageFormat::PNM => "image/x-portable-anymap",
+        ImageFormat::TIFF => "image/tiff",
+        ImageFormat::TGA => "image/x-tga",
+        ImageFormat::BMP => "image/bmp",
+        ImageFormat::ICO => "image/vnd.microsoft.icon",
+        ImageFormat::HDR => "image/vnd.radiance",
+    }
+}
+
+/// Read the info of the image stored in the file.
+///
+/// The file is expected to be opened with `file_utils::open_image_data`.
+pub fn read_file(file: &File) -> ImageResult<ImageInfo> {
+    ImageInfo::read(io::BufReader::new(file))
+}
+
+/// Decode the image of the specified format stored in the file.
+///
+/// Dimensions should be checked with `read_file` first, decoding of a huge image
+/// can exhaust the memory.
+pub fn decode_file(mut file: &File, format: ImageFormat) -> ImageResult<DynamicImage> {
+    log::trace!("decode_file(File, {:?}) ...", format);
+
+    file.seek(SeekFrom::Start(0))?;
+    image::load(io::BufReader::new(file), format)
+}
@@ -0,0 +1,369 @@
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::file_utils;
+use super::http_handlers::ImageUploadResult;
+
+lazy_static! {
+    /// Jobs being processed by this process, other unfinished jobs were interrupted.
+    static ref RUNNING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+static ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// State of the upload job and of its items.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// Item isn't processed yet.
+    Pending,
+    /// Job items are being processed.
+    Running,
+    /// Job or item is processed, the item result is available.
+    Done,
+    /// Processing was stopped by the service restart.
+    Interrupted,
+}
+
+/// Item of the upload job: one image of the upload request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobItem {
+    pub state: State,
+    /// Result of the item processing, when it's done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<ImageUploadResult>,
+    /// Thumbnail state of the stored image: "ready", "pending", "failed" or "missing".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+}
+
+/// Upload request processed in the background.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub state: State,
+    /// Creation time in RFC 3339 format.
+    pub created: String,
+    /// Number of the processed items.
+    pub completed: usize,
+    pub items: Vec<JobItem>,
+}
+
+impl Job {
+    /// Make a new job with a unique identifier and all the items pending.
+    pub fn new(items: usize) -> Job {
+        let nanos = UNIX_EPOCH.elapsed().map_or(0, |x| x.as_nanos());
+        let counter = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+
+        Job {
+            id: format!("{:x}{:04x}", nanos, counter & 0xffff),
+            state: State::Running,
+            created: Utc::now().to_rfc3339(),
+            completed: 0,
+            items: (0..items)
+                .map(|_| JobItem {
+                    state: State::Pending,
+                    result: None,
+                    thumbnail: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Set the result of the item, the job is done when all the items are.
+    pub fn complete(&mut self, index: usize, result: ImageUploadResult) {
+        let item = &mut self.items[index];
+        if item.state != State::Done {
+            self.completed += 1;
+        }
+        item.state = State::Done;
+        item.result = Some(result);
+
+        if self.completed == self.items.len() {
+            self.state = State::Done;
+        }
+    }
+
+    /// Store the job record under the upload path.
+    ///
+    /// The record is replaced atomically, so it can be loaded while the job is running.
+    pub fn save(&self, file_path: &Path) -> io::Result<()> {
+        log::trace!("Job::save(\"{}\") ...", self.id);
+
+        let job_path = path_for(file_path, &self.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid job identifier"))?;
+        fs::create_dir_all(dir_for(file_path))?;
+
+        let tmp_path = file_utils::temp_path_for(&job_path);
+        let data = serde_json::to_vec(self)?;
+        let result = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, &job_path));
+        if let Err(e) = &result {
+            log::warn!(
+                "I/O ERROR \"{}\" while saving job to file {}!",
+                e,
+                job_path.display()
+            );
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Load the job record stored under the upload path.
+    ///
+    /// Unfinished jobs not running in this process are reported as interrupted.
+    pub fn load(file_path: &Path, id: &str) -> io::Result<Job> {
+        log::trace!("Job::load(\"{}\") ...", id);
+
+        let job_path = path_for(file_path, id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "invalid job identifier"))?;
+        let mut job: Job = serde_json::from_slice(&fs::read(job_path)?)?;
+
+        if job.state == State::Running && !RUNNING.lock().unwrap().contains(&job.id) {
+            job.state = State::Interrupted;
+            for item in job.items.iter_mut().filter(|x| x.state != State::Done) {
+                item.state = State::Interrupted;
+            }
+        }
+
+        log::debug!(
+            "Job::load => {:?}, {} of {} items",
+            job.state,
+            job.completed,
+            job.items.len()
+        );
+        Ok(job)
+    }
+}
+
+/// Get the directory where the job records are stored.
+pub fn dir_for(file_path: &Path) -> PathBuf {
+    file_path.join("jobs")
+}
+
+/// Get the path where the job record is stored.
+///
+/// Returns `None` if the identifier isn't a job one, so it can't be used
+/// to access other files.
+pub fn path_for(file_path: &Path, id: &str) -> Option<PathBuf> {
+    if id.is_empty() || !id.chars().all(|x| x.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    let mut job_path = dir_for(file_path);
+    job_path.push(format!("{}.json", id));
+    Some(job_path)
+}
+
+/// Remove the records of the jobs not running in this process which weren't updated
+/// for longer than the time to live.
+///
+/// Unfinished jobs of the previous runs are interrupted, so their records expire like
+/// the records of the finished jobs. Returns the number of the removed records.
+pub fn remove_expired(file_path: &Path, ttl: Duration) -> io::Result<usize> {
+    log::trace!("remove_expired(\"{}\", {:?}) ...", file_path.display(), ttl);
+
+    let entries = match fs::read_dir(dir_for(file_path)) {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let job_path = entry?.path();
+        let id = match job_path.file_stem().map(|x| x.to_string_lossy()) {
+            Some(id) if path_for(file_path, &id).as_ref() == Some(&job_path) => id.to_string(),
+            _ => continue,
+        };
+        let age = fs::metadata(&job_path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age > ttl && !RUNNING.lock().unwrap().contains(&id) {
+            fs::remove_file(&job_path)?;
+            removed += 1;
+        }
+    }
+
+    log::debug!("remove_expired => {} jobs", removed);
+    Ok(removed)
+}
+
+/// Get the number of the jobs being processed by this process.
+pub fn running() -> usize {
+    RUNNING.lock().unwrap().len()
+}
+
+/// Process the job items in a background thread.
+///
+/// The job is saved before the processing starts and after every item is done,
+/// `process` is given the function to report the item results with.
+/// If `max_running` jobs are already being processed, the job isn't started and
+/// `io::ErrorKind::WouldBlock` error is returned.
+pub fn spawn<F>(job: Job, file_path: PathBuf, max_running: usize, process: F) -> io::Result<()>
+where
+    F: FnOnce(&(dyn Fn(usize, ImageUploadResult) + Sync)) + Send + 'static,
+{
+    log::trace!("spawn(\"{}\") ...", job.id);
+
+    {
+        let mut running = RUNNING.lock().unwrap();
+        if running.len() >= max_running {
+            log::debug!("spawn => Err(\"too many jobs running\")");
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many jobs running",
+            ));
+        }
+        running.insert(job.id.clone());
+    }
+    if let Err(e) = job.save(&file_path) {
+        RUNNING.lock().unwrap().remove(&job.id);
+        return Err(e);
+    }
+
+    std::thread::spawn(move || {
+        let id = job.id.clone();
+        let job = Mutex::new(job);
+
+        process(&|index, result| {
+            let mut job = job.lock().unwrap();
+            job.complete(index, result);
+            let _ = job.save(&file_path);
+        });
+
+        let mut job = job.into_inner().unwrap();
+        if job.state != State::Done {
+            job.state = State::Done;
+            let _ = job.save(&file_path);
+        }
+        RUNNING.lock().unwrap().remove(&id);
+        log::debug!("spawn => job \"{}\" is done", id);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-jobs-save-load-p2m8vx");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+
+        let mut job = Job::new(2);
+        assert_ne!(job.id, Job::new(2).id);
+        assert!(path_for(&tmp_path, "../images").is_none());
+
+        job.complete(
+            1,
+            ImageUploadResult {
+                filename: String::from("image.jpg"),
+                success: true,
+                ..ImageUploadResult::default()
+            },
+        );
+        assert_eq!(job.state, State::Running);
+        job.save(&tmp_path).unwrap();
+
+        // Not running in this process, so it was interrupted.
+        let loaded = Job::load(&tmp_path, &job.id).unwrap();
+        assert_eq!(loaded.state, State::Interrupted);
+        assert_eq!(loaded.completed, 1);
+        assert_eq!(loaded.items[0].state, State::Interrupted);
+        assert_eq!(loaded.items[1].state, State::Done);
+        assert_eq!(
+            loaded.items[1].result.as_ref().unwrap().filename,
+            "image.jpg"
+        );
+
+        job.complete(0, ImageUploadResult::default());
+        assert_eq!(job.state, State::Done);
+        job.save(&tmp_path).unwrap();
+        assert_eq!(Job::load(&tmp_path, &job.id).unwrap().state, State::Done);
+
+        assert_eq!(
+            Job::load(&tmp_path, "missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-jobs-remove-expired-k7w3qd");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        assert_eq!(
+            remove_expired(&tmp_path, Duration::from_secs(0)).unwrap(),
+            0
+        );
+
+        let job = Job::new(1);
+        job.save(&tmp_path).unwrap();
+        std::fs::write(dir_for(&tmp_path).join("notes.txt"), b"NOTES").unwrap();
+        assert_eq!(
+            remove_expired(&tmp_path, Duration::from_secs(60)).unwrap(),
+            0
+        );
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            remove_expired(&tmp_path, Duration::from_millis(1)).unwrap(),
+            1
+        );
+        assert_eq!(
+            Job::load(&tmp_path, &job.id).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(dir_for(&tmp_path).join("notes.txt").is_file());
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+
+    #[test]
+    fn test_spawn_limit() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-jobs-spawn-limit-f4j9sn");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+
+        let job = Job::new(1);
+        let id = job.id.clone();
+        let error = spawn(job, tmp_path.clone(), 0, |_| ()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(path_for(&tmp_path, &id).map_or(false, |x| !x.exists()));
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let job = Job::new(1);
+        let id = job.id.clone();
+        spawn(job, tmp_path.clone(), usize::MAX, move |done| {
+            let _ = rx.recv();
+            done(0, ImageUploadResult::default());
+        })
+        .unwrap();
+        assert_eq!(Job::load(&tmp_path, &id).unwrap().state, State::Running);
+        assert_eq!(
+            remove_expired(&tmp_path, Duration::from_secs(0)).unwrap(),
+            0
+        );
+
+        tx.send(()).unwrap();
+        while Job::load(&tmp_path, &id).unwrap().state != State::Done {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+}
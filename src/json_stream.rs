@@ -0,0 +1,415 @@
+use std::io::{self, BufRead, Read};
+
+/// Maximal length of a JSON string read into memory as a whole.
+pub const MAX_STRING_LENGTH: u64 = 64 * 1024;
+
+/// Maximal nesting depth of the skipped JSON values.
+const MAX_DEPTH: usize = 64;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Incremental reader of a JSON document.
+///
+/// Values are read one by one as the document is parsed, strings can be read as
+/// streams, so only the values being processed are kept in memory.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::io::Read;
+///     use trlogic_test::json_stream::JsonReader;
+///
+///     let mut json = JsonReader::new(&br#"[{"name": "a b", "skipped": [1, {}]}]"#[..]);
+///     json.expect(b'[').unwrap();
+///     assert!(json.next_element(b']', true).unwrap());
+///     json.expect(b'{').unwrap();
+///
+///     assert!(json.next_element(b'}', true).unwrap());
+///     assert_eq!(json.read_key().unwrap(), "name");
+///     let mut value = String::new();
+///     json.string_reader().unwrap().read_to_string(&mut value).unwrap();
+///     assert_eq!(value, "a b");
+///
+///     assert!(json.next_element(b'}', false).unwrap());
+///     assert_eq!(json.read_key().unwrap(), "skipped");
+///     json.skip_value().unwrap();
+///
+///     assert!(!json.next_element(b'}', false).unwrap());
+///     assert!(!json.next_element(b']', false).unwrap());
+///     json.expect_end().unwrap();
+/// ```
+pub struct JsonReader<R> {
+    reader: R,
+}
+
+impl<R: BufRead> JsonReader<R> {
+    pub fn new(reader: R) -> Self {
+        JsonReader { reader }
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().cloned())
+    }
+
+    fn next_byte(&mut self) -> io::Result<u8> {
+        match self.peek()? {
+            Some(x) => {
+                self.reader.consume(1);
+                Ok(x)
+            }
+            None => Err(invalid("unexpected end of JSON")),
+        }
+    }
+
+    /// Skip whitespace and get the next byte without consuming it.
+    pub fn peek_token(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.peek()? {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.reader.consume(1),
+                x => return Ok(x),
+            }
+        }
+    }
+
+    /// Skip whitespace and consume the expected byte.
+    pub fn expect(&mut self, token: u8) -> io::Result<()> {
+        if self.peek_token()? == Some(token) {
+            self.reader.consume(1);
+            Ok(())
+        } else {
+            Err(invalid(&format!("\"{}\" expected", token as char)))
+        }
+    }
+
+    /// Check that there is nothing but whitespace left.
+    pub fn expect_end(&mut self) -> io::Result<()> {
+        match self.peek_token()? {
+            None => Ok(()),
+            Some(_) => Err(invalid("trailing characters after JSON")),
+        }
+    }
+
+    /// Move to the next element of the array or the object, `close` is its closing
+    /// bracket and `first` is whether no elements were read yet.
+    ///
+    /// Returns `false` after the closing bracket is consumed.
+    pub fn next_element(&mut self, close: u8, first: bool) -> io::Result<bool> {
+        if self.peek_token()? == Some(close) {
+            self.reader.consume(1);
+            return Ok(false);
+        }
+        if !first {
+            self.expect(b',')?;
+        }
+        Ok(true)
+    }
+
+    /// Read the object key and the following colon.
+    pub fn read_key(&mut self) -> io::Result<String> {
+        let key = self.read_string()?;
+        self.expect(b':')?;
+        Ok(key)
+    }
+
+    /// Get the reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Read the string value, up to `MAX_STRING_LENGTH` bytes long.
+    pub fn read_string(&mut self) -> io::Result<String> {
+        self.string_reader()?.into_string(Vec::new())
+    }
+
+    /// Read the string value, or `None` if the value is null.
+    pub fn read_optional_string(&mut self) -> io::Result<Option<String>> {
+        if self.read_null()? {
+            Ok(None)
+        } else {
+            self.read_string().map(Some)
+        }
+    }
+
+    /// Consume the null value if it's next.
+    pub fn read_null(&mut self) -> io::Result<bool> {
+        if self.peek_token()? != Some(b'n') {
+            return Ok(false);
+        }
+        self.read_literal(b"null")?;
+        Ok(true)
+    }
+
+    fn read_literal(&mut self, literal: &[u8]) -> io::Result<()> {
+        for &x in literal {
+            if self.next_byte()? != x {
+                return Err(invalid("invalid JSON literal"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the reader of the string value with the escape sequences decoded.
+    ///
+    /// The reader yields UTF-8 bytes of the string, the string must be read to the end,
+    /// or skipped with `StringReader::finish`, before the next value is read.
+    pub fn string_reader(&mut self) -> io::Result<StringReader<'_, R>> {
+        self.expect(b'"')?;
+        Ok(StringReader {
+            json: self,
+            pending: [0; 4],
+            pending_pos: 0,
+            pending_len: 0,
+            done: false,
+            error: None,
+        })
+    }
+
+    /// Skip the value of any type.
+    pub fn skip_value(&mut self) -> io::Result<()> {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("JSON nesting too deep"));
+        }
+
+        match self.peek_token()? {
+            Some(b'"') => self.string_reader()?.finish(),
+            Some(b'{') => {
+                self.reader.consume(1);
+                let mut first = true;
+                while self.next_element(b'}', first)? {
+                    first = false;
+                    self.string_reader()?.finish()?;
+                    self.expect(b':')?;
+                    self.skip_nested(depth + 1)?;
+                }
+                Ok(())
+            }
+            Some(b'[') => {
+                self.reader.consume(1);
+                let mut first = true;
+                while self.next_element(b']', first)? {
+                    first = false;
+                    self.skip_nested(depth + 1)?;
+                }
+                Ok(())
+            }
+            Some(b't') => self.read_literal(b"true"),
+            Some(b'f') => self.read_literal(b"false"),
+            Some(b'n') => self.read_literal(b"null"),
+            Some(b'-') | Some(b'0'..=b'9') => {
+                while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+                | Some(b'0'..=b'9') = self.peek()?
+                {
+                    self.reader.consume(1);
+                }
+                Ok(())
+            }
+            _ => Err(invalid("JSON value expected")),
+        }
+    }
+}
+
+/// Reader of the JSON string value, see `JsonReader::string_reader`.
+pub struct StringReader<'a, R> {
+    json: &'a mut JsonReader<R>,
+    /// UTF-8 bytes of the decoded escape sequence not read yet.
+    pending: [u8; 4],
+    pending_pos: usize,
+    pending_len: usize,
+    done: bool,
+    /// Syntax error, the reader fails with it once it occurred.
+    error: Option<String>,
+}
+
+impl<'a, R: BufRead> StringReader<'a, R> {
+    /// Skip the rest of the string.
+    pub fn finish(mut self) -> io::Result<()> {
+        io::copy(&mut self, &mut io::sink()).map(|_| ())
+    }
+
+    /// Read the rest of the string after the bytes already read from it, up to
+    /// `MAX_STRING_LENGTH` bytes long in total.
+    pub fn into_string(mut self, mut value: Vec<u8>) -> io::Result<String> {
+        let limit = (MAX_STRING_LENGTH + 1).saturating_sub(value.len() as u64);
+        self.by_ref().take(limit).read_to_end(&mut value)?;
+        if value.len() as u64 > MAX_STRING_LENGTH {
+            return Err(invalid("JSON string too long"));
+        }
+        self.finish()?;
+
+        String::from_utf8(value).map_err(|_| invalid("invalid UTF-8 in JSON string"))
+    }
+
+    fn read_hex4(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let digit = (self.json.next_byte()? as char)
+                .to_digit(16)
+                .ok_or_else(|| invalid("invalid JSON escape sequence"))?;
+            value = value * 16 + digit;
+        }
+        Ok(value)
+    }
+
+    fn read_escape(&mut self) -> io::Result<()> {
+        let decoded = match self.json.next_byte()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let high = self.read_hex4()?;
+                let code = if (0xd800..0xdc00).contains(&high) {
+                    self.json.read_literal(b"\\u")?;
+                    let low = self.read_hex4()?;
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(invalid("invalid JSON unicode escape"));
+                    }
+                    0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                } else {
+                    high
+                };
+                std::char::from_u32(code).ok_or_else(|| invalid("invalid JSON unicode escape"))?
+            }
+            _ => return Err(invalid("invalid JSON escape sequence")),
+        };
+
+        self.pending_len = decoded.encode_utf8(&mut self.pending).len();
+        self.pending_pos = 0;
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pending_pos < self.pending_len {
+                let len = (self.pending_len - self.pending_pos).min(buf.len());
+                buf[..len].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + len]);
+                self.pending_pos += len;
+                return Ok(len);
+            }
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+
+            let available = self.json.reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(invalid("unterminated JSON string"));
+            }
+            // Plain characters are copied as is up to the next special one.
+            let plain = available
+                .iter()
+                .position(|&x| x == b'"' || x == b'\\' || x < 0x20)
+                .unwrap_or(available.len())
+                .min(buf.len());
+            if plain > 0 {
+                buf[..plain].copy_from_slice(&available[..plain]);
+                self.json.reader.consume(plain);
+                return Ok(plain);
+            }
+
+            match self.json.next_byte()? {
+                b'"' => self.done = true,
+                b'\\' => self.read_escape()?,
+                _ => return Err(invalid("control character in JSON string")),
+            }
+        }
+    }
+}
+
+impl<'a, R: BufRead> Read for StringReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = &self.error {
+            return Err(invalid(e));
+        }
+
+        let result = self.read_chunk(buf);
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::InvalidData {
+                self.error = Some(e.to_string());
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_string(json: &str) -> io::Result<String> {
+        JsonReader::new(json.as_bytes()).read_string()
+    }
+
+    #[test]
+    fn test_read_string() {
+        assert_eq!(read_string(r#""plain""#).unwrap(), "plain");
+        assert_eq!(
+            read_string(r#" "q\"b\\s\/n\nt\tuAé""#).unwrap(),
+            "q\"b\\s/n\nt\tuAé"
+        );
+        assert_eq!(read_string(r#""😀""#).unwrap(), "\u{1f600}");
+        assert_eq!(read_string("\"юникод\"").unwrap(), "юникод");
+
+        assert!(read_string(r#""unterminated"#).is_err());
+        assert!(read_string(r#""\ud83d""#).is_err());
+        assert!(read_string(r#""\x""#).is_err());
+        assert!(read_string("\"line\nbreak\"").is_err());
+        assert!(read_string("plain").is_err());
+
+        let long = format!("\"{}\"", "x".repeat(MAX_STRING_LENGTH as usize + 1));
+        assert!(read_string(&long).is_err());
+    }
+
+    #[test]
+    fn test_string_reader() {
+        // Tiny buffer makes the reader stop in the middle of the escape sequences.
+        let data = format!("\"{}\\u00e9\\/\" ,", "x".repeat(10000));
+        let mut json = JsonReader::new(io::BufReader::with_capacity(7, data.as_bytes()));
+
+        let mut value = Vec::new();
+        let mut reader = json.string_reader().unwrap();
+        let mut buf = [0; 3];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                n => value.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(value.len(), 10003);
+        assert!(value.ends_with("é/".as_bytes()));
+        json.expect(b',').unwrap();
+
+        // Syntax errors aren't skipped over.
+        let mut json = JsonReader::new(&b"\"ab\x01cd\""[..]);
+        let mut reader = json.string_reader().unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(reader.finish().is_err());
+    }
+
+    #[test]
+    fn test_skip_value() {
+        let mut json = JsonReader::new(
+            &br#"{"a": [1, -2.5e+3, true, false, null, "s\"]"], "b": {"c": {}}} 7"#[..],
+        );
+        json.skip_value().unwrap();
+        json.skip_value().unwrap();
+        json.expect_end().unwrap();
+
+        assert!(JsonReader::new(&b"[1, 2"[..]).skip_value().is_err());
+        assert!(JsonReader::new(&b"[1 2]"[..]).skip_value().is_err());
+        assert!(JsonReader::new(&b"{\"a\" 1}"[..]).skip_value().is_err());
+        assert!(JsonReader::new(&b"nul"[..]).skip_value().is_err());
+        assert!(JsonReader::new("[".repeat(100).as_bytes())
+            .skip_value()
+            .is_err());
+    }
+}
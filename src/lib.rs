@@ -1,4 +1,12 @@
+pub mod data_uri;
 pub mod file_utils;
+pub mod http_client;
 pub mod http_handlers;
+pub mod image_info;
+pub mod jobs;
+pub mod json_stream;
+pub mod listing;
 pub mod microservice;
+pub mod settings;
 pub mod thumbnail;
+pub mod variants;
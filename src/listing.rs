@@ -0,0 +1,386 @@
+use base64;
+use std::cmp::Ordering;
+use std::fs;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::file_utils;
+
+/// Number of images returned in one page if the limit isn't specified.
+pub const DEFAULT_LIMIT: usize = 100;
+/// Maximal number of images returned in one page.
+pub const MAX_LIMIT: usize = 1000;
+
+/// Stored image file: the filename and its metadata.
+pub type ImageFile = (String, fs::Metadata);
+
+/// Images list sorting key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Name,
+    Mtime,
+    Size,
+}
+
+impl SortKey {
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Mtime => "mtime",
+            SortKey::Size => "size",
+        }
+    }
+
+    /// Get the sort value of the image, the filename is compared after it.
+    fn value(self, metadata: &fs::Metadata) -> u128 {
+        match self {
+            SortKey::Name => 0,
+            SortKey::Mtime => metadata
+                .modified()
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |x| x.as_nanos()),
+            SortKey::Size => u128::from(metadata.len()),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "name" => Ok(SortKey::Name),
+            "mtime" => Ok(SortKey::Mtime),
+            "size" => Ok(SortKey::Size),
+            _ => Err(format!("unknown sort key \"{}\"", s)),
+        }
+    }
+}
+
+/// Position in the sorted images list after which the next page starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    sort: SortKey,
+    descending: bool,
+    value: u128,
+    filename: String,
+}
+
+impl Cursor {
+    /// Encode the cursor into an opaque URL-safe string.
+    pub fn encode(&self) -> String {
+        let direction = if self.descending { "desc" } else { "asc" };
+        let cursor = format!(
+            "{}-{}:{}:{}",
+            self.sort.name(),
+            direction,
+            self.value,
+            self.filename
+        );
+        base64::encode_config(&cursor, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decode the cursor from the string made by `Cursor::encode`.
+    pub fn decode(cursor: &str) -> Result<Cursor, String> {
+        let invalid = || format!("invalid cursor \"{}\"", cursor);
+
+        let decoded = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|x| String::from_utf8(x).ok())
+            .ok_or_else(invalid)?;
+
+        let mut parts = decoded.splitn(3, ':');
+        let (order, value, filename) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(order), Some(value), Some(filename)) => (order, value, filename),
+            _ => return Err(invalid()),
+        };
+
+        let (sort, descending) = match order.rfind('-') {
+            Some(i) => match (order[..i].parse::<SortKey>(), &order[i + 1..]) {
+                (Ok(sort), "asc") => (sort, false),
+                (Ok(sort), "desc") => (sort, true),
+                _ => return Err(invalid()),
+            },
+            None => return Err(invalid()),
+        };
+
+        Ok(Cursor {
+            sort,
+            descending,
+            value: value.parse().map_err(|_| invalid())?,
+            filename: filename.to_string(),
+        })
+    }
+}
+
+/// Images list query: the page, its order and the filters.
+#[derive(Clone, Debug)]
+pub struct ListQuery {
+    pub limit: usize,
+    pub sort: SortKey,
+    pub descending: bool,
+    pub cursor: Option<Cursor>,
+    pub prefix: Option<String>,
+    /// Filename pattern with "*" and "?" wildcards.
+    pub glob: Option<String>,
+    /// Content type, "image/*" form is allowed.
+    pub content_type: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        ListQuery {
+            limit: DEFAULT_LIMIT,
+            sort: SortKey::Name,
+            descending: false,
+            cursor: None,
+            prefix: None,
+            glob: None,
+            content_type: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+        }
+    }
+}
+
+impl ListQuery {
+    /// Check whether the image passes the query filters.
+    pub fn matches(&self, filename: &str, metadata: &fs::Metadata) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !filename.starts_with(&prefix[..]) {
+                return false;
+            }
+        }
+
+        if let Some(glob) = &self.glob {
+            if !glob_match(glob, filename) {
+                return false;
+            }
+        }
+
+        if let Some(content_type) = &self.content_type {
+            let actual = file_utils::content_type_for(filename);
+            let matched = if content_type.ends_with("/*") {
+                actual.starts_with(&content_type[..content_type.len() - 1])
+            } else {
+                actual == content_type
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        let size = metadata.len();
+        if matches!(self.min_size, Some(x) if size < x)
+            || matches!(self.max_size, Some(x) if size > x)
+        {
+            return false;
+        }
+
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            let modified = match metadata.modified() {
+                Ok(x) => x,
+                Err(_) => return false,
+            };
+            if matches!(self.modified_after, Some(x) if modified < x)
+                || matches!(self.modified_before, Some(x) if modified > x)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn compare(&self, a: &(u128, &str), b: &(u128, &str)) -> Ordering {
+        let ordering = a.cmp(b);
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Select the page of images matching the query.
+    ///
+    /// Returns the images of the page in the requested order and the cursor of
+    /// the next page if there are more images.
+    pub fn select(
+        &self,
+        images: Vec<ImageFile>,
+    ) -> Result<(Vec<ImageFile>, Option<Cursor>), String> {
+        log::trace!("select({:?}) ...", self);
+
+        if let Some(cursor) = &self.cursor {
+            if cursor.sort != self.sort || cursor.descending != self.descending {
+                return Err(String::from("cursor doesn't match the sort order"));
+            }
+        }
+
+        let mut images: Vec<_> = images
+            .into_iter()
+            .filter(|(filename, metadata)| self.matches(filename, metadata))
+            .map(|(filename, metadata)| (self.sort.value(&metadata), filename, metadata))
+            .collect();
+        images.sort_by(|a, b| self.compare(&(a.0, &a.1), &(b.0, &b.1)));
+
+        let start = match &self.cursor {
+            Some(cursor) => images.partition_point(|x| {
+                self.compare(&(x.0, &x.1), &(cursor.value, &cursor.filename)) != Ordering::Greater
+            }),
+            None => 0,
+        };
+
+        let mut page: Vec<_> = images
+            .into_iter()
+            .skip(start)
+            .take(self.limit + 1)
+            .collect();
+        let next = if page.len() > self.limit {
+            page.truncate(self.limit);
+            page.last().map(|(value, filename, _)| Cursor {
+                sort: self.sort,
+                descending: self.descending,
+                value: *value,
+                filename: filename.clone(),
+            })
+        } else {
+            None
+        };
+
+        let page = page
+            .into_iter()
+            .map(|(_, filename, metadata)| (filename, metadata))
+            .collect();
+        Ok((page, next))
+    }
+}
+
+/// Match the filename against the pattern with "*" (any characters) and "?" (any single
+/// character) wildcards.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::listing;
+///
+///     assert!(listing::glob_match("*.jpg", "photo.jpg"));
+///     assert!(listing::glob_match("img-??.*", "img-01.png"));
+///     assert!(!listing::glob_match("*.jpg", "photo.png"));
+///     assert!(!listing::glob_match("img-?", "img-01"));
+/// ```
+pub fn glob_match(pattern: &str, filename: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let filename: Vec<char> = filename.chars().collect();
+
+    let (mut p, mut f) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while f < filename.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == filename[f]) {
+            p += 1;
+            f += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, f));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            f = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&x| x == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_encode_decode() {
+        let cursor = Cursor {
+            sort: SortKey::Mtime,
+            descending: true,
+            value: 1_560_000_000_000_000_000,
+            filename: String::from("photo:1.jpg"),
+        };
+
+        assert_eq!(Cursor::decode(&cursor.encode()), Ok(cursor));
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&base64::encode("size-up:1:x")).is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-listing-select-w7dk2q");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        let mut images = Vec::new();
+        for (filename, size) in &[("b.jpg", 30), ("a.png", 10), ("c.jpg", 20), ("d.gif", 40)] {
+            tmp_path.push(filename);
+            std::fs::write(&tmp_path, vec![0u8; *size]).unwrap();
+            images.push((filename.to_string(), std::fs::metadata(&tmp_path).unwrap()));
+            tmp_path.pop();
+        }
+        let names = |page: &[ImageFile]| -> Vec<String> {
+            page.iter().map(|(filename, _)| filename.clone()).collect()
+        };
+
+        let mut query = ListQuery {
+            limit: 2,
+            ..ListQuery::default()
+        };
+        let (page, next) = query.select(images.clone()).unwrap();
+        assert_eq!(names(&page), ["a.png", "b.jpg"]);
+        query.cursor = next;
+        let (page, next) = query.select(images.clone()).unwrap();
+        assert_eq!(names(&page), ["c.jpg", "d.gif"]);
+        assert_eq!(next, None);
+
+        let query = ListQuery {
+            sort: SortKey::Size,
+            descending: true,
+            ..ListQuery::default()
+        };
+        let (page, _) = query.select(images.clone()).unwrap();
+        assert_eq!(names(&page), ["d.gif", "b.jpg", "c.jpg", "a.png"]);
+
+        let query = ListQuery {
+            content_type: Some(String::from("image/jpeg")),
+            min_size: Some(25),
+            ..ListQuery::default()
+        };
+        let (page, _) = query.select(images.clone()).unwrap();
+        assert_eq!(names(&page), ["b.jpg"]);
+
+        let query = ListQuery {
+            glob: Some(String::from("*.?if")),
+            ..ListQuery::default()
+        };
+        let (page, _) = query.select(images.clone()).unwrap();
+        assert_eq!(names(&page), ["d.gif"]);
+
+        let mut query = ListQuery {
+            limit: 1,
+            ..ListQuery::default()
+        };
+        let (_, next) = query.select(images.clone()).unwrap();
+        query.cursor = next;
+        query.sort = SortKey::Size;
+        assert!(query.select(images).is_err());
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
 use pretty_env_logger;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 use trlogic_test::microservice;
+use trlogic_test::settings::{
+    self, Collision, DecodeCheck, DownloadSettings, HostRule, PixelLimits, Settings,
+    TypeMismatch,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "TRLogic test microservice", about = "A microservice for images upload.")]
@@ -15,6 +20,89 @@ struct Opt {
     /// Upload path
     #[structopt(short, long, default_value="./uploads/", parse(from_os_str))]
     upload: PathBuf,
+    /// Image sizes allowed for resized variants, as comma-separated WIDTHxHEIGHT list
+    #[structopt(
+        long = "allowed-sizes",
+        default_value = "100x100,320x320,640x640,1280x1280",
+        parse(try_from_str = "settings::parse_size"),
+        raw(use_delimiter = "true")
+    )]
+    allowed_sizes: Vec<(u32, u32)>,
+    /// What to do when an uploaded image has the filename of a stored one: overwrite, reject or rename
+    #[structopt(long = "on-collision", default_value = "overwrite")]
+    on_collision: Collision,
+    /// What to do with uploads not recognized as images of the declared type: reject or flag
+    #[structopt(long = "type-mismatch", default_value = "flag")]
+    type_mismatch: TypeMismatch,
+    /// Decode uploaded images before they are stored: off, headers or full
+    #[structopt(long = "decode-check", default_value = "off")]
+    decode_check: DecodeCheck,
+    /// Maximal size of an uploaded image, K, M or G suffix is allowed
+    #[structopt(
+        long = "max-image-size",
+        default_value = "16M",
+        parse(try_from_str = "settings::parse_bytes")
+    )]
+    max_image_size: u64,
+    /// Maximal number of images in one upload request
+    #[structopt(long = "max-batch-items", default_value = "100")]
+    max_batch_items: usize,
+    /// Maximal size of an upload request body, K, M or G suffix is allowed
+    #[structopt(
+        long = "max-body-size",
+        default_value = "64M",
+        parse(try_from_str = "settings::parse_bytes")
+    )]
+    max_body_size: u64,
+    /// Maximal number of pixels of an image to decode
+    #[structopt(long = "max-pixels", default_value = "50000000")]
+    max_pixels: u64,
+    /// Maximal width of an image to decode
+    #[structopt(long = "max-width", default_value = "16384")]
+    max_width: u64,
+    /// Maximal height of an image to decode
+    #[structopt(long = "max-height", default_value = "16384")]
+    max_height: u64,
+    /// Content types of images accepted for upload, as comma-separated list
+    #[structopt(
+        long = "allowed-types",
+        default_value = "image/jpeg,image/png,image/gif,image/webp",
+        raw(use_delimiter = "true")
+    )]
+    allowed_types: Vec<String>,
+    /// Timeout of the connection to the image origin, in seconds
+    #[structopt(long = "connect-timeout", default_value = "10")]
+    connect_timeout: u64,
+    /// Timeout of reading from the image origin, in seconds
+    #[structopt(long = "read-timeout", default_value = "30")]
+    read_timeout: u64,
+    /// Maximal number of redirects followed while downloading an image
+    #[structopt(long = "max-redirects", default_value = "5")]
+    max_redirects: u32,
+    /// Number of retries of an image download after connection errors and HTTP 5xx responses
+    #[structopt(long = "retries", default_value = "2")]
+    retries: u32,
+    /// Delay before the first retry of an image download, in milliseconds, doubled for every next one
+    #[structopt(long = "retry-delay", default_value = "500")]
+    retry_delay: u64,
+    /// Hosts allowed for image download even with non-public addresses, as comma-separated list of domain names, IP addresses and CIDR networks
+    #[structopt(long = "allow-hosts", raw(use_delimiter = "true"))]
+    allow_hosts: Vec<HostRule>,
+    /// Hosts denied for image download, as comma-separated list of domain names, IP addresses and CIDR networks
+    #[structopt(long = "deny-hosts", raw(use_delimiter = "true"))]
+    deny_hosts: Vec<HostRule>,
+    /// Maximal number of images of one upload request downloaded at once
+    #[structopt(long = "download-concurrency", default_value = "8")]
+    download_concurrency: usize,
+    /// Time limit of all the image downloads of one upload request, in seconds
+    #[structopt(long = "batch-timeout", default_value = "120")]
+    batch_timeout: u64,
+    /// Maximal number of asynchronous upload jobs processed at once
+    #[structopt(long = "max-jobs", default_value = "4")]
+    max_jobs: usize,
+    /// Time the records of finished and interrupted upload jobs are kept for, in seconds
+    #[structopt(long = "job-ttl", default_value = "86400")]
+    job_ttl: u64,
 }
 
 fn main() {
@@ -28,7 +116,37 @@ fn main() {
         panic!("Can't use specified upload path!");
     }
 
-    let (server, _srv_tx, srv_rx) = microservice::init(&opt.host, opt.port, &opt.upload.to_string_lossy());
+    let settings = Settings {
+        allowed_sizes: opt.allowed_sizes,
+        collision: opt.on_collision,
+        type_mismatch: opt.type_mismatch,
+        decode_check: opt.decode_check,
+        max_image_size: opt.max_image_size,
+        max_batch_items: opt.max_batch_items,
+        max_body_size: opt.max_body_size,
+        pixel_limits: PixelLimits {
+            max_pixels: opt.max_pixels,
+            max_width: opt.max_width,
+            max_height: opt.max_height,
+        },
+        allowed_types: opt.allowed_types,
+        download: DownloadSettings {
+            connect_timeout: Duration::from_secs(opt.connect_timeout),
+            read_timeout: Duration::from_secs(opt.read_timeout),
+            max_redirects: opt.max_redirects,
+            retries: opt.retries,
+            retry_delay: Duration::from_millis(opt.retry_delay),
+            allow_hosts: opt.allow_hosts,
+            deny_hosts: opt.deny_hosts,
+            concurrency: opt.download_concurrency,
+            batch_timeout: Duration::from_secs(opt.batch_timeout),
+        },
+        max_jobs: opt.max_jobs,
+        job_ttl: Duration::from_secs(opt.job_ttl),
+    };
+
+    let (server, _srv_tx, srv_rx) =
+        microservice::init(&opt.host, opt.port, &opt.upload.to_string_lossy(), settings);
     microservice::run(server, srv_rx);
 
     log::trace!("main() shutdown.");
@@ -1,8 +1,13 @@
 use rouille;
+use std::path::Path;
 use std::sync::mpsc;
+use super::file_utils;
 use super::http_handlers;
+use super::jobs;
+use super::settings::Settings;
+use super::thumbnail;
 
-pub fn init<'a>(host: &str, port: u16, upload_path: &str) -> (
+pub fn init(host: &str, port: u16, upload_path: &str, settings: Settings) -> (
     rouille::Server<impl Send + Sync + 'static + Fn(&rouille::Request) -> rouille::Response>,
     mpsc::Sender<&'static str>,
     mpsc::Receiver<&'static str>,
@@ -19,12 +24,14 @@ pub fn init<'a>(host: &str, port: u16, upload_path: &str) -> (
         })
     };
 
+    clean_up(Path::new(upload_path), &settings);
+
     log::debug!("Starting web server...");
     {
         let upload_path = String::from(upload_path);
         
         let server = match rouille::Server::new(format!("{}:{}", host, port), move |request| {
-            http_handlers::route(&request, &upload_path)
+            http_handlers::route(request, &upload_path, &settings)
         }) {
             Ok(x) => x,
 
@@ -38,6 +45,29 @@ pub fn init<'a>(host: &str, port: u16, upload_path: &str) -> (
     }
 }
 
+/// Remove the files left by the previous runs: temporary files of the uploads, thumbnails
+/// and job records interrupted by the stop, and the expired job records.
+fn clean_up(upload_path: &Path, settings: &Settings) {
+    let dirs = [
+        upload_path.to_path_buf(),
+        jobs::dir_for(upload_path),
+        thumbnail::dir_for(upload_path),
+    ];
+    for dir in &dirs {
+        if let Err(e) = file_utils::remove_temp_files(dir) {
+            log::warn!(
+                "Can't remove temporary files from {}! {}",
+                dir.display(),
+                e.to_string()
+            );
+        }
+    }
+
+    if let Err(e) = jobs::remove_expired(upload_path, settings.job_ttl) {
+        log::warn!("Can't remove expired jobs! {}", e.to_string());
+    }
+}
+
 pub fn run(
     server: rouille::Server<
         impl Send + Sync + 'static + Fn(&rouille::Request) -> rouille::Response,
@@ -0,0 +1,431 @@
+use serde_derive::Deserialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What to do when an uploaded image has the same filename as a stored one.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Collision {
+    /// Replace the stored image.
+    Overwrite,
+    /// Fail the upload.
+    Reject,
+    /// Store the image under the filename with "-1", "-2", ... suffix.
+    Rename,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "overwrite" => Ok(Collision::Overwrite),
+            "reject" => Ok(Collision::Reject),
+            "rename" => Ok(Collision::Rename),
+            _ => Err(format!(
+                "unknown collision policy \"{}\", overwrite, reject or rename expected",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when the uploaded data isn't an image of the declared type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TypeMismatch {
+    /// Fail the upload.
+    Reject,
+    /// Store the image with the detected type, reporting a warning.
+    Flag,
+}
+
+impl FromStr for TypeMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "reject" => Ok(TypeMismatch::Reject),
+            "flag" => Ok(TypeMismatch::Flag),
+            _ => Err(format!(
+                "unknown type mismatch policy \"{}\", reject or flag expected",
+                s
+            )),
+        }
+    }
+}
+
+/// How uploaded images are decoded before they are stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeCheck {
+    /// Store the data as is.
+    Off,
+    /// Decode the image headers only.
+    Headers,
+    /// Decode the whole image.
+    Full,
+}
+
+impl FromStr for DecodeCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "off" => Ok(DecodeCheck::Off),
+            "headers" => Ok(DecodeCheck::Headers),
+            "full" => Ok(DecodeCheck::Full),
+            _ => Err(format!(
+                "unknown decode check \"{}\", off, headers or full expected",
+                s
+            )),
+        }
+    }
+}
+
+/// Image dimension limits checked by the image headers before the image is decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelLimits {
+    pub max_pixels: u64,
+    pub max_width: u64,
+    pub max_height: u64,
+}
+
+impl Default for PixelLimits {
+    fn default() -> Self {
+        PixelLimits {
+            max_pixels: 50_000_000,
+            max_width: 16384,
+            max_height: 16384,
+        }
+    }
+}
+
+impl PixelLimits {
+    /// Check the image dimensions against the limits.
+    ///
+    /// Returns the reason if the image is too large to be decoded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::settings::PixelLimits;
+    ///
+    ///     let limits = PixelLimits::default();
+    ///     assert!(limits.check(4000, 3000).is_ok());
+    ///     assert!(limits.check(50000, 50000).is_err());
+    ///     assert!(limits.check(10000, 10000).is_err());
+    /// ```
+    pub fn check(&self, width: u64, height: u64) -> Result<(), String> {
+        if width > self.max_width || height > self.max_height {
+            Err(format!(
+                "image dimensions {}x{} exceed {}x{} limit",
+                width, height, self.max_width, self.max_height
+            ))
+        } else if width.saturating_mul(height) > self.max_pixels {
+            Err(format!(
+                "image dimensions {}x{} exceed {} pixels limit",
+                width, height, self.max_pixels
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Host rule of the image download allow and deny lists.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostRule {
+    /// Domain name, "*.example.com" matches the subdomains of "example.com".
+    Domain(String),
+    /// IP network: the address and the prefix length, single address is a full length network.
+    Network(IpAddr, u8),
+}
+
+impl HostRule {
+    /// Check whether the domain name matches the rule, names are case-insensitive.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::settings::HostRule;
+    ///
+    ///     let rule: HostRule = "*.example.com".parse().unwrap();
+    ///     assert!(rule.matches_domain("img.EXAMPLE.com."));
+    ///     assert!(!rule.matches_domain("example.com"));
+    ///     assert!(!rule.matches_domain("badexample.com"));
+    /// ```
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        match self {
+            HostRule::Domain(x) if x.starts_with("*.") => domain.ends_with(&x[1..]),
+            HostRule::Domain(x) => domain == *x,
+            HostRule::Network(..) => false,
+        }
+    }
+
+    /// Check whether the IP address matches the rule.
+    ///
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 ones.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::settings::HostRule;
+    ///
+    ///     let rule: HostRule = "10.0.0.0/8".parse().unwrap();
+    ///     assert!(rule.matches_addr("10.1.2.3".parse().unwrap()));
+    ///     assert!(rule.matches_addr("::ffff:10.1.2.3".parse().unwrap()));
+    ///     assert!(!rule.matches_addr("11.1.2.3".parse().unwrap()));
+    /// ```
+    pub fn matches_addr(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(x) => x.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            x => x,
+        };
+        match (self, addr) {
+            (HostRule::Network(IpAddr::V4(net), len), IpAddr::V4(x)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*len)).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(x) & mask
+            }
+            (HostRule::Network(IpAddr::V6(net), len), IpAddr::V6(x)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*len)).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(x) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = String;
+
+    /// Parse the rule: IP address, network in CIDR notation or domain name.
+    ///
+    /// Internationalized domain names are converted to the ASCII form used in URLs.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::settings::HostRule;
+    ///
+    ///     let rule: HostRule = "*.Пример.рф".parse().unwrap();
+    ///     assert_eq!(rule, HostRule::Domain(String::from("*.xn--e1afmkfd.xn--p1ai")));
+    ///     assert!(rule.matches_domain("img.xn--e1afmkfd.xn--p1ai"));
+    ///     assert!("пример.рф".parse::<HostRule>().is_ok());
+    ///     assert!("*".parse::<HostRule>().is_err());
+    ///     assert!("img.*.com".parse::<HostRule>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            format!(
+                "invalid host rule \"{}\", domain name, IP address or network expected",
+                s
+            )
+        };
+
+        let (addr, len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (addr.parse::<IpAddr>(), len) {
+            (Ok(addr), len) => {
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let len = match len {
+                    Some(x) => x.parse::<u8>().map_err(|_| invalid())?,
+                    None => max,
+                };
+                if len > max {
+                    return Err(invalid());
+                }
+                Ok(HostRule::Network(addr, len))
+            }
+            (Err(_), None) => {
+                let (wildcard, domain) = match s.strip_prefix("*.") {
+                    Some(x) => ("*.", x),
+                    None => ("", s),
+                };
+                let domain = domain.trim_end_matches('.');
+                if domain.is_empty() || domain.contains('*') {
+                    return Err(invalid());
+                }
+                let domain = url::idna::domain_to_ascii(domain).map_err(|_| invalid())?;
+                Ok(HostRule::Domain(format!("{}{}", wildcard, domain)))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Settings of the image downloads by URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownloadSettings {
+    /// Timeout of the connection to the origin.
+    pub connect_timeout: Duration,
+    /// Timeout of every read from and write to the origin connection.
+    pub read_timeout: Duration,
+    /// Maximal number of redirects followed.
+    pub max_redirects: u32,
+    /// Number of retries after connection errors and HTTP 5xx responses.
+    pub retries: u32,
+    /// Delay before the first retry, it's doubled for every next one.
+    pub retry_delay: Duration,
+    /// Hosts allowed for download even if they have loopback, private or other
+    /// non-public addresses.
+    pub allow_hosts: Vec<HostRule>,
+    /// Hosts denied for download, the deny list takes precedence over the allow list.
+    pub deny_hosts: Vec<HostRule>,
+    /// Maximal number of images of one upload request downloaded at once.
+    pub concurrency: usize,
+    /// Time limit of all the downloads of one upload request.
+    pub batch_timeout: Duration,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        DownloadSettings {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            retries: 2,
+            retry_delay: Duration::from_millis(500),
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            concurrency: 8,
+            batch_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Microservice runtime settings.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Image sizes (width, height) allowed for on-demand resized variants.
+    pub allowed_sizes: Vec<(u32, u32)>,
+    /// Default filename collision policy for uploads.
+    pub collision: Collision,
+    /// Policy for uploads with unrecognized data or data of other type than declared.
+    pub type_mismatch: TypeMismatch,
+    /// Uploaded images decoding, undecodable images aren't stored unless it's off.
+    pub decode_check: DecodeCheck,
+    /// Maximal size of an uploaded image in bytes.
+    pub max_image_size: u64,
+    /// Maximal number of images in one upload request.
+    pub max_batch_items: usize,
+    /// Maximal size of an upload request body in bytes.
+    pub max_body_size: u64,
+    /// Limits of the image dimensions accepted for upload and decoding.
+    pub pixel_limits: PixelLimits,
+    /// Content types of the images accepted for upload, both declared and detected.
+    pub allowed_types: Vec<String>,
+    /// Settings of the image downloads by URL.
+    pub download: DownloadSettings,
+    /// Maximal number of upload jobs processed in the background at once.
+    pub max_jobs: usize,
+    /// Time the records of the finished and interrupted upload jobs are kept for.
+    pub job_ttl: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            allowed_sizes: vec![(100, 100), (320, 320), (640, 640), (1280, 1280)],
+            collision: Collision::Overwrite,
+            type_mismatch: TypeMismatch::Flag,
+            decode_check: DecodeCheck::Off,
+            max_image_size: 16 << 20,
+            max_batch_items: 100,
+            max_body_size: 64 << 20,
+            pixel_limits: PixelLimits::default(),
+            allowed_types: vec![
+                String::from("image/jpeg"),
+                String::from("image/png"),
+                String::from("image/gif"),
+                String::from("image/webp"),
+            ],
+            download: DownloadSettings::default(),
+            max_jobs: 4,
+            job_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl Settings {
+    /// Check whether images of the content type are accepted for upload.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    ///     use trlogic_test::settings::Settings;
+    ///
+    ///     let settings = Settings::default();
+    ///     assert!(settings.allows_type("image/PNG"));
+    ///     assert!(!settings.allows_type("image/svg+xml"));
+    ///     assert!(!settings.allows_type("image/*"));
+    /// ```
+    pub fn allows_type(&self, content_type: &str) -> bool {
+        self.allowed_types
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(content_type))
+    }
+}
+
+/// Parse image size specified in "WIDTHxHEIGHT" form.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::settings;
+///
+///     assert_eq!(settings::parse_size("320x240"), Ok((320, 240)));
+///     assert!(settings::parse_size("320").is_err());
+///     assert!(settings::parse_size("0x240").is_err());
+/// ```
+pub fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    let pair: Vec<&str> = size.trim().split(&['x', 'X'][..]).collect();
+    if pair.len() == 2 {
+        if let (Ok(width), Ok(height)) = (pair[0].parse::<u32>(), pair[1].parse::<u32>()) {
+            if width > 0 && height > 0 {
+                return Ok((width, height));
+            }
+        }
+    }
+
+    Err(format!(
+        "invalid image size \"{}\", WIDTHxHEIGHT expected",
+        size
+    ))
+}
+
+/// Parse size in bytes with optional "K", "M" or "G" binary multiple suffix.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::settings;
+///
+///     assert_eq!(settings::parse_bytes("1024"), Ok(1024));
+///     assert_eq!(settings::parse_bytes("16M"), Ok(16 * 1024 * 1024));
+///     assert_eq!(settings::parse_bytes("2k"), Ok(2048));
+///     assert!(settings::parse_bytes("16MB").is_err());
+/// ```
+pub fn parse_bytes(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, shift) = match size.chars().last().map(|x| x.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(1 << shift))
+        .ok_or_else(|| {
+            format!(
+                "invalid size \"{}\", number of bytes with optional K, M or G suffix expected",
+                size
+            )
+        })
+}
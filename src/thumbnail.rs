@@ -1,14 +1,144 @@
 use fs2::FileExt;
 use image;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-pub fn make(file_path: &str) {
+use super::file_utils;
+use super::image_info::ImageInfo;
+use super::settings::PixelLimits;
+
+lazy_static! {
+    static ref PENDING: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    static ref FAILED: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    static ref CANCELLED: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// State of the thumbnail for a stored image.
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// Thumbnail is generated and stored at the path.
+    Ready(PathBuf),
+    /// Thumbnail generation is in progress.
+    Pending,
+    /// Thumbnail generation has failed, i.e. the image can't be decoded.
+    Failed,
+    /// There is no thumbnail and no attempt to generate it was made.
+    Missing,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Ready(_) => "ready",
+            Status::Pending => "pending",
+            Status::Failed => "failed",
+            Status::Missing => "missing",
+        }
+    }
+}
+
+/// Get the directory where the thumbnails for the images of the upload directory are stored.
+pub fn dir_for(upload_path: &Path) -> PathBuf {
+    upload_path.join("thumbnails")
+}
+
+/// Get the path where the thumbnail for the image is stored.
+pub fn path_for(file_path: &Path) -> PathBuf {
+    let mut thumbnail_path = file_path.to_path_buf();
+    let file = thumbnail_path
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    thumbnail_path.pop();
+    let mut thumbnail_path = dir_for(&thumbnail_path);
+    thumbnail_path.push(file);
+    thumbnail_path
+}
+
+/// Get the state of the thumbnail for the image.
+pub fn status(file_path: &Path) -> Status {
+    if PENDING.lock().unwrap().contains(file_path) {
+        return Status::Pending;
+    }
+
+    let thumbnail_path = path_for(file_path);
+    if thumbnail_path.is_file() {
+        Status::Ready(thumbnail_path)
+    } else if FAILED.lock().unwrap().contains(file_path) {
+        Status::Failed
+    } else {
+        Status::Missing
+    }
+}
+
+/// Remove the thumbnail of the image.
+///
+/// The pending thumbnail generation is cancelled, the thumbnail is removed as soon
+/// as it's generated.
+pub fn remove(file_path: &Path) -> io::Result<()> {
+    log::trace!("remove(\"{}\") ...", file_path.display());
+
+    FAILED.lock().unwrap().remove(file_path);
+
+    let pending = PENDING.lock().unwrap();
+    if pending.contains(file_path) {
+        CANCELLED.lock().unwrap().insert(file_path.to_path_buf());
+    }
+
+    match fs::remove_file(path_for(file_path)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Generate the thumbnail for the image in a background thread.
+///
+/// The thumbnail is reported as pending until the generation is complete.
+pub fn spawn(file_path: PathBuf, limits: PixelLimits) {
+    log::trace!("spawn(\"{}\") ...", file_path.display());
+
+    {
+        let mut pending = PENDING.lock().unwrap();
+        pending.insert(file_path.clone());
+        CANCELLED.lock().unwrap().remove(&file_path);
+    }
+
+    std::thread::spawn(move || {
+        let success = make(&file_path.to_string_lossy(), &limits);
+
+        let mut pending = PENDING.lock().unwrap();
+        if CANCELLED.lock().unwrap().remove(&file_path) {
+            log::debug!(
+                "Thumbnail of removed image {} is discarded",
+                file_path.display()
+            );
+            let _ = fs::remove_file(path_for(&file_path));
+        } else if success {
+            FAILED.lock().unwrap().remove(&file_path);
+        } else {
+            FAILED.lock().unwrap().insert(file_path.clone());
+        }
+        pending.remove(&file_path);
+    });
+}
+
+/// Generate the thumbnail for the image.
+///
+/// Images with the dimensions over the limits, or the dimensions that can't be read,
+/// aren't decoded. The thumbnail is replaced atomically. Returns whether the thumbnail
+/// is generated.
+pub fn make(file_path: &str, limits: &PixelLimits) -> bool {
     log::trace!("make(\"{}\") ...", &file_path);
 
-    let mut file_path: PathBuf = file_path.into();
+    let file_path: PathBuf = file_path.into();
 
-    let img = {
+    // The image is read under a shared lock, the lock is released before decoding
+    // so the image can be removed meanwhile.
+    let buffer = {
         let file = fs::OpenOptions::new().read(true).open(&file_path);
         if let Err(e) = &file {
             log::warn!(
@@ -16,69 +146,121 @@ pub fn make(file_path: &str) {
                 e.to_string(),
                 &file_path.to_string_lossy()
             );
-            return;
+            return false;
         }
-        let file = file.unwrap();
+        let mut file = file.unwrap();
 
-        let lock = file.lock_shared();
+        let lock = FileExt::lock_shared(&file);
         if let Err(e) = &lock {
             log::warn!(
                 "I/O ERROR \"{}\" while attempt to place shared lock on {} file!",
                 e.to_string(),
                 &file_path.to_string_lossy()
             );
-            return;
+            return false;
         }
 
-        if let Ok(data) = image::open(&file_path) {
-            let lock = file.unlock();
-            if let Err(e) = &lock {
-                log::warn!(
-                    "I/O ERROR \"{}\" while attempt to free shared lock on {} file!",
-                    e.to_string(),
-                    &file_path.to_string_lossy()
-                );
-            }
-            data
-        } else {
-            let lock = file.unlock();
-            if let Err(e) = &lock {
+        let mut buffer = Vec::new();
+        let read = file.read_to_end(&mut buffer);
+        if let Err(e) = &read {
+            log::warn!(
+                "I/O ERROR \"{}\" while reading {} file!",
+                e.to_string(),
+                &file_path.to_string_lossy()
+            );
+        }
+
+        let lock = FileExt::unlock(&file);
+        if let Err(e) = &lock {
+            log::warn!(
+                "I/O ERROR \"{}\" while attempt to free shared lock on {} file!",
+                e.to_string(),
+                &file_path.to_string_lossy()
+            );
+        }
+
+        if read.is_err() {
+            return false;
+        }
+        buffer
+    };
+
+    let format = match ImageInfo::read(io::Cursor::new(&buffer)) {
+        Ok(info) => match limits.check(info.width, info.height) {
+            Ok(()) => info.format,
+            Err(reason) => {
                 log::warn!(
-                    "I/O ERROR \"{}\" while attempt to free shared lock on {} file!",
-                    e.to_string(),
-                    &file_path.to_string_lossy()
+                    "Image {} isn't decoded for thumbnail, {}!",
+                    &file_path.to_string_lossy(),
+                    reason
                 );
+                return false;
             }
-            return;
+        },
+        Err(e) => {
+            log::warn!(
+                "Image {} isn't decoded for thumbnail, can't read dimensions: {}!",
+                &file_path.to_string_lossy(),
+                e
+            );
+            return false;
         }
     };
 
-    let thumbnail = img.resize_to_fill(100, 100, image::FilterType::Lanczos3);
+    let img = match image::load_from_memory_with_format(&buffer, format) {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!(
+                "Image {} isn't decoded for thumbnail: {}!",
+                &file_path.to_string_lossy(),
+                e
+            );
+            return false;
+        }
+    };
 
-    let file = file_path.file_name().unwrap().to_os_string();
-    file_path.pop();
-    file_path.push("thumbnails");
+    let thumbnail = img.resize_to_fill(100, 100, image::FilterType::Lanczos3);
 
-    match fs::create_dir_all(&file_path) {
-        Ok(_) => file_path.push(file),
+    let thumbnail_path = path_for(&file_path);
 
-        Err(e) => {
+    if let Some(thumbnails_dir) = thumbnail_path.parent() {
+        if let Err(e) = fs::create_dir_all(thumbnails_dir) {
             log::warn!(
                 "I/O ERROR \"{}\" while attempt to create directory {}!",
                 e.to_string(),
-                &file_path.to_string_lossy()
+                &thumbnails_dir.to_string_lossy()
             );
-            return;
+            return false;
         }
     }
 
-    if let Err(e) = thumbnail.save(&file_path) {
+    // The thumbnail is written to a temporary file and then renamed, so it's never
+    // served partially written. The extension is kept, it selects the image format.
+    let mut tmp_path = file_utils::temp_path_for(&thumbnail_path).into_os_string();
+    if let Some(ext) = thumbnail_path.extension() {
+        tmp_path.push(".");
+        tmp_path.push(ext);
+    }
+    let tmp_path = PathBuf::from(tmp_path);
+
+    // Thumbnails of the images removed while decoding aren't stored.
+    let saved = thumbnail.save(&tmp_path).and_then(|_| {
+        if file_path.is_file() {
+            fs::rename(&tmp_path, &thumbnail_path)
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound, "image is removed"))
+        }
+    });
+    if let Err(e) = saved {
         log::warn!(
             "I/O ERROR \"{}\" while saving thumbnail to file {}!",
             e.to_string(),
-            &file_path.to_string_lossy()
+            &thumbnail_path.to_string_lossy()
         );
+        let _ = fs::remove_file(&tmp_path);
+        return false;
     }
 
-    log::debug!("make => {}", file_path.to_string_lossy());
+    log::debug!("make => {}", thumbnail_path.to_string_lossy());
+    true
 }
@@ -0,0 +1,553 @@
+use image;
+use image::{GenericImageView, ImageError};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::file_utils;
+use super::image_info::ImageInfo;
+use super::settings::PixelLimits;
+
+/// How the image is fitted into the requested size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    /// Stretch to the exact size ignoring the aspect ratio.
+    Fill,
+    /// Scale preserving the aspect ratio to fit within the size.
+    Contain,
+    /// Scale preserving the aspect ratio to cover the size, cropping the excess.
+    Cover,
+}
+
+impl Fit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fit::Fill => "fill",
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        }
+    }
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "fill" => Ok(Fit::Fill),
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            _ => Err(format!("unknown fit \"{}\"", s)),
+        }
+    }
+}
+
+/// Resampling filter used for resizing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Filter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::Nearest => "nearest",
+            Filter::Triangle => "triangle",
+            Filter::CatmullRom => "catmullrom",
+            Filter::Gaussian => "gaussian",
+            Filter::Lanczos3 => "lanczos3",
+        }
+    }
+
+    fn filter_type(self) -> image::FilterType {
+        match self {
+            Filter::Nearest => image::FilterType::Nearest,
+            Filter::Triangle => image::FilterType::Triangle,
+            Filter::CatmullRom => image::FilterType::CatmullRom,
+            Filter::Gaussian => image::FilterType::Gaussian,
+            Filter::Lanczos3 => image::FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "catmullrom" => Ok(Filter::CatmullRom),
+            "gaussian" => Ok(Filter::Gaussian),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => Err(format!("unknown filter \"{}\"", s)),
+        }
+    }
+}
+
+/// Output image format of the variant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+    Ico,
+}
+
+impl Format {
+    /// Get the format the image with the specified filename is stored in,
+    /// formats without encoder support are reported as PNG.
+    pub fn for_filename(filename: &str) -> Format {
+        match file_utils::content_type_for(filename) {
+            "image/jpeg" => Format::Jpeg,
+            "image/gif" => Format::Gif,
+            "image/bmp" => Format::Bmp,
+            "image/vnd.microsoft.icon" => Format::Ico,
+            _ => Format::Png,
+        }
+    }
+
+    pub fn ext(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Gif => "gif",
+            Format::Bmp => "bmp",
+            Format::Ico => "ico",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "png" => Ok(Format::Png),
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "gif" => Ok(Format::Gif),
+            "bmp" => Ok(Format::Bmp),
+            "ico" => Ok(Format::Ico),
+            _ => Err(format!("unknown format \"{}\"", s)),
+        }
+    }
+}
+
+/// JPEG encoding quality levels the variants are made with, so the number of cached
+/// variants of the image is limited.
+pub const QUALITY_LEVELS: [u8; 5] = [25, 50, 75, 90, 100];
+
+/// Round the JPEG encoding quality up to the nearest of `QUALITY_LEVELS`.
+///
+/// # Examples
+///
+/// ```rust
+///     use trlogic_test::variants;
+///
+///     assert_eq!(variants::quality_level(1), 25);
+///     assert_eq!(variants::quality_level(75), 75);
+///     assert_eq!(variants::quality_level(80), 90);
+///     assert_eq!(variants::quality_level(100), 100);
+/// ```
+pub fn quality_level(quality: u8) -> u8 {
+    QUALITY_LEVELS
+        .iter()
+        .copied()
+        .find(|&x| x >= quality)
+        .unwrap_or(100)
+}
+
+/// Parameters of resizing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resize {
+    pub width: u32,
+    pub height: u32,
+    pub fit: Fit,
+    pub filter: Filter,
+}
+
+/// Parameters of the derived image variant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    /// Resize parameters, the image is kept in original size if not specified.
+    pub resize: Option<Resize>,
+    /// Output format, the format of the original is kept if not specified.
+    pub format: Option<Format>,
+    /// JPEG encoding quality, one of `QUALITY_LEVELS`.
+    pub quality: u8,
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Variant {
+            resize: None,
+            format: None,
+            quality: 90,
+        }
+    }
+}
+
+impl Variant {
+    /// Get the output format of the variant for the image with the specified filename.
+    pub fn format_for(&self, filename: &str) -> Format {
+        self.format
+            .unwrap_or_else(|| Format::for_filename(filename))
+    }
+
+    /// Get the cache key unique for the variant parameters and the output format.
+    pub fn key(&self, format: Format) -> String {
+        let mut key = match &self.resize {
+            Some(resize) => format!(
+                "{}x{}-{}-{}",
+                resize.width,
+                resize.height,
+                resize.fit.name(),
+                resize.filter.name()
+            ),
+            None => String::from("original"),
+        };
+
+        if format == Format::Jpeg {
+            key.push_str(&format!("-q{}", self.quality));
+        }
+
+        key
+    }
+}
+
+/// Get the path where the variant of the image is cached.
+///
+/// Variants are cached in "variants/{filename}/" directory next to the image,
+/// the filename of the variant is made of its parameters and the output format.
+///
+/// # Examples
+///
+/// ```rust
+///     use std::path::Path;
+///     use trlogic_test::variants::{self, Filter, Fit, Format, Resize, Variant};
+///
+///     let variant = Variant {
+///         resize: Some(Resize { width: 320, height: 240, fit: Fit::Cover, filter: Filter::Lanczos3 }),
+///         ..Variant::default()
+///     };
+///     assert_eq!(
+///         variants::path_for(Path::new("uploads/photo.jpg"), &variant),
+///         Path::new("uploads/variants/photo.jpg/320x240-cover-lanczos3-q90.jpg")
+///     );
+///     assert_eq!(
+///         variants::path_for(Path::new("uploads/photo.tif"), &variant),
+///         Path::new("uploads/variants/photo.tif/320x240-cover-lanczos3.png")
+///     );
+///
+///     let variant = Variant { format: Some(Format::Gif), ..Variant::default() };
+///     assert_eq!(
+///         variants::path_for(Path::new("uploads/photo.jpg"), &variant),
+///         Path::new("uploads/variants/photo.jpg/original.gif")
+///     );
+/// ```
+pub fn path_for(file_path: &Path, variant: &Variant) -> PathBuf {
+    let filename = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let format = variant.format_for(&filename);
+
+    let mut variant_path = dir_for(file_path);
+    variant_path.push(format!("{}.{}", variant.key(format), format.ext()));
+    variant_path
+}
+
+/// Get the directory where all variants of the image are cached.
+pub fn dir_for(file_path: &Path) -> PathBuf {
+    let mut variants_dir = file_path.to_path_buf();
+    let filename = variants_dir.file_name().unwrap_or_default().to_os_string();
+    variants_dir.pop();
+    variants_dir.push("variants");
+    variants_dir.push(filename);
+    variants_dir
+}
+
+/// Maximal width and height of the ICO image, larger images can't be encoded.
+pub const MAX_ICO_SIZE: u32 = 256;
+
+fn output_format(format: Format, quality: u8) -> image::ImageOutputFormat {
+    match format {
+        Format::Png => image::ImageOutputFormat::PNG,
+        Format::Jpeg => image::ImageOutputFormat::JPEG(quality),
+        Format::Gif => image::ImageOutputFormat::GIF,
+        Format::Bmp => image::ImageOutputFormat::BMP,
+        Format::Ico => image::ImageOutputFormat::ICO,
+    }
+}
+
+/// Remove all cached variants of the image.
+pub fn remove(file_path: &Path) -> io::Result<()> {
+    log::trace!("remove(\"{}\") ...", file_path.display());
+
+    match fs::remove_dir_all(dir_for(file_path)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Make the variant of the image or reuse the cached one.
+///
+/// Decodes the image, resizes and re-encodes it to the requested format.
+/// ICO variants are converted to RGBA and shrunk to fit `MAX_ICO_SIZE` preserving
+/// the aspect ratio.
+/// Images with the dimensions over the limits, or the dimensions that can't be read,
+/// aren't decoded.
+/// Returns the path of the cached variant, the variant is regenerated if the image
+/// was modified after it was cached. The image is read under a shared lock,
+/// the variant is written to a temporary file and then renamed, so readers never
+/// see a partially written variant.
+pub fn make(
+    file_path: &Path,
+    variant: &Variant,
+    limits: &PixelLimits,
+) -> Result<PathBuf, ImageError> {
+    log::trace!("make(\"{}\", {:?}) ...", file_path.display(), variant);
+
+    let variant_path = path_for(file_path, variant);
+
+    if is_fresh(file_path, &variant_path) {
+        log::debug!("make => {} (cached)", variant_path.display());
+        return Ok(variant_path);
+    }
+
+    let img = {
+        let mut buffer = Vec::new();
+        file_utils::open_image_data(file_path)?.read_to_end(&mut buffer)?;
+        // Images with the dimensions that can't be checked aren't decoded.
+        let info = ImageInfo::read(io::Cursor::new(&buffer))?;
+        if let Err(reason) = limits.check(info.width, info.height) {
+            log::warn!(
+                "Image {} isn't decoded for variant, {}!",
+                file_path.display(),
+                reason
+            );
+            return Err(ImageError::DimensionError);
+        }
+        image::load_from_memory_with_format(&buffer, info.format)?
+    };
+
+    let img = match &variant.resize {
+        Some(resize) => {
+            let (width, height) = (resize.width, resize.height);
+            let filter = resize.filter.filter_type();
+            match resize.fit {
+                Fit::Fill => img.resize_exact(width, height, filter),
+                Fit::Contain => img.resize(width, height, filter),
+                Fit::Cover => img.resize_to_fill(width, height, filter),
+            }
+        }
+        None => img,
+    };
+
+    let format = variant.format_for(&file_path.file_name().unwrap_or_default().to_string_lossy());
+
+    let img = if format == Format::Ico {
+        let img = if img.width() > MAX_ICO_SIZE || img.height() > MAX_ICO_SIZE {
+            let filter = variant
+                .resize
+                .as_ref()
+                .map_or(Filter::Triangle, |x| x.filter);
+            img.resize(MAX_ICO_SIZE, MAX_ICO_SIZE, filter.filter_type())
+        } else {
+            img
+        };
+        // PNG images in ICO are expected to be RGBA.
+        image::DynamicImage::ImageRgba8(img.to_rgba())
+    } else {
+        img
+    };
+
+    if let Some(variants_dir) = variant_path.parent() {
+        fs::create_dir_all(variants_dir)?;
+    }
+
+    let tmp_path = file_utils::temp_path_for(&variant_path);
+
+    let result = fs::File::create(&tmp_path)
+        .map_err(ImageError::from)
+        .and_then(|file| {
+            let mut writer = io::BufWriter::new(file);
+            img.write_to(&mut writer, output_format(format, variant.quality))?;
+            writer.flush().map_err(ImageError::from)
+        })
+        .and_then(|_| fs::rename(&tmp_path, &variant_path).map_err(ImageError::from));
+
+    if let Err(e) = &result {
+        log::warn!(
+            "ERROR \"{}\" while saving image variant to file {}!",
+            e,
+            variant_path.display()
+        );
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    log::debug!("make => {}", variant_path.display());
+    Ok(variant_path)
+}
+
+fn is_fresh(file_path: &Path, variant_path: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+
+    match (modified(file_path), modified(variant_path)) {
+        (Some(image_time), Some(variant_time)) => variant_time >= image_time,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, Fit, Format, ImageError, PixelLimits, Resize, Variant};
+    use image::GenericImageView;
+
+    #[test]
+    fn test_variant_params_from_str() {
+        assert_eq!("fill".parse::<Fit>(), Ok(Fit::Fill));
+        assert_eq!("Contain".parse::<Fit>(), Ok(Fit::Contain));
+        assert_eq!("cover".parse::<Fit>(), Ok(Fit::Cover));
+        assert!("stretch".parse::<Fit>().is_err());
+
+        assert_eq!("nearest".parse::<Filter>(), Ok(Filter::Nearest));
+        assert_eq!("triangle".parse::<Filter>(), Ok(Filter::Triangle));
+        assert_eq!("catmullrom".parse::<Filter>(), Ok(Filter::CatmullRom));
+        assert_eq!("gaussian".parse::<Filter>(), Ok(Filter::Gaussian));
+        assert_eq!("LANCZOS3".parse::<Filter>(), Ok(Filter::Lanczos3));
+        assert!("bicubic".parse::<Filter>().is_err());
+
+        assert_eq!("png".parse::<Format>(), Ok(Format::Png));
+        assert_eq!("jpeg".parse::<Format>(), Ok(Format::Jpeg));
+        assert_eq!("JPG".parse::<Format>(), Ok(Format::Jpeg));
+        assert_eq!("gif".parse::<Format>(), Ok(Format::Gif));
+        assert_eq!("bmp".parse::<Format>(), Ok(Format::Bmp));
+        assert_eq!("ico".parse::<Format>(), Ok(Format::Ico));
+        assert!("webp".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_make() {
+        let mut tmp_path = std::env::temp_dir();
+        tmp_path.push("test-variants-kdjf73");
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        std::fs::create_dir_all(&tmp_path).unwrap();
+
+        tmp_path.push("test.png");
+        image::DynamicImage::new_rgb8(200, 100)
+            .save(&tmp_path)
+            .unwrap();
+
+        let limits = PixelLimits::default();
+        let mut resize = Resize {
+            width: 50,
+            height: 50,
+            fit: Fit::Contain,
+            filter: Filter::Triangle,
+        };
+        let mut variant = Variant {
+            resize: Some(resize.clone()),
+            ..Variant::default()
+        };
+
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert!(variant_path.ends_with("variants/test.png/50x50-contain-triangle.png"));
+        assert_eq!(image::open(&variant_path).unwrap().dimensions(), (50, 25));
+        let cached_time = std::fs::metadata(&variant_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        let reused_time = std::fs::metadata(&variant_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(cached_time, reused_time);
+
+        resize.fit = Fit::Cover;
+        variant.resize = Some(resize.clone());
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert_eq!(image::open(&variant_path).unwrap().dimensions(), (50, 50));
+
+        resize.fit = Fit::Fill;
+        variant.resize = Some(resize.clone());
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert_eq!(image::open(&variant_path).unwrap().dimensions(), (50, 50));
+
+        let variant = Variant {
+            format: Some(Format::Jpeg),
+            quality: 75,
+            ..Variant::default()
+        };
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert!(variant_path.ends_with("variants/test.png/original-q75.jpg"));
+        let img = image::open(&variant_path).unwrap();
+        assert_eq!(img.dimensions(), (200, 100));
+
+        let variant = Variant {
+            format: Some(Format::Gif),
+            ..Variant::default()
+        };
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert!(variant_path.ends_with("variants/test.png/original.gif"));
+        assert!(image::open(&variant_path).is_ok());
+
+        // ICO variants of the large images are shrunk to the maximal ICO size.
+        tmp_path.pop();
+        tmp_path.push("large.png");
+        image::DynamicImage::new_rgb8(300, 600)
+            .save(&tmp_path)
+            .unwrap();
+        let variant = Variant {
+            format: Some(Format::Ico),
+            ..Variant::default()
+        };
+        let variant_path = super::make(&tmp_path, &variant, &limits).unwrap();
+        assert!(variant_path.ends_with("variants/large.png/original.ico"));
+        assert_eq!(image::open(&variant_path).unwrap().dimensions(), (128, 256));
+        tmp_path.pop();
+        tmp_path.push("test.png");
+
+        let limits = PixelLimits {
+            max_pixels: 10000,
+            ..PixelLimits::default()
+        };
+        let variant = Variant {
+            format: Some(Format::Bmp),
+            ..Variant::default()
+        };
+        match super::make(&tmp_path, &variant, &limits) {
+            Err(ImageError::DimensionError) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+
+        tmp_path.pop();
+        tmp_path.push("broken.png");
+        crate::file_utils::write_image_data(&b"NOT A PNG"[..], &tmp_path).unwrap();
+        assert!(super::make(&tmp_path, &variant, &limits).is_err());
+        tmp_path.pop();
+
+        // Decodable images with the dimensions that can't be read aren't decoded.
+        tmp_path.push("radiance.hdr");
+        let file = std::fs::File::create(&tmp_path).unwrap();
+        image::hdr::HDREncoder::new(file)
+            .encode(&[image::Rgb([0.5f32; 3]); 4], 2, 2)
+            .unwrap();
+        assert!(image::open(&tmp_path).is_ok());
+        assert!(super::make(&tmp_path, &variant, &PixelLimits::default()).is_err());
+        assert!(!crate::thumbnail::make(
+            &tmp_path.to_string_lossy(),
+            &PixelLimits::default()
+        ));
+        tmp_path.pop();
+
+        std::fs::remove_dir_all(&tmp_path).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use std::thread;
-use trlogic_test::http_handlers::ImageUploadResult;
+use trlogic_test::http_handlers::{ErrorResult, ImageListPage, ImageUploadResult};
+use trlogic_test::jobs;
 use trlogic_test::microservice;
+use trlogic_test::settings::{HostRule, Settings};
 
 #[test]
 fn test_http_microservice_for_json_post()
@@ -10,7 +12,7 @@ fn test_http_microservice_for_json_post()
     let _ = std::fs::remove_dir_all(&tmp_path);
     std::fs::create_dir_all(&tmp_path).unwrap();
 
-    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8100, tmp_path.to_str().unwrap());
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8100, tmp_path.to_str().unwrap(), Settings::default());
     let srv = thread::spawn(move || {    
         microservice::run(server, srv_rx);
     });
@@ -66,7 +68,7 @@ fn test_http_microservice_for_multipart_post()
     let _ = std::fs::remove_dir_all(&tmp_path);
     std::fs::create_dir_all(&tmp_path).unwrap();
 
-    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8101, tmp_path.to_str().unwrap());
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8101, tmp_path.to_str().unwrap(), Settings::default());
     let srv = thread::spawn(move || {    
         microservice::run(server, srv_rx);
     });
@@ -107,7 +109,7 @@ fn test_http_microservice_for_unacceptable_content_post()
     let _ = std::fs::remove_dir_all(&tmp_path);
     std::fs::create_dir_all(&tmp_path).unwrap();
 
-    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8102, tmp_path.to_str().unwrap());
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8102, tmp_path.to_str().unwrap(), Settings::default());
     let srv = thread::spawn(move || {    
         microservice::run(server, srv_rx);
     });
@@ -128,7 +130,7 @@ fn test_http_microservice_for_malformed_post()
     let _ = std::fs::remove_dir_all(&tmp_path);
     std::fs::create_dir_all(&tmp_path).unwrap();
 
-    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8103, tmp_path.to_str().unwrap());
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8103, tmp_path.to_str().unwrap(), Settings::default());
     let srv = thread::spawn(move || {    
         microservice::run(server, srv_rx);
     });
@@ -141,8 +143,262 @@ fn test_http_microservice_for_malformed_post()
     let _ = std::fs::remove_dir_all(&tmp_path);
 }
 
+#[test]
+fn test_http_microservice_for_url_filenames()
+{
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("trlogic-test-kqwpfnmz7");
+    let _ = std::fs::remove_dir_all(&tmp_path);
+    std::fs::create_dir_all(&tmp_path).unwrap();
+
+    let (origin, origin_tx) = mock::origin_server(8104);
+
+    let mut settings = Settings::default();
+    settings.download.allow_hosts = vec![HostRule::Domain(String::from("localhost"))];
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8105, tmp_path.to_str().unwrap(), settings);
+    let srv = thread::spawn(move || {
+        microservice::run(server, srv_rx);
+    });
+
+    let urls = [
+        "http://localhost:8104/download?id=1",
+        "http://localhost:8104/photos/my%20cat.png?size=big&sig=abc#top",
+        "http://localhost:8104/photos/?name=dog.png",
+    ];
+    let mut response = mock::url_json_request(8105, &urls).send().unwrap();
+    assert!(response.status.is_success());
+
+    let content_lenght = response.headers.get("Content-Length").unwrap().parse::<usize>().unwrap();
+    let mut body = vec![0u8; content_lenght];
+    response.body.read_exact(&mut body).unwrap();
+
+    let results: Vec<ImageUploadResult> = serde_json::from_slice(&body[..]).unwrap();
+    assert!(results.iter().all(|x| x.success));
+    assert_eq!(results[0].filename, "\u{444}\u{43e}\u{442}\u{43e}.png");
+    assert_eq!(results[1].filename, "my cat.png");
+    assert!(results[2].filename.starts_with("untitled@"));
+    assert!(results[2].filename.ends_with(".png"));
+
+    let mut dir_list = std::fs::read_dir(&tmp_path)
+        .unwrap()
+        .map(|x| x.unwrap())
+        .filter(|x| x.file_type().unwrap().is_file())
+        .map(|x| x.file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    dir_list[..].sort();
+    assert_eq!(dir_list.len(), 3);
+    assert_eq!(dir_list[0], "my cat.png");
+    assert_eq!(dir_list[1], results[2].filename);
+    assert_eq!(dir_list[2], "\u{444}\u{43e}\u{442}\u{43e}.png");
+
+    srv_tx.send("stop").unwrap();
+    srv.join().unwrap();
+    origin_tx.send("stop").unwrap();
+    origin.join().unwrap();
+    let _ = std::fs::remove_dir_all(&tmp_path);
+}
+
+#[test]
+fn test_http_microservice_for_url_size_limit()
+{
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("trlogic-test-zhvbrtqx3");
+    let _ = std::fs::remove_dir_all(&tmp_path);
+    std::fs::create_dir_all(&tmp_path).unwrap();
+
+    let (origin, origin_tx) = mock::origin_server(8106);
+
+    let mut settings = Settings {
+        max_image_size: 32,
+        ..Settings::default()
+    };
+    settings.download.allow_hosts = vec![HostRule::Domain(String::from("localhost"))];
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8107, tmp_path.to_str().unwrap(), settings);
+    let srv = thread::spawn(move || {
+        microservice::run(server, srv_rx);
+    });
+
+    let urls = [
+        "http://localhost:8106/large",
+        "http://localhost:8106/large-chunked",
+        "http://localhost:8106/chunked",
+    ];
+    let mut response = mock::url_json_request(8107, &urls).send().unwrap();
+    assert!(response.status.is_success());
+
+    let content_lenght = response.headers.get("Content-Length").unwrap().parse::<usize>().unwrap();
+    let mut body = vec![0u8; content_lenght];
+    response.body.read_exact(&mut body).unwrap();
+
+    let results: Vec<ImageUploadResult> = serde_json::from_slice(&body[..]).unwrap();
+    assert!(!results[0].success);
+    assert_eq!(results[0].reason, "image too large");
+    assert!(!results[1].success);
+    assert_eq!(results[1].reason, "image too large");
+    assert!(results[2].success);
+    assert_eq!(results[2].size, 17);
+
+    let dir_list = std::fs::read_dir(&tmp_path)
+        .unwrap()
+        .map(|x| x.unwrap())
+        .filter(|x| x.file_type().unwrap().is_file())
+        .map(|x| x.file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(dir_list, ["chunked.jpg"]);
+
+    srv_tx.send("stop").unwrap();
+    srv.join().unwrap();
+    origin_tx.send("stop").unwrap();
+    origin.join().unwrap();
+    let _ = std::fs::remove_dir_all(&tmp_path);
+}
+
+#[test]
+fn test_http_microservice_for_images_list()
+{
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("trlogic-test-gmcxwqlp8");
+    let _ = std::fs::remove_dir_all(&tmp_path);
+    std::fs::create_dir_all(tmp_path.join("thumbnails")).unwrap();
+    image::DynamicImage::new_rgb8(64, 48).save(tmp_path.join("photo.png")).unwrap();
+    std::fs::write(tmp_path.join("notes.jpg"), "NOT AN IMAGE").unwrap();
+
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8108, tmp_path.to_str().unwrap(), Settings::default());
+    let srv = thread::spawn(move || {
+        microservice::run(server, srv_rx);
+    });
+
+    let mut response = mrq::get("http://localhost:8108/images").send().unwrap();
+    assert_eq!(i32::from(&response.status), 200);
+
+    let content_lenght = response.headers.get("Content-Length").unwrap().parse::<usize>().unwrap();
+    let mut body = vec![0u8; content_lenght];
+    response.body.read_exact(&mut body).unwrap();
+
+    let page: ImageListPage = serde_json::from_slice(&body[..]).unwrap();
+    assert_eq!(page.next, None);
+    assert_eq!(page.images.len(), 2);
+
+    let notes = &page.images[0];
+    assert_eq!(notes.filename, "notes.jpg");
+    assert_eq!(notes.size, 12);
+    assert_eq!(notes.format, None);
+    assert_eq!((notes.width, notes.height), (None, None));
+    assert_eq!(notes.content_type, "image/jpeg");
+
+    let photo = &page.images[1];
+    assert_eq!(photo.filename, "photo.png");
+    assert_eq!(photo.size, std::fs::metadata(tmp_path.join("photo.png")).unwrap().len());
+    assert_eq!(photo.format, Some(String::from("png")));
+    assert_eq!((photo.width, photo.height), (Some(64), Some(48)));
+    assert_eq!(photo.content_type, "image/png");
+    assert_eq!(photo.thumbnail_url, "/images/photo.png/thumbnail");
+    assert_eq!(photo.thumbnail, "missing");
+    assert!(!photo.modified.is_empty());
+
+    std::fs::remove_dir_all(&tmp_path).unwrap();
+
+    let mut response = mrq::get("http://localhost:8108/images").send().unwrap();
+    assert_eq!(i32::from(&response.status), 500);
+
+    let content_lenght = response.headers.get("Content-Length").unwrap().parse::<usize>().unwrap();
+    let mut body = vec![0u8; content_lenght];
+    response.body.read_exact(&mut body).unwrap();
+
+    let result: ErrorResult = serde_json::from_slice(&body[..]).unwrap();
+    assert!(!result.success);
+    assert_eq!(result.reason, "I/O error");
+
+    srv_tx.send("stop").unwrap();
+    srv.join().unwrap();
+}
+
+#[test]
+fn test_http_microservice_for_unrecognized_content_put()
+{
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("trlogic-test-rtnvjsde2");
+    let _ = std::fs::remove_dir_all(&tmp_path);
+    std::fs::create_dir_all(&tmp_path).unwrap();
+
+    let (server, srv_tx, srv_rx) = microservice::init("localhost", 8109, tmp_path.to_str().unwrap(), Settings::default());
+    let srv = thread::spawn(move || {
+        microservice::run(server, srv_rx);
+    });
+
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)"/>"#;
+    let mut response = mrq::put("http://localhost:8109/images/evil.svg")
+        .with_header("Content-Type", "image/png")
+        .with_body(svg)
+        .send()
+        .unwrap();
+    assert_eq!(i32::from(&response.status), 200);
+
+    let content_lenght = response.headers.get("Content-Length").unwrap().parse::<usize>().unwrap();
+    let mut body = vec![0u8; content_lenght];
+    response.body.read_exact(&mut body).unwrap();
+
+    let result: ImageUploadResult = serde_json::from_slice(&body[..]).unwrap();
+    assert!(result.success);
+    assert_eq!(result.filename, "evil.bin");
+    assert_eq!(result.content_type, "application/octet-stream");
+    assert!(result.warnings.iter().any(|x| x == "filename extension changed to .bin"));
+
+    let response = mrq::get("http://localhost:8109/images/evil.svg").send().unwrap();
+    assert_eq!(i32::from(&response.status), 404);
+
+    let response = mrq::get("http://localhost:8109/images/evil.bin").send().unwrap();
+    assert_eq!(i32::from(&response.status), 200);
+    assert_eq!(response.headers.get("Content-Type").map(|x| &x[..]), Some("application/octet-stream"));
+
+    srv_tx.send("stop").unwrap();
+    srv.join().unwrap();
+    let _ = std::fs::remove_dir_all(&tmp_path);
+}
+
+#[test]
+fn test_http_microservice_for_leftovers_cleanup()
+{
+    let mut tmp_path = std::env::temp_dir();
+    tmp_path.push("trlogic-test-hwzlqbr6");
+    let _ = std::fs::remove_dir_all(&tmp_path);
+    std::fs::create_dir_all(tmp_path.join("thumbnails")).unwrap();
+
+    let job = jobs::Job::new(1);
+    job.save(&tmp_path).unwrap();
+    std::fs::write(tmp_path.join("photo.jpg"), b"TEST JPEG DATA").unwrap();
+    std::fs::write(tmp_path.join(".data.1-0.tmp"), b"STAGED DATA").unwrap();
+    std::fs::write(tmp_path.join("thumbnails/photo.jpg"), b"THUMB").unwrap();
+    std::fs::write(tmp_path.join("thumbnails/.photo.jpg.1-1.tmp.jpg"), b"THUMB").unwrap();
+    std::fs::write(tmp_path.join(format!("jobs/.{}.json.1-2.tmp", job.id)), b"{").unwrap();
+    thread::sleep(std::time::Duration::from_millis(10));
+
+    let settings = Settings {
+        job_ttl: std::time::Duration::from_millis(1),
+        ..Settings::default()
+    };
+    let (server, _srv_tx, _srv_rx) = microservice::init("localhost", 8110, tmp_path.to_str().unwrap(), settings);
+    drop(server);
+
+    let names = |dir: &str| {
+        let mut names: Vec<String> = std::fs::read_dir(tmp_path.join(dir))
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(""), vec!["jobs", "photo.jpg", "thumbnails"]);
+    assert_eq!(names("jobs"), Vec::<String>::new());
+    assert_eq!(names("thumbnails"), vec!["photo.jpg"]);
+
+    let _ = std::fs::remove_dir_all(&tmp_path);
+}
+
 mod mock {
     use mrq;
+    use std::sync::mpsc;
+    use std::thread;
     
     pub fn json_request(port: u16) -> mrq::Request {
         let body = r#"
@@ -207,4 +463,55 @@ mod mock {
             .with_header("Content-Type", "application/json")
             .with_body(body)
     }
-}
\ No newline at end of file
+
+    pub fn url_json_request(port: u16, urls: &[&str]) -> mrq::Request {
+        let items = urls
+            .iter()
+            .map(|x| serde_json::json!({ "url": x }))
+            .collect::<Vec<_>>();
+        let body = serde_json::to_string(&items).unwrap();
+
+        mrq::post(format!("http://localhost:{}/images", port))
+            .with_header("Content-Type", "application/json")
+            .with_body(body)
+    }
+
+    /// Start the HTTP server serving the images to download by URL.
+    pub fn origin_server(port: u16) -> (thread::JoinHandle<()>, mpsc::Sender<&'static str>) {
+        let (srv_tx, srv_rx) = mpsc::channel::<&str>();
+        let server = rouille::Server::new(format!("localhost:{}", port), |request| {
+            match &request.url()[..] {
+                "/download" => rouille::Response::from_data("image/png", "TEST PNG DATA")
+                    .with_additional_header(
+                        "Content-Disposition",
+                        "attachment; filename=\"photo.png\"; filename*=UTF-8''%D1%84%D0%BE%D1%82%D0%BE.png",
+                    ),
+                x if x.starts_with("/photos/") => rouille::Response::from_data("image/png", "TEST PNG DATA"),
+                "/large" => rouille::Response::from_data("image/jpeg", vec![0; 64]),
+                "/large-chunked" => chunked_response(vec![0; 64]),
+                "/chunked" => chunked_response(b"CHUNKED JPEG DATA".to_vec()),
+                _ => rouille::Response::empty_404(),
+            }
+        })
+        .unwrap();
+
+        let join_handle = thread::spawn(move || loop {
+            match srv_rx.recv_timeout(std::time::Duration::from_millis(10)) {
+                Ok(_) => break,
+                _ => server.poll(),
+            }
+        });
+
+        (join_handle, srv_tx)
+    }
+
+    /// Make a response without Content-Length, it's sent with chunked transfer encoding.
+    fn chunked_response(data: Vec<u8>) -> rouille::Response {
+        rouille::Response {
+            status_code: 200,
+            headers: vec![("Content-Type".into(), "image/jpeg".into())],
+            data: rouille::ResponseBody::from_reader(std::io::Cursor::new(data)),
+            upgrade: None,
+        }
+    }
+}